@@ -0,0 +1,33 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use babi::php::{serialize, unserialize_ref_with, unserialize_with, UnserializeOptions};
+
+// run with `cargo +nightly fuzz run unserialize -- -malloc_limit_mb=64`.
+// every allocation is bounded by the options below (and the input by
+// -max_len), so a larger one is a bug just like a panic is
+fuzz_target!(|data: &[u8]| {
+    let opts = UnserializeOptions {
+        max_depth: 64,
+        max_elements: 1 << 12,
+        max_string_len: 1 << 12,
+        max_input_len: 1 << 16,
+        ..Default::default()
+    };
+    let var = match unserialize_with(data, &opts) {
+        Ok(var) => var,
+        Err(_) => {
+            assert!(unserialize_ref_with(data, &opts).is_err());
+            return;
+        }
+    };
+    // whatever is accepted serializes to something that reads back the
+    // same, sharing included. NAN is never equal to itself, so the bytes
+    // are compared instead of the vars
+    let raw = serialize(&var).unwrap();
+    let back = unserialize_with(&raw, &opts).unwrap();
+    assert_eq!(serialize(&back).unwrap(), raw);
+
+    let borrowed = unserialize_ref_with(data, &opts).unwrap();
+    assert_eq!(serialize(&borrowed.into_owned()).unwrap(), raw);
+});
@@ -1,3 +1,4 @@
+use std::env;
 use std::fs::File;
 use std::os::unix::io::FromRawFd;
 use std::io::{Write, BufReader};
@@ -7,31 +8,68 @@ use libotp::totp;
 use rand::Rng;
 
 use crate::errors::*;
-use crate::php::{serialize, unserialize, PhpVar};
-use crate::http::{HttpRequest, HttpResponse};
+use crate::php::{unserialize_with, session_decode, session_decode_ref, session_encode, SessionHandler, PhpVar, PhpArray, PhpKey,
+                 PhpValueRef, PhpKeyRef, UnserializeOptions, UnserializeError, AllowedClasses, var_dump_with, ExpansionLimits, to_json_with,
+                 JsonOptions, BinaryStrings};
+use crate::http::{HttpRequest, HttpResponse, form_decode, form_encode};
+use crate::qr;
 
 pub struct Route {
     method: String,
     matcher: Regex,
-    handler: fn(&HttpRequest) -> Result<HttpResponse>
+    handler: fn(&HttpRequest, &Config) -> Result<HttpResponse>
+}
+
+// settings read once at startup, never from the requests
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    // the format new cookies are written in, for deployments that want a
+    // more compact one than the format sessions came in
+    pub session_format: Option<SessionHandler>,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self> {
+        let session_format = match env::var("BABI_SESSION_FORMAT") {
+            Ok(name) => match SessionHandler::from_name(&name) {
+                Some(handler) => Some(handler),
+                None => bail!("unknown session format {:?}", name)
+            },
+            Err(_) => None
+        };
+        Ok(Config { session_format })
+    }
 }
 
 pub struct App {
-    routes: Vec<Route>
+    routes: Vec<Route>,
+    config: Config,
+}
+
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl App {
     pub fn new() -> Self {
         App {
-            routes: vec![]
+            routes: vec![],
+            config: Config::default(),
         }
     }
 
-    pub fn reg(&mut self, method: &str, re: Regex, handler: fn(&HttpRequest) -> Result<HttpResponse>) -> &mut Self {
+    pub fn config(&mut self, config: Config) -> &mut Self {
+        self.config = config;
+        self
+    }
+
+    pub fn reg(&mut self, method: &str, re: Regex, handler: fn(&HttpRequest, &Config) -> Result<HttpResponse>) -> &mut Self {
         let r = Route {
             method: method.to_string(),
             matcher: re,
-            handler: handler
+            handler,
         };
         self.routes.push(r);
         self
@@ -42,23 +80,33 @@ impl App {
         let mut ostream = unsafe {File::from_raw_fd(fd)};
 
         loop {
-            match HttpRequest::from_stream::<File>(&mut istream) {
-                Ok(req) => {
-                    // println!("{:?}", req);
-                    if self.route(&req)
-                        .set_option("Connection".to_string(), 
-                                    (if req.keep_alive() { "keep-alive" } else { "close" }).to_string())
-                        .to_stream(&mut ostream)
-                        .is_err() {
-                            break;
-                        }
-                    if !req.keep_alive() {
-                        break;
+            let req = match HttpRequest::from_stream::<File>(&mut istream) {
+                Ok(req) => req,
+                Err(e) => {
+                    // answered if the client is still there, but never read
+                    // past, as where the next request starts is unknown
+                    if let Some(mut resp) = HttpResponse::from_request_error(&e) {
+                        let _ = resp.to_stream(&mut ostream);
                     }
-                },
-                _ => {
                     break;
                 }
+            };
+            // println!("{:?}", req);
+            let mut resp = self.route(&req);
+            let mut keep_alive = req.keep_alive();
+            if req.version() < (1, 1) && resp.is_streamed() {
+                resp.unchunked();
+                keep_alive = false;
+            }
+            if resp
+                .set_option("Connection".to_string(), 
+                            (if keep_alive { "keep-alive" } else { "close" }).to_string())
+                .to_stream(&mut ostream)
+                .is_err() {
+                    break;
+                }
+            if !keep_alive {
+                break;
             }
         }
     }
@@ -66,7 +114,7 @@ impl App {
     fn route(&self, req: &HttpRequest) -> HttpResponse {
         for r in &self.routes {
             if r.method == req.method() && r.matcher.is_match(req.path()) {
-                if let Ok(resp) = (r.handler)(req) {
+                if let Ok(resp) = (r.handler)(req, &self.config) {
                     return resp;
                 } else {
                     return HttpResponse::new(500, b"internal server error".to_vec())
@@ -77,11 +125,11 @@ impl App {
     }
 }
 
-pub fn index(_req: &HttpRequest) -> Result<HttpResponse> {
-    Ok(HttpResponse::new(200, r#"<html><body><h1>Authenticator</h1><a href="/gen"><h2>generate</h2></a><a href="/list"><h2>list</h2></a></body></html>"#.as_bytes().to_vec()))
+pub fn index(_req: &HttpRequest, _config: &Config) -> Result<HttpResponse> {
+    Ok(HttpResponse::new(200, r#"<html><body><h1>Authenticator</h1><a href="/gen"><h2>generate</h2></a><a href="/list"><h2>list</h2></a><a href="/import"><h2>import</h2></a><a href="/export"><h2>export</h2></a></body></html>"#.as_bytes().to_vec()))
 }
 
-pub fn gen(_req: &HttpRequest) -> Result<HttpResponse> {
+pub fn gen(_req: &HttpRequest, _config: &Config) -> Result<HttpResponse> {
     let mut rng = rand::thread_rng();
     let charset: &'static [u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
     let secret: String = (0..32).map(|_| charset[rng.gen::<usize>() % 32] as char).collect();
@@ -99,18 +147,50 @@ Secret:<br>
 </body></html>"#, secret, secret).as_bytes().to_vec()))
 }
 
-pub fn list(req: &HttpRequest) -> Result<HttpResponse> {
+fn write_entry(resp: &mut Vec<u8>, label: &PhpKeyRef, secret: &PhpValueRef) -> Result<()> {
+    let code = secret.as_bytes().and_then(|s| totp(&String::from_utf8_lossy(s), 6, 30, 0));
+    if let Some(code) = code {
+        write!(resp, r#"Label: {}<br/>Secret: {}<br/>Code: {:06}<hr>"#, label, secret, code)?;
+    } else {
+        write!(resp, r#"Label: {}<br/>Secret: {}<br/>Code: INVALID<hr>"#, label, secret)?;
+    }
+    Ok(())
+}
+
+// sessions come from the client, so objects of any class only ever show up
+// as incomplete class objects
+fn session_options() -> UnserializeOptions {
+    UnserializeOptions {
+        allowed_classes: AllowedClasses::Only(vec![]),
+        ..Default::default()
+    }
+}
+
+// the cookie holds either our own serialized array or a session file
+// written by PHP with any of its serialize handlers
+fn load_session(raw: &[u8]) -> ::std::result::Result<PhpVar, UnserializeError> {
+    match SessionHandler::detect(raw) {
+        // shown as is even when it is not an array
+        SessionHandler::PhpSerialize => unserialize_with(raw, &session_options()),
+        handler => session_decode(raw, handler, &session_options()).map(PhpVar::Array),
+    }
+}
+
+pub fn list(req: &HttpRequest, _config: &Config) -> Result<HttpResponse> {
     let mut resp = b"<html><body><h1>Authenticator</h1><hr>".to_vec();
-    if let Ok(param) = req.get(b"session") {
-        if let PhpVar::Array(k, v) = *unserialize(&base64::decode(param)?) {
-            for i in 0..k.len() {
-                if let PhpVar::String(ref s) = *v[i] {
-                    if let Some(code) = totp(&String::from_utf8_lossy(s), 6, 30, 0) {
-                        write!(resp, r#"Label: {}<br/>Secret: {}<br/>Code: {:06}<hr>"#, k[i], v[i], code)?;
-                        continue;
-                    }
+    if let Some(param) = req.cookies().get(b"session") {
+        // only labels and secrets are read, so nothing is copied out
+        let raw = base64::decode(param)?;
+        if let Ok(session) = session_decode_ref(&raw, SessionHandler::detect(&raw), &session_options()) {
+            if let Ok(label) = req.get(b"label") {
+                let label = PhpKeyRef::from_bytes(label);
+                if let Some(secret) = session.get(label) {
+                    write_entry(&mut resp, &label, secret)?;
+                }
+            } else {
+                for (label, secret) in session.entries() {
+                    write_entry(&mut resp, label, secret)?;
                 }
-                write!(resp, r#"Label: {}<br/>Secret: {}<br/>Code: INVALID<hr>"#, k[i], v[i])?;
             }
         }
     }
@@ -118,7 +198,8 @@ pub fn list(req: &HttpRequest) -> Result<HttpResponse> {
     Ok(HttpResponse::new(200, resp))
 }
 
-pub fn info(req: &HttpRequest) -> Result<HttpResponse> {
+pub fn info(req: &HttpRequest, _config: &Config) -> Result<HttpResponse> {
+    let mut status = 200;
     let mut resp = vec![];
     write!(resp, "<html><body><h1>Request</h1><p>{:?}</p>", req)?;
     /*
@@ -126,31 +207,76 @@ pub fn info(req: &HttpRequest) -> Result<HttpResponse> {
         resp.extend_from_slice(format!("{:?}", req).as_bytes());
     }
     */
-    if let Ok(param) = req.get(b"session") {
-        let session = unserialize(&base64::decode(param)?);
-        write!(resp, "<h1>Session</h1><p>{:?}</p>", session)?;
+    if let Some(param) = req.cookies().get(b"session") {
+        // exactly what PHP's var_dump prints, so it can be diffed. a session
+        // that cannot be read or is too large to print is the client's fault
+        match load_session(&base64::decode(param)?).map_err(Error::from)
+                .and_then(|session| var_dump_with(&session, &ExpansionLimits::from(&session_options()))) {
+            Ok(dump) => {
+                write!(resp, "<h1>Session</h1><pre>")?;
+                resp.extend_from_slice(&dump);
+                write!(resp, "</pre>")?;
+            },
+            Err(e) => {
+                status = 400;
+                write!(resp, "<h1>Session</h1><p>rejected: {}</p>", e)?;
+            },
+        }
     }
     write!(resp, "</body></html>")?;
-    Ok(HttpResponse::new(200, resp))
+    Ok(HttpResponse::new(status, resp))
 }
 
-pub fn enroll(req: &HttpRequest) -> Result<HttpResponse> {
-    let label = req.get(b"label")?;
-    let secret = req.get(b"secret")?;
+// the session the way PHP's json_encode would render it, for tools that do
+// not speak the serialize format
+pub fn session_json(req: &HttpRequest, _config: &Config) -> Result<HttpResponse> {
+    let opts = JsonOptions {
+        binary_strings: BinaryStrings::Substitute,
+        expansion: ExpansionLimits::from(&session_options()),
+        ..Default::default()
+    };
+    let (status, body) = match req.cookies().get(b"session") {
+        Some(param) => match load_session(&base64::decode(param)?).map_err(Error::from)
+                .and_then(|session| to_json_with(&session, &opts)) {
+            Ok(json) => (200, json),
+            Err(e) => (400, serde_json::json!({ "error": e.to_string() })),
+        },
+        None => (200, serde_json::json!([])),
+    };
+    let mut resp = HttpResponse::new(status, serde_json::to_vec(&body)?);
+    resp.set_option("Content-Type".to_string(), "application/json".to_string());
+    Ok(resp)
+}
 
-    let mut k = vec![];
-    let mut v = vec![];
-    if let Ok(param) = req.get(b"session") {
-        if let PhpVar::Array(k_, v_) = *unserialize(&base64::decode(param)?) {
-            k.extend(k_);
-            v.extend(v_);
+// the session in the cookie, if there is one, and the format it goes back
+// in. a session that came from PHP goes back in the format it came in
+fn cookie_session(req: &HttpRequest) -> Result<(PhpArray, SessionHandler)> {
+    let mut session = PhpArray::new();
+    let mut handler = SessionHandler::PhpSerialize;
+    if let Some(param) = req.cookies().get(b"session") {
+        let raw = base64::decode(param)?;
+        if let Ok(PhpVar::Array(arr)) = load_session(&raw) {
+            session = arr;
+            if !raw.is_empty() {
+                handler = SessionHandler::detect(&raw);
+            }
         }
     }
-    k.push(Box::new(PhpVar::String(label.to_vec())));
-    v.push(Box::new(PhpVar::String(secret.to_vec())));
+    Ok((session, handler))
+}
 
-    let session = PhpVar::Array(k, v);
-    let cookie = format!("session={};", base64::encode(&serialize(&session)?));
+// sets the cookie and sends the client back to the list
+fn save_session(session: &PhpArray, handler: SessionHandler, config: &Config) -> Result<HttpResponse> {
+    let raw = match session_encode(session, config.session_format.unwrap_or(handler)) {
+        Ok(raw) => raw,
+        // copies the session only points at have to be written out in full,
+        // and labels have to fit the format
+        Err(e) if matches!(e.kind(), ErrorKind::ExpansionLimit | ErrorKind::SessionVarName(_)) => {
+            return Ok(HttpResponse::new(400, e.to_string().into_bytes()))
+        },
+        Err(e) => return Err(e),
+    };
+    let cookie = format!("session={};", base64::encode(&raw));
 
     let mut resp = HttpResponse::new(301, vec![]);
     resp.set_option("Location".to_string(), "/list".to_string())
@@ -158,47 +284,447 @@ pub fn enroll(req: &HttpRequest) -> Result<HttpResponse> {
     Ok(resp)
 }
 
+pub fn enroll(req: &HttpRequest, config: &Config) -> Result<HttpResponse> {
+    let label = req.get(b"label")?;
+    let secret = req.get(b"secret")?;
+
+    let (mut session, handler) = cookie_session(req)?;
+    // enrolling an existing label replaces its secret
+    session.insert(label.as_slice(), PhpVar::String(secret.to_vec()));
+    save_session(&session, handler, config)
+}
+
+pub fn import_form(_req: &HttpRequest, _config: &Config) -> Result<HttpResponse> {
+    Ok(HttpResponse::new(200, r#"<html><body><h1>Authenticator</h1><hr><form action="/import" method="POST" enctype="multipart/form-data">
+Backup:<br/>
+<input type="file" name="backup">
+<br><br>
+<input type="submit" value="import">
+</form>
+</body></html>"#.as_bytes().to_vec()))
+}
+
+// `otpauth://totp/label?secret=...` URIs, one per line, the way
+// authenticator apps export them and the QR codes from /gen hold them.
+// other lines, HOTP ones included, are skipped
+fn otpauth_entries(backup: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut entries = vec![];
+    for line in backup.split(|c| *c == b'\n') {
+        let line = String::from_utf8_lossy(line);
+        let uri = match line.trim().strip_prefix("otpauth://totp/") {
+            Some(uri) => uri,
+            None => continue
+        };
+        let (label, query) = uri.split_at(uri.find('?').unwrap_or(uri.len()));
+        let secret = query.trim_start_matches('?').split('&').find_map(|p| p.strip_prefix("secret="));
+        match secret {
+            Some(secret) if !label.is_empty() && !secret.is_empty() =>
+                entries.push((form_decode(label.as_bytes()), form_decode(secret.as_bytes()))),
+            _ => ()
+        }
+    }
+    entries
+}
+
+// the entries of an uploaded backup are enrolled like /enroll does, later
+// ones replacing earlier ones with the same label
+pub fn import(req: &HttpRequest, config: &Config) -> Result<HttpResponse> {
+    let backup = match req.file(b"backup") {
+        Some(backup) => backup,
+        None => return Ok(HttpResponse::new(400, b"no backup uploaded".to_vec()))
+    };
+    // a PNG is a screenshot of the QR code of a single entry
+    let data = &backup.data;
+    let text = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        match qr::decode(data) {
+            Ok(text) => text,
+            Err(e) => return Ok(HttpResponse::new(400, format!("no QR code could be read: {}", e).into_bytes()))
+        }
+    } else if backup.content_type.as_ref().is_some_and(|t| t.starts_with("image/")) ||
+              data.starts_with(b"\xff\xd8\xff") || data.starts_with(b"GIF8") {
+        return Ok(HttpResponse::new(415, b"only PNG images of QR codes can be read".to_vec()));
+    } else {
+        data.to_vec()
+    };
+    let entries = otpauth_entries(&text);
+    if entries.is_empty() {
+        return Ok(HttpResponse::new(400, b"no otpauth:// URIs in the backup".to_vec()));
+    }
+
+    let (mut session, handler) = cookie_session(req)?;
+    for (label, secret) in entries {
+        session.insert(label, PhpVar::String(secret));
+    }
+    save_session(&session, handler, config)
+}
+
+// the session as the otpauth:// URIs /import reads, written an entry at a
+// time so a large session is never held as text. entries whose secret is
+// not a string are left out
+pub fn export(req: &HttpRequest, _config: &Config) -> Result<HttpResponse> {
+    let (session, _) = cookie_session(req)?;
+    let mut resp = HttpResponse::streaming(200, move |out| {
+        for (label, secret) in session {
+            let label = match label {
+                PhpKey::Int(i) => i.to_string().into_bytes(),
+                PhpKey::String(s) => s,
+            };
+            if let PhpVar::String(secret) = secret {
+                let uri = format!("otpauth://totp/{}?secret={}&issuer=babi\n", form_encode(&label), form_encode(&secret));
+                out.write_all(uri.as_bytes())?;
+            }
+        }
+        Ok(())
+    });
+    resp.set_option("Content-Type".to_string(), "text/plain".to_string())
+        .set_option("Content-Disposition".to_string(), "attachment; filename=\"babi.txt\"".to_string());
+    Ok(resp)
+}
+
 #[cfg(test)]
-fn local_request(payload: &mut [u8], handler: fn(&HttpRequest) -> Result<HttpResponse>) -> Result<HttpResponse> {
+use crate::php::{serialize, pack, Packing, RefKind};
+#[cfg(test)]
+use std::{cell::RefCell, rc::Rc};
+#[cfg(test)]
+use qrcodegen::{QrCode, QrCodeEcc};
+
+#[cfg(test)]
+fn local_request(payload: &mut [u8], handler: fn(&HttpRequest, &Config) -> Result<HttpResponse>) -> Result<HttpResponse> {
     let mut istream = BufReader::new(&payload[..]);
     let req = HttpRequest::from_stream(&mut istream)?;
-    handler(&req)
+    handler(&req, &Config::default())
 }
 
-/*
 #[test]
-fn exploit_leak() -> Result<()> {
+fn test_enroll_oversized_session() -> Result<()> {
+    // the declared string length used to be sliced out of the input as is
     let mut payload = vec![];
-    // write!(payload, "GET /index HTTP/1.1\r\n\r\n")?;
-    write!(payload, "POST /enroll?session={}&label=1&secret=2 HTTP/1.1\r\nHTTP_RUST_BACKTRACE: full\r\n\r\n",
-           base64::encode(&b"s:18446744073709551611:\"\";"))?;
+    write!(payload, "POST /enroll?label=1&secret=2 HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\nContent-Length: 0\r\n\r\n",
+           base64::encode(b"s:18446744073709551611:\"\";"))?;
     let resp = local_request(&mut payload[..], enroll)?;
-    println!("{:?}", String::from_utf8_lossy(resp.content()));
+    assert_eq!(resp.get_option("Set-Cookie")?, "session=YToxOntpOjE7czoxOiIyIjt9;");
+    Ok(())
+}
+
+#[test]
+fn test_info_rejected_session() -> Result<()> {
+    let mut payload = vec![];
+    write!(payload, "GET /info HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\n\r\n", base64::encode(b"a:2:{i:0;N;}"))?;
+    let resp = local_request(&mut payload[..], info)?;
+    // 400, like a session too large to print
+    assert_eq!(resp.status(), 400);
+    let content = String::from_utf8_lossy(resp.content());
+    assert!(content.contains("<p>rejected: bad length at offset 11, expected key</p>"));
+    Ok(())
+}
+
+// a session whose levels each hold the one before twice, 2^levels entries
+// once expanded. `R:` unserializes fine and only shares them, `r:` copies
+// have to be made by unserialize itself
+#[cfg(test)]
+fn expanding_session(levels: usize, tag: char) -> Vec<u8> {
+    let mut raw = format!("a:{}:{{i:0;a:0:{{}}", levels).into_bytes();
+    let (mut prev, mut next) = (2, 3);
+    for i in 1..levels {
+        raw.extend_from_slice(format!("i:{};a:2:{{i:0;{}:{};i:1;{}:{};}}", i, tag, prev, tag, prev).as_bytes());
+        prev = next;
+        next += if tag == 'r' { 3 } else { 1 };
+    }
+    raw.push(b'}');
+    raw
+}
+
+#[test]
+fn test_info_expanding_session() -> Result<()> {
+    let mut payload = vec![];
+    write!(payload, "GET /info HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\n\r\n",
+           base64::encode(&expanding_session(22, 'R')))?;
+    let resp = local_request(&mut payload[..], info)?;
+    assert_eq!(resp.status(), 400);
+    let content = String::from_utf8_lossy(resp.content());
+    assert!(content.contains("<p>rejected: value too large once its references are expanded</p>"));
+
+    let mut payload = vec![];
+    write!(payload, "GET /info HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\n\r\n",
+           base64::encode(&expanding_session(22, 'r')))?;
+    let resp = local_request(&mut payload[..], info)?;
+    assert_eq!(resp.status(), 400);
+    let content = String::from_utf8_lossy(resp.content());
+    assert!(content.contains("<p>rejected: Copies limit exceeded at offset"));
+
+    for tag in &['r', 'R'] {
+        let mut payload = vec![];
+        write!(payload, "GET /info HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\n\r\n",
+               base64::encode(&expanding_session(3, *tag)))?;
+        let resp = local_request(&mut payload[..], info)?;
+        assert_eq!(resp.status(), 200);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_save_expanding_session() -> Result<()> {
+    // igbinary can only refer back to objects, every other `r:` cell is
+    // written out in full for each holder
+    let mut session = PhpArray::new();
+    let mut level = PhpVar::Array(PhpArray::new());
+    for i in 0..22 {
+        let cell = Rc::new(RefCell::new(level));
+        session.insert(i, PhpVar::Ref(RefKind::Value, cell.clone()));
+        level = PhpVar::Array(vec![PhpVar::Ref(RefKind::Value, cell.clone()), PhpVar::Ref(RefKind::Value, cell)].into_iter().collect());
+    }
+    let resp = save_session(&session, SessionHandler::Igbinary, &Config::default())?;
+    assert_eq!(resp.status(), 400);
+    assert_eq!(resp.get_option("Set-Cookie").ok(), None);
+    Ok(())
+}
+
+#[test]
+fn test_save_session_format() -> Result<()> {
+    let session: PhpArray = vec![("a", PhpVar::String(b"b".to_vec()))].into_iter().collect();
+    let config = Config { session_format: Some(SessionHandler::Php) };
+    let resp = save_session(&session, SessionHandler::PhpSerialize, &config)?;
+    let cookie = resp.get_option("Set-Cookie")?;
+    let cookie = base64::decode(&cookie["session=".len()..(cookie.len() - 1)])?;
+    assert_eq!(cookie, b"a|s:1:\"b\";".to_vec());
+    Ok(())
+}
+
+#[test]
+fn test_list_incomplete_class() -> Result<()> {
+    let mut payload = vec![];
+    write!(payload, "GET /list HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\n\r\n",
+           base64::encode(b"a:1:{s:1:\"a\";O:3:\"Foo\":0:{}}"))?;
+    let resp = local_request(&mut payload[..], list)?;
+    let content = String::from_utf8_lossy(resp.content());
+    assert!(content.contains("Label: a<br/>Secret: Object<br/>Code: INVALID<hr>"));
+
+    let mut payload = vec![];
+    write!(payload, "GET /info HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\n\r\n",
+           base64::encode(b"O:3:\"Foo\":0:{}"))?;
+    let resp = local_request(&mut payload[..], info)?;
+    let content = String::from_utf8_lossy(resp.content());
+    assert!(content.contains("<pre>object(__PHP_Incomplete_Class)#1 (1) {
+  [\"__PHP_Incomplete_Class_Name\"]=>
+  string(3) \"Foo\"
+}
+</pre>"));
+    Ok(())
+}
+
+#[test]
+fn test_session_json() -> Result<()> {
+    let mut payload = vec![];
+    write!(payload, "GET /session.json HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\n\r\n",
+           base64::encode(b"a:2:{s:1:\"a\";s:1:\"b\";i:1;s:2:\"\xff2\";}"))?;
+    let resp = local_request(&mut payload[..], session_json)?;
+    assert_eq!(resp.get_option("Content-Type")?, "application/json");
+    assert_eq!(resp.content(), "{\"a\":\"b\",\"1\":\"\u{fffd}2\"}".as_bytes());
+
+    let mut payload = vec![];
+    write!(payload, "GET /session.json HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\n\r\n", base64::encode(b"a:1:{"))?;
+    let resp = local_request(&mut payload[..], session_json)?;
+    assert_eq!(resp.content(), &b"{\"error\":\"truncated input at offset 5, expected key\"}"[..]);
+
+    let mut payload = vec![];
+    write!(payload, "GET /session.json HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\n\r\n",
+           base64::encode(&expanding_session(22, 'R')))?;
+    let resp = local_request(&mut payload[..], session_json)?;
+    assert_eq!(resp.status(), 400);
+    assert_eq!(resp.content(), &b"{\"error\":\"value too large once its references are expanded\"}"[..]);
     Ok(())
 }
-*/
 
 #[test]
 fn test_enroll() -> Result<()> {
     let mut payload = vec![];
     let label = "1";
     let secret = "2";
-    let session = PhpVar::Array(
-        vec![Box::new(PhpVar::String(b"a".to_vec()))],
-        vec![Box::new(PhpVar::String(b"b".to_vec()))],
-        );
-    write!(payload, "POST /enroll?session={}&label={}&secret={} HTTP/1.1\r\n\r\n",
-           base64::encode(&serialize(&session)?),
-           label, secret)?;
+    let mut session = PhpArray::new();
+    session.insert("a", PhpVar::String(b"b".to_vec()));
+    let session = PhpVar::Array(session);
+    write!(payload, "POST /enroll?label={}&secret={} HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\nContent-Length: 0\r\n\r\n",
+           label, secret, base64::encode(&serialize(&session)?))?;
+    let resp = local_request(&mut payload[..], enroll)?;
+    // php > var_dump(unserialize(base64_decode('YToyOntzOjE6ImEiO3M6MToiYiI7czoxOiIxIjtzOjE6IjIiO30')));
+    // array(2) {
+    //   ["a"]=>
+    //   string(1) "b"
+    //   [1]=>
+    //   string(1) "2"
+    // }
+    // the label "1" is stored under the int key 1, just like PHP does
+    // php > echo base64_encode(serialize(['a' => 'b', '1' => '2']));
+    assert_eq!(resp.get_option("Set-Cookie")?, "session=YToyOntzOjE6ImEiO3M6MToiYiI7aToxO3M6MToiMiI7fQ==;");
+    Ok(())
+}
+
+#[test]
+fn test_enroll_existing_label() -> Result<()> {
+    let mut session = PhpArray::new();
+    session.insert("a", PhpVar::String(b"b".to_vec()));
+    session.insert("c", PhpVar::String(b"d".to_vec()));
+    let mut payload = vec![];
+    write!(payload, "POST /enroll?label=a&secret=e HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\nContent-Length: 0\r\n\r\n",
+           base64::encode(&serialize(&PhpVar::Array(session))?))?;
+    let resp = local_request(&mut payload[..], enroll)?;
+
+    let cookie = resp.get_option("Set-Cookie")?;
+    let cookie = base64::decode(&cookie["session=".len()..(cookie.len() - 1)])?;
+    assert_eq!(cookie, b"a:2:{s:1:\"a\";s:1:\"e\";s:1:\"c\";s:1:\"d\";}".to_vec());
+    Ok(())
+}
+
+#[test]
+fn test_session_from_cookie() -> Result<()> {
+    // a session in the query string is not the session, and a cookie named
+    // like a form param does not replace it
+    let session = base64::encode(b"a:1:{s:1:\"a\";s:1:\"b\";}");
+    let mut payload = vec![];
+    write!(payload, "POST /enroll?session={} HTTP/1.1\r\nHost: babi\r\nCookie: label=x; secret=y\r\nContent-Length: 16\r\n\r\n\
+                     label=c&secret=d", form_encode(session.as_bytes()))?;
+    let resp = local_request(&mut payload[..], enroll)?;
+    let cookie = resp.get_option("Set-Cookie")?;
+    let cookie = base64::decode(&cookie["session=".len()..(cookie.len() - 1)])?;
+    assert_eq!(cookie, b"a:1:{s:1:\"c\";s:1:\"d\";}".to_vec());
+
+    let mut payload = vec![];
+    write!(payload, "GET /list?session={} HTTP/1.1\r\nHost: babi\r\n\r\n", form_encode(session.as_bytes()))?;
+    let resp = local_request(&mut payload[..], list)?;
+    assert!(!String::from_utf8_lossy(resp.content()).contains("Label: a"));
+    Ok(())
+}
+
+#[test]
+fn test_php_session_file() -> Result<()> {
+    // php > session_start(); $_SESSION['a'] = 'b'; $_SESSION['c'] = 'd';
+    // $ cat /var/lib/php/sessions/sess_*
+    let raw = b"a|s:1:\"b\";c|s:1:\"d\";";
+    let mut payload = vec![];
+    write!(payload, "GET /list HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\n\r\n", base64::encode(raw))?;
+    let resp = local_request(&mut payload[..], list)?;
+    let content = String::from_utf8_lossy(resp.content());
+    assert!(content.contains("Label: a<br/>Secret: b<br/>Code: INVALID<hr>Label: c<br/>Secret: d<br/>"));
+
+    // enrolling keeps the session readable by PHP
+    let mut payload = vec![];
+    write!(payload, "POST /enroll?label=c&secret=e HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\nContent-Length: 0\r\n\r\n",
+           base64::encode(raw))?;
+    let resp = local_request(&mut payload[..], enroll)?;
+    let cookie = resp.get_option("Set-Cookie")?;
+    let cookie = base64::decode(&cookie["session=".len()..(cookie.len() - 1)])?;
+    assert_eq!(cookie, b"a|s:1:\"b\";c|s:1:\"e\";".to_vec());
+
+    // as long as the label can be written in it
+    let mut payload = vec![];
+    write!(payload, "POST /enroll?label=c%7Cd&secret=e HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\nContent-Length: 0\r\n\r\n",
+           base64::encode(raw))?;
+    let resp = local_request(&mut payload[..], enroll)?;
+    assert_eq!(resp.status(), 400);
+    assert_eq!(resp.content(), b"session var name contains '|'");
+    let mut payload = vec![];
+    write!(payload, "POST /enroll?label={}&secret=e HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\nContent-Length: 0\r\n\r\n",
+           "c".repeat(128), base64::encode(b"\x01as:1:\"b\";"))?;
     let resp = local_request(&mut payload[..], enroll)?;
-    r#"
-    php > var_dump(unserialize(base64_decode('YToyOntzOjE6ImEiO3M6MToiYiI7czoxOiIxIjtzOjE6IjIiO30')));
-    array(2) {
-      ["a"]=>
-      string(1) "b"
-      [1]=>
-      string(1) "2"
-    }"#;
-    assert_eq!(resp.get_option("Set-Cookie")?, "session=YToyOntzOjE6ImEiO3M6MToiYiI7czoxOiIxIjtzOjE6IjIiO30=;");
+    assert_eq!(resp.status(), 400);
+    assert_eq!(resp.get_option("Set-Cookie").ok(), None);
+    Ok(())
+}
+
+#[test]
+fn test_packed_session() -> Result<()> {
+    let session: PhpArray = vec![("a", PhpVar::String(b"b".to_vec()))].into_iter().collect();
+    for packing in &[Packing::MsgPack, Packing::Cbor] {
+        let raw = pack(&PhpVar::Array(session.clone()), *packing)?;
+        let mut payload = vec![];
+        write!(payload, "GET /list HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\n\r\n", base64::encode(&raw))?;
+        let resp = local_request(&mut payload[..], list)?;
+        assert!(String::from_utf8_lossy(resp.content()).contains("Label: a<br/>Secret: b<br/>"));
+
+        // and it stays packed
+        let mut payload = vec![];
+        write!(payload, "POST /enroll?label=c&secret=d HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\nContent-Length: 0\r\n\r\n",
+               base64::encode(&raw))?;
+        let resp = local_request(&mut payload[..], enroll)?;
+        let cookie = resp.get_option("Set-Cookie")?;
+        let cookie = base64::decode(&cookie["session=".len()..(cookie.len() - 1)])?;
+        let mut expected = session.clone();
+        expected.insert("c", PhpVar::String(b"d".to_vec()));
+        assert_eq!(cookie, pack(&PhpVar::Array(expected), *packing)?);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_import() -> Result<()> {
+    let upload = |session: &[u8], filename: &str, data: &[u8]| -> Result<HttpResponse> {
+        let mut body = format!("--b\r\nContent-Disposition: form-data; name=\"backup\"; filename=\"{}\"\r\n\r\n", filename)
+            .into_bytes();
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\r\n--b--\r\n");
+        let mut payload = vec![];
+        write!(payload, "POST /import HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\nContent-Type: multipart/form-data; boundary=b\r\n\
+                         Content-Length: {}\r\n\r\n", base64::encode(session), body.len())?;
+        payload.extend_from_slice(&body);
+        local_request(&mut payload[..], import)
+    };
+
+    let backup = b"otpauth://totp/babi:demo?secret=AAAA&issuer=babi\r\n\
+                   otpauth://hotp/counter?secret=BBBB&counter=1\n\
+                   # a comment\n\
+                   otpauth://totp/a?issuer=babi&secret=CC%3D%3D\n\
+                   otpauth://totp/nosecret?issuer=babi\n";
+    let resp = upload(b"a:1:{s:1:\"a\";s:1:\"b\";}", "backup.txt", backup)?;
+    assert_eq!(resp.get_option("Location")?, "/list");
+    let cookie = resp.get_option("Set-Cookie")?;
+    let cookie = base64::decode(&cookie["session=".len()..(cookie.len() - 1)])?;
+    assert_eq!(cookie, b"a:2:{s:1:\"a\";s:4:\"CC==\";s:9:\"babi:demo\";s:4:\"AAAA\";}".to_vec());
+
+    let resp = upload(b"", "backup.txt", b"not a backup")?;
+    assert_eq!(resp.get_option("Set-Cookie").ok(), None);
+    assert_eq!(resp.content(), b"no otpauth:// URIs in the backup");
+
+    // a screenshot of a QR code
+    let code = QrCode::encode_text("otpauth://totp/babi:qr?secret=DDDD&issuer=babi", QrCodeEcc::Medium).unwrap();
+    let resp = upload(b"a:1:{s:1:\"a\";s:1:\"b\";}", "qr.png", &qr::render(&code, 3, 0, &[]))?;
+    let cookie = resp.get_option("Set-Cookie")?;
+    let cookie = base64::decode(&cookie["session=".len()..(cookie.len() - 1)])?;
+    assert_eq!(cookie, b"a:2:{s:1:\"a\";s:1:\"b\";s:7:\"babi:qr\";s:4:\"DDDD\";}".to_vec());
+    let resp = upload(b"", "qr.png", b"\x89PNG\r\n\x1a\n")?;
+    assert!(String::from_utf8_lossy(resp.content()).starts_with("no QR code could be read"));
+    let resp = upload(b"", "qr.jpg", b"\xff\xd8\xff\xe0")?;
+    assert_eq!(resp.content(), b"only PNG images of QR codes can be read");
+
+    // and a plain form is not an upload
+    let mut payload = b"POST /import HTTP/1.1\r\nHost: babi\r\nContent-Length: 8\r\n\r\nbackup=x".to_vec();
+    let resp = local_request(&mut payload[..], import)?;
+    assert_eq!(resp.content(), b"no backup uploaded");
+    Ok(())
+}
+
+#[test]
+fn test_export() -> Result<()> {
+    let session = b"a:3:{s:9:\"babi:demo\";s:4:\"AAAA\";i:7;s:5:\"B B+C\";s:1:\"x\";a:0:{}}";
+    let mut payload = vec![];
+    write!(payload, "GET /export HTTP/1.1\r\nHost: babi\r\nCookie: session={}\r\n\r\n", base64::encode(&session[..]))?;
+    let mut resp = local_request(&mut payload[..], export)?;
+    let mut raw = vec![];
+    resp.to_stream(&mut raw)?;
+    let raw = String::from_utf8(raw)?;
+    assert!(raw.contains("\r\nContent-Disposition: attachment; filename=\"babi.txt\"\r\n"));
+    let backup = "otpauth://totp/babi%3Ademo?secret=AAAA&issuer=babi\n";
+    assert!(raw.contains(&format!("\r\n\r\n{:x}\r\n{}\r\n", backup.len(), backup)));
+    assert!(raw.ends_with("\r\notpauth://totp/7?secret=B+B%2BC&issuer=babi\n\r\n0\r\n\r\n"));
+
+    // and what comes out goes back in
+    let backup = "otpauth://totp/babi%3Ademo?secret=AAAA&issuer=babi\notpauth://totp/7?secret=B+B%2BC&issuer=babi\n";
+    let body = format!("--b\r\nContent-Disposition: form-data; name=\"backup\"; filename=\"babi.txt\"\r\n\r\n{}\r\n--b--", backup);
+    let mut payload = vec![];
+    write!(payload, "POST /import HTTP/1.1\r\nHost: babi\r\nContent-Type: multipart/form-data; boundary=b\r\nContent-Length: {}\r\n\r\n{}",
+           body.len(), body)?;
+    let resp = local_request(&mut payload[..], import)?;
+    let cookie = resp.get_option("Set-Cookie")?;
+    let cookie = base64::decode(&cookie["session=".len()..(cookie.len() - 1)])?;
+    assert_eq!(cookie, b"a:2:{s:9:\"babi:demo\";s:4:\"AAAA\";i:7;s:5:\"B B+C\";}".to_vec());
     Ok(())
 }
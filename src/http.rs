@@ -3,106 +3,636 @@ use std::collections::HashMap;
 
 use crate::errors::*;
 
+// application/x-www-form-urlencoded: `+` is a space and `%XX` a byte. a `%`
+// that does not start a valid escape is kept as is, like PHP's urldecode()
+// and browsers do
+pub fn form_decode(s: &[u8]) -> Vec<u8> {
+    fn hex(c: u8) -> Option<u8> {
+        (c as char).to_digit(16).map(|d| d as u8)
+    }
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        match s[i] {
+            b'+' => out.push(b' '),
+            b'%' => match (s.get(i + 1).and_then(|c| hex(*c)), s.get(i + 2).and_then(|c| hex(*c))) {
+                (Some(h), Some(l)) => {
+                    out.push(h << 4 | l);
+                    i += 2;
+                },
+                _ => out.push(b'%'),
+            },
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    out
+}
+
+// the inverse, escaping everything but the unreserved characters
+pub fn form_encode(s: &[u8]) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s {
+        match c {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(*c as char),
+            b' ' => out.push('+'),
+            _ => out.push_str(&format!("%{:02X}", c)),
+        }
+    }
+    out
+}
+
+// the params of one source in the order they were sent, names repeated
+#[derive(Debug, Default)]
+pub struct Params {
+    pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    // PHP keeps the first of several cookies of the same name (the one for
+    // the most specific path) but the last of several query or form params
+    first_wins: bool,
+}
+
+impl Params {
+    fn push(&mut self, k: Vec<u8>, v: Vec<u8>) {
+        self.pairs.push((k, v));
+    }
+
+    pub fn get(&self, k: &[u8]) -> Option<&Vec<u8>> {
+        let mut all = self.pairs.iter().filter(|(name, _)| name.as_slice() == k).map(|(_, v)| v);
+        if self.first_wins {
+            all.next()
+        } else {
+            all.next_back()
+        }
+    }
+
+    pub fn get_all<'p>(&'p self, k: &'p [u8]) -> impl Iterator<Item = &'p Vec<u8>> + 'p {
+        self.pairs.iter().filter(move |(name, _)| name.as_slice() == k).map(|(_, v)| v)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Vec<u8>)> {
+        self.pairs.iter().map(|(k, v)| (k, v))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+// `name=value&...`, both sides decoded. a pair without `=` is a name with
+// an empty value, empty pairs are skipped
+fn parse_form(raw: &[u8], vars: &mut Params) {
+    for param in raw.split(|c| *c == b'&').filter(|p| !p.is_empty()) {
+        let (k, v) = match param.iter().position(|c| *c == b'=') {
+            Some(i) => (&param[..i], &param[(i + 1)..]),
+            None => (param, &b""[..]),
+        };
+        vars.push(form_decode(k), form_decode(v));
+    }
+}
+
+// RFC 6265 `cookie-octet`: no controls, whitespace, `"`, `,`, `;` or `\`
+fn cookie_octet(c: u8) -> bool {
+    c == 0x21 || (0x23..=0x2b).contains(&c) || (0x2d..=0x3a).contains(&c) || (0x3c..=0x5b).contains(&c) ||
+        (0x5d..=0x7e).contains(&c)
+}
+
+// the `Cookie` header of RFC 6265, section 4.2: `name=value` pairs split
+// by `;` and optional whitespace. values may be quoted and are not
+// decoded, so base64 keeps its `+`. pairs that do not parse, with an empty
+// name or with octets a cookie value cannot hold are skipped
+fn parse_cookies(raw: &[u8], vars: &mut Params) {
+    fn trim(mut s: &[u8]) -> &[u8] {
+        while let [b' ', rest @ ..] | [b'\t', rest @ ..] = s {
+            s = rest;
+        }
+        while let [rest @ .., b' '] | [rest @ .., b'\t'] = s {
+            s = rest;
+        }
+        s
+    }
+    for pair in raw.split(|c| *c == b';') {
+        let pair = trim(pair);
+        let i = match pair.iter().position(|c| *c == b'=') {
+            Some(i) if i > 0 => i,
+            _ => continue
+        };
+        let (k, v) = (&pair[..i], &pair[(i + 1)..]);
+        let v = match v {
+            [b'"', inner @ .., b'"'] => inner,
+            _ => v
+        };
+        // a token, i.e. printable without separators
+        let valid_name = k.iter().all(|c| c.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(c));
+        if valid_name && v.iter().all(|c| cookie_octet(*c)) {
+            vars.push(k.to_vec(), v.to_vec());
+        }
+    }
+}
+
+// limits on multipart bodies, like PHP's max_file_uploads and
+// upload_max_filesize. a body over them is rejected as a whole
+const MAX_PARTS: usize = 20;
+const MAX_PART_HEADERS: usize = 0x2000;
+const MAX_FILE_SIZE: usize = 2 << 20;
+
+// a file part of a multipart/form-data body
+#[derive(Debug)]
+pub struct Upload {
+    pub name: Vec<u8>,
+    // without any directories the client put in
+    pub filename: Vec<u8>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// `value; name=token; name="quoted"` as in Content-Type and
+// Content-Disposition, parameter names lowercased. quoted values end at the
+// next quote, browsers send a quote in a filename as %22
+fn header_params(value: &[u8]) -> (Vec<u8>, Params) {
+    let is_ws = |c: &u8| *c == b' ' || *c == b'\t';
+    let mut params = Params::default();
+    let end = value.iter().position(|c| *c == b';').unwrap_or(value.len());
+    let first = value[..end].iter().filter(|c| !is_ws(c)).map(u8::to_ascii_lowercase).collect();
+    let mut rest = &value[end..];
+    while let Some((_, tail)) = rest.split_first() {
+        let tail = &tail[tail.iter().position(|c| !is_ws(c)).unwrap_or(tail.len())..];
+        let eq = tail.iter().position(|c| *c == b'=' || *c == b';').unwrap_or(tail.len());
+        let name: Vec<u8> = tail[..eq].iter().filter(|c| !is_ws(c)).map(u8::to_ascii_lowercase).collect();
+        if tail.get(eq) != Some(&b'=') {
+            rest = &tail[eq..];
+            continue;
+        }
+        let v = &tail[(eq + 1)..];
+        let v = &v[v.iter().position(|c| !is_ws(c)).unwrap_or(v.len())..];
+        let (v, next) = match v.split_first() {
+            Some((b'"', quoted)) => {
+                let close = quoted.iter().position(|c| *c == b'"').unwrap_or(quoted.len());
+                let after = &quoted[close..];
+                (&quoted[..close], &after[after.iter().position(|c| *c == b';').unwrap_or(after.len())..])
+            },
+            _ => {
+                let semi = v.iter().position(|c| *c == b';').unwrap_or(v.len());
+                let mut token = &v[..semi];
+                while let [t @ .., b' '] | [t @ .., b'\t'] = token {
+                    token = t;
+                }
+                (token, &v[semi..])
+            }
+        };
+        params.push(name, v.to_vec());
+        rest = next;
+    }
+    (first, params)
+}
+
+// the boundary of a multipart/form-data Content-Type, or None for any
+// other type. RFC 2046 caps boundaries at 70 characters
+fn multipart_boundary(content_type: &str) -> Result<Option<Vec<u8>>> {
+    let (media_type, params) = header_params(content_type.as_bytes());
+    if media_type != b"multipart/form-data" {
+        return Ok(None);
+    }
+    match params.get(b"boundary") {
+        Some(b) if !b.is_empty() && b.len() <= 70 => Ok(Some(b.clone())),
+        _ => bail!("multipart/form-data without a valid boundary")
+    }
+}
+
+// a multipart/form-data body: parts between `--boundary` lines, each with
+// its own headers, up to a closing `--boundary--`. parts with a filename
+// are uploads, unless the filename is empty (no file was picked), the
+// others are form params. the preamble and the epilogue are ignored
+fn parse_multipart(body: &[u8], boundary: &[u8], form: &mut Params, files: &mut Vec<Upload>) -> Result<()> {
+    let mut delim = b"\r\n--".to_vec();
+    delim.extend_from_slice(boundary);
+    // the first delimiter may open the body without a line before it
+    let mut pos = if body.starts_with(&delim[2..]) {
+        delim.len() - 2
+    } else {
+        match find(body, &delim) {
+            Some(i) => i + delim.len(),
+            None => bail!("multipart body without a boundary")
+        }
+    };
+    let mut parts = 0;
+    loop {
+        let rest = &body[pos..];
+        if rest.starts_with(b"--") {
+            return Ok(());
+        }
+        // transport padding, then the line break
+        let start = rest.iter().position(|c| *c != b' ' && *c != b'\t').unwrap_or(rest.len());
+        if !rest[start..].starts_with(b"\r\n") {
+            bail!("bad multipart delimiter at {}", pos);
+        }
+        let part = &rest[(start + 2)..];
+        let len = match find(part, &delim) {
+            Some(len) => len,
+            None => bail!("unterminated multipart body")
+        };
+        pos += start + 2 + len + delim.len();
+
+        parts += 1;
+        if parts > MAX_PARTS {
+            bail!("more than {} multipart parts", MAX_PARTS);
+        }
+        let part = &part[..len];
+        let (headers, data) = if part.starts_with(b"\r\n") {
+            (&b""[..], &part[2..])
+        } else {
+            match find(part, b"\r\n\r\n") {
+                Some(i) => (&part[..i], &part[(i + 4)..]),
+                None => bail!("multipart part without a body")
+            }
+        };
+        if headers.len() > MAX_PART_HEADERS {
+            bail!("multipart part headers over {} bytes", MAX_PART_HEADERS);
+        }
+
+        let mut disposition = None;
+        let mut content_type = None;
+        for line in headers.split(|c| *c == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let colon = match line.iter().position(|c| *c == b':') {
+                Some(i) => i,
+                None => continue
+            };
+            let value = &line[(colon + 1)..];
+            match line[..colon].to_ascii_lowercase().as_slice() {
+                b"content-disposition" => disposition = Some(header_params(value)),
+                b"content-type" => content_type = Some(String::from_utf8_lossy(value).trim().to_string()),
+                _ => ()
+            }
+        }
+        let params = match disposition {
+            Some((kind, params)) if kind == b"form-data" => params,
+            _ => continue
+        };
+        let name = match params.get(b"name") {
+            Some(name) => name.clone(),
+            None => continue
+        };
+        match params.get(b"filename") {
+            Some(filename) => {
+                let base = filename.iter().rposition(|c| *c == b'/' || *c == b'\\').map_or(0, |i| i + 1);
+                if base == filename.len() {
+                    continue;
+                }
+                if data.len() > MAX_FILE_SIZE {
+                    bail!("uploaded file over {} bytes", MAX_FILE_SIZE);
+                }
+                files.push(Upload {
+                    name,
+                    filename: filename[base..].to_vec(),
+                    content_type,
+                    data: data.to_vec(),
+                });
+            },
+            None => form.push(name, data.to_vec()),
+        }
+    }
+}
+
+// limits on the request head and body. a request line over its limit is
+// answered with 414 since it is the target that makes it long
+const MAX_REQUEST_LINE: usize = 8192;
+const MAX_HEADER_BYTES: usize = 16384;
+const MAX_HEADERS: usize = 100;
+const MAX_CHUNK_LINE: usize = 1024;
+const MAX_BODY: usize = 4 << 20;
+
+// a request we answer with `status` and then hang up on
+fn reject<T>(status: u32, reason: &str) -> Result<T> {
+    bail!(ErrorKind::Http(status, reason.to_string()))
+}
+
+// a line of at most `limit` bytes with its CRLF, which is stripped. a bare
+// CR or LF is an error, a longer line is answered with `too_long`, and
+// running out of input is an I/O error like any other
+fn read_line<T: Read>(stream: &mut BufReader<T>, limit: usize, too_long: u32) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(0x100);
+    stream.by_ref().take(limit as u64).read_until(b'\n', &mut buf)?;
+    if buf.last() != Some(&b'\n') {
+        if buf.len() == limit {
+            return reject(too_long, "line too long");
+        }
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    buf.pop();
+    if buf.pop() != Some(b'\r') || buf.contains(&b'\r') {
+        return reject(400, "bare CR or LF");
+    }
+    Ok(buf)
+}
+
+// RFC 9110 `tchar`, what methods and field names are made of
+fn is_tchar(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+// `HTTP/x.y`. only major version 1 is spoken here
+fn parse_version(version: &str) -> Result<(u8, u8)> {
+    match version.as_bytes() {
+        [b'H', b'T', b'T', b'P', b'/', major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit() => {
+            if *major != b'1' {
+                return reject(505, "only HTTP/1.x is supported");
+            }
+            Ok((1, minor - b'0'))
+        },
+        _ => reject(400, "bad HTTP version")
+    }
+}
+
+// origin-form `/path?query`, absolute-form `http://host/path?query` as sent
+// to proxies, which servers have to accept as well, or `*` for OPTIONS.
+// the authority of the absolute form replaces any Host
+fn request_target(method: &str, target: &str) -> Result<(String, Option<String>)> {
+    if target.is_empty() || !target.bytes().all(|c| c.is_ascii_graphic()) {
+        return reject(400, "bad request target");
+    }
+    if target.starts_with('/') || (target == "*" && method == "OPTIONS") {
+        return Ok((target.to_string(), None));
+    }
+    for scheme in &["http://", "https://"] {
+        if target.len() > scheme.len() && target[..scheme.len()].eq_ignore_ascii_case(scheme) {
+            let rest = &target[scheme.len()..];
+            let end = rest.find(['/', '?']).unwrap_or(rest.len());
+            if end == 0 {
+                break;
+            }
+            let path = match &rest[end..] {
+                "" => "/".to_string(),
+                p if p.starts_with('?') => format!("/{}", p),
+                p => p.to_string(),
+            };
+            return Ok((path, Some(rest[..end].to_string())));
+        }
+    }
+    reject(400, "bad request target")
+}
+
+// field-line = field-name ":" OWS field-value OWS (RFC 9112, section 5).
+// whitespace before the colon and values folded onto the next line
+// (obs-fold) are rejected since proxies disagree on what they mean
+fn parse_field(line: &[u8]) -> Result<(String, String)> {
+    if line.starts_with(b" ") || line.starts_with(b"\t") {
+        return reject(400, "obsolete line folding");
+    }
+    let colon = match line.iter().position(|c| *c == b':') {
+        Some(i) => i,
+        None => return reject(400, "header without a colon")
+    };
+    let name = &line[..colon];
+    if name.is_empty() || !name.iter().all(|c| is_tchar(*c)) {
+        return reject(400, "bad header name");
+    }
+    let mut value = &line[(colon + 1)..];
+    while let [b' ', rest @ ..] | [b'\t', rest @ ..] = value {
+        value = rest;
+    }
+    while let [rest @ .., b' '] | [rest @ .., b'\t'] = value {
+        value = rest;
+    }
+    if value.iter().any(|c| (*c < 0x20 && *c != b'\t') || *c == 0x7f) {
+        return reject(400, "bad header value");
+    }
+    // obs-text is allowed in values but has no charset, so bytes that are
+    // not UTF-8 only show up as U+FFFD
+    Ok((String::from_utf8(name.to_ascii_uppercase())?, String::from_utf8_lossy(value).into_owned()))
+}
+
+// repeated fields are one comma separated list (RFC 9110, section 5.3),
+// cookies are joined with `; ` the way RFC 6265 sends them. a second Host
+// could be read as either one, so it is an error
+fn add_field(fields: &mut HashMap<String, String>, name: String, value: String) -> Result<()> {
+    match fields.get_mut(&name) {
+        None => {
+            fields.insert(name, value);
+        },
+        Some(_) if name == "HOST" => return reject(400, "more than one Host"),
+        Some(old) => {
+            old.push_str(if name == "COOKIE" { "; " } else { ", " });
+            old.push_str(&value);
+        }
+    }
+    Ok(())
+}
+
+// header or trailer fields up to an empty line, `MAX_HEADER_BYTES` and
+// `MAX_HEADERS` at most
+fn read_fields<T: Read>(stream: &mut BufReader<T>, fields: &mut HashMap<String, String>) -> Result<()> {
+    let mut size = 0;
+    for _ in 0..=MAX_HEADERS {
+        let line = read_line(stream, MAX_HEADER_BYTES - size, 431)?;
+        if line.is_empty() {
+            return Ok(());
+        }
+        size += line.len() + 2;
+        let (name, value) = parse_field(&line)?;
+        add_field(fields, name, value)?;
+    }
+    reject(431, "too many header fields")
+}
+
+// Content-Length = 1*DIGIT. a list repeating one value, as some proxies
+// send it, is that value, a list of different ones an error
+fn parse_content_length(value: &str) -> Result<usize> {
+    let mut length = None;
+    for v in value.split(',').map(|v| v.trim_matches([' ', '\t'])) {
+        if v.is_empty() || !v.bytes().all(|c| c.is_ascii_digit()) {
+            return reject(400, "bad Content-Length");
+        }
+        let v = match v.parse() {
+            Ok(v) => v,
+            Err(_) => return reject(413, "body too large")
+        };
+        if length.is_some_and(|l| l != v) {
+            return reject(400, "conflicting Content-Length");
+        }
+        length = Some(v);
+    }
+    Ok(length.unwrap_or(0))
+}
+
+// how the body is framed (RFC 9112, section 6.3): chunked when it is the
+// final transfer coding, else by Content-Length. both at once, or transfer
+// codings in an HTTP/1.0 request, are how requests get smuggled past a
+// proxy, so they are errors. returns whether the body is chunked
+fn transfer_coding(fields: &HashMap<String, String>, version: (u8, u8)) -> Result<bool> {
+    let codings = match fields.get("TRANSFER-ENCODING") {
+        Some(codings) => codings,
+        None => return Ok(false)
+    };
+    if version < (1, 1) {
+        return reject(400, "Transfer-Encoding in an HTTP/1.0 request");
+    }
+    if fields.contains_key("CONTENT-LENGTH") {
+        return reject(400, "both Transfer-Encoding and Content-Length");
+    }
+    let codings: Vec<String> = codings.split(',').map(|c| c.trim_matches([' ', '\t']).to_lowercase()).collect();
+    match codings.split_last() {
+        Some((last, rest)) if last == "chunked" => {
+            if rest.iter().any(|c| c == "chunked") {
+                return reject(400, "chunked applied more than once");
+            }
+            if !rest.is_empty() {
+                return reject(501, "unsupported transfer coding");
+            }
+            Ok(true)
+        },
+        _ => reject(400, "chunked is not the final transfer coding")
+    }
+}
+
+// a chunked body (RFC 9112, section 7.1): chunks of a hex size, optional
+// extensions we ignore and a line break, then a last chunk of size 0 and
+// trailer fields up to an empty line
+fn read_chunked<T: Read>(stream: &mut BufReader<T>, trailers: &mut HashMap<String, String>) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let line = String::from_utf8(read_line(stream, MAX_CHUNK_LINE, 400)?)?;
+        let size = line.split(';').next().unwrap_or("").trim_end_matches([' ', '\t']);
+        if size.is_empty() || !size.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return reject(400, "bad chunk size");
+        }
+        let size = match usize::from_str_radix(size, 16) {
+            Ok(size) if size <= MAX_BODY - body.len() => size,
+            _ => return reject(413, "body too large")
+        };
+        if size == 0 {
+            break;
+        }
+        // read as it comes rather than trusting the size up front
+        if stream.by_ref().take(size as u64).read_to_end(&mut body)? != size {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        if !read_line(stream, 2, 400)?.is_empty() {
+            return reject(400, "chunk longer than its size");
+        }
+    }
+    read_fields(stream, trailers)?;
+    Ok(body)
+}
+
 #[derive(Debug)]
 pub struct HttpRequest {
     keep_alive: bool,
     content_length: usize,
     method: String,
     path: String,
+    version: (u8, u8),
     body: Vec<u8>,
     env: HashMap<String, String>,
-    vars: HashMap<Vec<u8>, Vec<u8>>,
+    trailers: HashMap<String, String>,
+    query: Params,
+    form: Params,
+    cookies: Params,
+    files: Vec<Upload>,
 }
 
 impl HttpRequest {
+    // errors with ErrorKind::Http are the client's and get an answer, I/O
+    // errors mean it is gone
     pub fn from_stream<T>(stream: &mut BufReader<T>) -> Result<Self> where T: Read {
-        let method;
-        let mut path;
         let mut env = HashMap::new();
-        let mut vars = HashMap::new();
-
-        // parse method/path
-        {
-            let mut buf = Vec::with_capacity(0x100);
-            stream.read_until(b'\n', &mut buf)?;
-            let s = String::from_utf8(buf)?;
-            let v: Vec<&str> = s.split_whitespace().collect();
-            if v.len() < 2 {
-                bail!(ErrorKind::Invalid)
-            }
-            method = v[0].to_string();
-            path = v[1].to_string();
-        }
-
-        fn parse_param(raw: &[u8], sep: u8, vars: &mut HashMap<Vec<u8>, Vec<u8>>) {
-            for param in raw.split(|c| *c == sep) {
-                for i in 0..param.len() {
-                    if param[i] == b'=' {
-                        let k = &param[..i];
-                        let v = &param[(i + 1)..];
-                        vars.insert(k.to_vec(), v.to_vec());
-                        break;
-                    }
-                }
+        let mut query = Params::default();
+        let mut form = Params::default();
+        let mut cookies = Params { first_wins: true, ..Default::default() };
+        let mut files = vec![];
+
+        // request-line = method SP request-target SP HTTP-version, after
+        // the empty lines some clients send after a body
+        let mut line = read_line(stream, MAX_REQUEST_LINE, 414)?;
+        for _ in 0..4 {
+            if !line.is_empty() {
+                break;
             }
+            line = read_line(stream, MAX_REQUEST_LINE, 414)?;
+        }
+        let line = match String::from_utf8(line) {
+            Ok(line) => line,
+            Err(_) => return reject(400, "bad request line")
+        };
+        let v: Vec<&str> = line.split(' ').collect();
+        if v.len() != 3 || v[0].is_empty() || !v[0].bytes().all(is_tchar) {
+            return reject(400, "bad request line");
         }
+        let method = v[0].to_string();
+        let version = parse_version(v[2])?;
+        let (mut path, authority) = request_target(v[0], v[1])?;
 
         // parse url
         if let Some(pos) = path.find('?') {
-            parse_param(&path[(pos + 1)..].as_bytes(), b'&', &mut vars);
+            parse_form(&path.as_bytes()[(pos + 1)..], &mut query);
             path.truncate(pos);
         }
 
-        // println!("method = {:?} path = {:?}", method, path);
-
-        // options
-        loop {
-            let mut buf = Vec::with_capacity(0x100);
-            if stream.read_until(b'\n', &mut buf)? == 2 {
-                break;
-            }
-            let s = String::from_utf8(buf)?;
-            let v: Vec<&str> = s.splitn(2, ": ").collect();
-            if v.len() == 2 {
-                env.insert(v[0].trim().to_uppercase(), v[1].trim().to_string());
-            }
+        read_fields(stream, &mut env)?;
+        if let Some(authority) = authority {
+            env.insert("HOST".to_string(), authority);
         }
-
-        let mut keep_alive = false;
-        if let Some(opt) = env.get("CONNECTION") {
-            keep_alive = opt.to_lowercase() == "keep-alive";
+        if version >= (1, 1) && !env.contains_key("HOST") {
+            return reject(400, "no Host");
         }
 
-        let mut content_length = 0;
-        if let Some(opt) = env.get("CONTENT-LENGTH") {
-            content_length = opt.parse()?;
-        }
+        // HTTP/1.1 connections persist unless the client says close,
+        // HTTP/1.0 ones only when it asks for keep-alive
+        let connection: Vec<String> = env.get("CONNECTION")
+            .map_or(vec![], |c| c.split(',').map(|t| t.trim_matches([' ', '\t']).to_lowercase()).collect());
+        let keep_alive = !connection.iter().any(|t| t == "close") &&
+            (version >= (1, 1) || connection.iter().any(|t| t == "keep-alive"));
 
-        if let Some(cookie) = env.get("COOKIE") {
-            parse_param(cookie.as_bytes(), b';', &mut vars);
+        let chunked = transfer_coding(&env, version)?;
+        let mut content_length = match env.get("CONTENT-LENGTH") {
+            Some(v) => Some(parse_content_length(v)?),
+            None => None
+        };
+        if !chunked && content_length.is_none() && (method == "POST" || method == "PUT") {
+            return reject(411, "Content-Length required");
+        }
+        if content_length.is_some_and(|l| l > MAX_BODY) {
+            return reject(413, "body too large");
         }
 
-        for (k, v) in &env {
-            if k.starts_with("HTTP_") {
-                std::env::set_var(k.get(5..).unwrap(), v);
-            }
+        if let Some(cookie) = env.get("COOKIE") {
+            parse_cookies(cookie.as_bytes(), &mut cookies);
         }
 
-        let mut body = Vec::new();
-        body.resize(content_length, 0);
-        stream.read_exact(&mut body)?;
+        let mut trailers = HashMap::new();
+        let body = if chunked {
+            let body = read_chunked(stream, &mut trailers)?;
+            content_length = Some(body.len());
+            body
+        } else {
+            let mut body = vec![0; content_length.unwrap_or(0)];
+            stream.read_exact(&mut body)?;
+            body
+        };
 
-        // try parse body arguments
-        parse_param(&body, b'&', &mut vars);
+        // multipart bodies are split into params and files, anything else
+        // is tried as a urlencoded form
+        match multipart_boundary(env.get("CONTENT-TYPE").map_or("", |t| t.as_str()))? {
+            Some(boundary) => parse_multipart(&body, &boundary, &mut form, &mut files)?,
+            None => parse_form(&body, &mut form),
+        }
 
         Ok(HttpRequest {
-            keep_alive: keep_alive,
-            content_length: content_length,
-            method: method,
-            path: path,
-            env: env,
-            vars: vars,
-            body: body
+            keep_alive,
+            content_length: content_length.unwrap_or(0),
+            method,
+            path,
+            version,
+            env,
+            trailers,
+            query,
+            form,
+            cookies,
+            files,
+            body,
         })
     }
 
@@ -118,18 +648,97 @@ impl HttpRequest {
         &self.method
     }
 
+    pub fn version(&self) -> (u8, u8) {
+        self.version
+    }
+
+    pub fn content_length(&self) -> usize {
+        self.content_length
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn header(&self, k: &str) -> Option<&str> {
+        self.env.get(&k.to_uppercase()).map(|v| v.as_str())
+    }
+
+    // a field sent after a chunked body. kept apart from the headers since
+    // they were not there when the request was routed
+    pub fn trailer(&self, k: &str) -> Option<&str> {
+        self.trailers.get(&k.to_uppercase()).map(|v| v.as_str())
+    }
+
+    pub fn query(&self) -> &Params {
+        &self.query
+    }
+
+    pub fn form(&self) -> &Params {
+        &self.form
+    }
+
+    pub fn cookies(&self) -> &Params {
+        &self.cookies
+    }
+
+    pub fn files(&self) -> &[Upload] {
+        &self.files
+    }
+
+    // the first file uploaded under `name`
+    pub fn file(&self, name: &[u8]) -> Option<&Upload> {
+        self.files.iter().find(|f| f.name == name)
+    }
+
+    // a form param, else a query param, like PHP's $_REQUEST with
+    // request_order = "GP". cookies are never looked at
     pub fn get(&self, k: &[u8]) -> Result<&Vec<u8>> {
-        match self.vars.get(k) {
+        match self.form.get(k).or_else(|| self.query.get(k)) {
             Some(v) => Ok(v),
             _ => bail!(ErrorKind::Unknown)
         }
     }
 }
 
+// a body produced while it is being sent
+type WriteBody = dyn FnOnce(&mut dyn Write) -> Result<()>;
+
+struct BodyWriter(Box<WriteBody>);
+
+impl std::fmt::Debug for BodyWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BodyWriter")
+    }
+}
+
+// frames every write as a chunk of its own. an empty chunk would end the
+// body, so empty writes are dropped
+struct ChunkedWriter<'w, T: Write> {
+    inner: &'w mut T,
+}
+
+impl<'w, T: Write> Write for ChunkedWriter<'w, T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !buf.is_empty() {
+            write!(self.inner, "{:x}\r\n", buf.len())?;
+            self.inner.write_all(buf)?;
+            self.inner.write_all(b"\r\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 #[derive(Debug)]
 pub struct HttpResponse {
     status: u32,
     response: Vec<u8>,
+    stream: Option<BodyWriter>,
+    chunked: bool,
     env: HashMap<String, String>,
 }
 
@@ -138,35 +747,102 @@ impl HttpResponse {
         let mut env = HashMap::new();
         env.insert("Content-Length".to_string(), response.len().to_string());
         HttpResponse {
-            status: status,
-            response: response,
-            env: env
+            status,
+            response,
+            stream: None,
+            chunked: false,
+            env,
+        }
+    }
+
+    // a body written as `body` produces it, sent chunked since its length
+    // is not known up front. if `body` fails halfway the last chunk is never
+    // sent, so the client can tell the body was cut short
+    pub fn streaming<F>(status: u32, body: F) -> Self where F: FnOnce(&mut dyn Write) -> Result<()> + 'static {
+        let mut env = HashMap::new();
+        env.insert("Transfer-Encoding".to_string(), "chunked".to_string());
+        HttpResponse {
+            status,
+            response: vec![],
+            stream: Some(BodyWriter(Box::new(body))),
+            chunked: true,
+            env,
         }
     }
 
+    // the answer to a request that could not be read, or None when the
+    // client is gone and there is no one to answer
+    pub fn from_request_error(e: &Error) -> Option<Self> {
+        let (status, reason) = match e.kind() {
+            ErrorKind::IoError(_) => return None,
+            ErrorKind::Http(status, reason) => (*status, reason.clone()),
+            _ => (400, e.to_string()),
+        };
+        let mut resp = HttpResponse::new(status, reason.into_bytes());
+        resp.set_option("Connection".to_string(), "close".to_string());
+        Some(resp)
+    }
+
+    pub fn is_streamed(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    // HTTP/1.0 has no chunked coding, so a streamed body is sent as is and
+    // ends when the connection is closed
+    pub fn unchunked(&mut self) -> &mut Self {
+        if self.chunked {
+            self.env.remove("Transfer-Encoding");
+            self.chunked = false;
+        }
+        self
+    }
+
     #[inline]
     fn desc(&self) -> &str {
         match self.status {
             200 => "OK",
             301 => "Moved Permanently",
+            400 => "Bad Request",
             404 => "Not Found",
+            411 => "Length Required",
+            413 => "Content Too Large",
+            414 => "URI Too Long",
+            415 => "Unsupported Media Type",
+            431 => "Request Header Fields Too Large",
             500 => "Internal Server Error",
+            501 => "Not Implemented",
+            505 => "HTTP Version Not Supported",
             _ => "Unknown Error"
         }
     }
 
+    #[inline]
+    pub fn status(&self) -> u32 {
+        self.status
+    }
+
+    // empty for a streamed body
     #[inline]
     pub fn content(&self) -> &[u8] {
         &self.response
     }
 
-    pub fn to_stream<T>(&self, stream: &mut T) -> Result<()> where T: Write {
+    // a streamed body can only be sent once
+    pub fn to_stream<T>(&mut self, stream: &mut T) -> Result<()> where T: Write {
         stream.write_fmt(format_args!("HTTP/1.1 {} {}\r\nServer: BABI/0.1\r\n", self.status, self.desc()))?;
         for (k, v) in &self.env {
             stream.write_fmt(format_args!("{}: {}\r\n", k, v))?;
         }
         stream.write_all(b"\r\n")?;
-        stream.write_all(&self.response)?;
+        match self.stream.take() {
+            Some(BodyWriter(body)) if self.chunked => {
+                body(&mut ChunkedWriter { inner: stream })?;
+                stream.write_all(b"0\r\n\r\n")?;
+            },
+            Some(BodyWriter(body)) => body(stream)?,
+            None => stream.write_all(&self.response)?,
+        }
+        stream.flush()?;
         Ok(())
     }
 
@@ -177,9 +853,304 @@ impl HttpResponse {
 
     pub fn get_option(&self, option: &str) -> Result<&str> {
         if let Some(v) = self.env.get(option) {
-            Ok(&v)
+            Ok(v)
         } else {
             bail!(ErrorKind::Invalid)
         }
     }
 }
+
+#[test]
+fn test_form_decode() {
+    assert_eq!(form_decode(b"my%20phone"), b"my phone".to_vec());
+    assert_eq!(form_decode(b"a+b%2Bc"), b"a b+c".to_vec());
+    assert_eq!(form_decode(b"%00%ff%FF"), b"\x00\xff\xff".to_vec());
+    // broken escapes are kept
+    assert_eq!(form_decode(b"100%"), b"100%".to_vec());
+    assert_eq!(form_decode(b"%zz%4"), b"%zz%4".to_vec());
+    assert_eq!(form_decode(b"%%41"), b"%A".to_vec());
+    // and bytes that should have been escaped pass through
+    assert_eq!(form_decode(b"\xff\x00"), b"\xff\x00".to_vec());
+
+    let raw = b"a b+c/d=\x00\xff~";
+    assert_eq!(form_encode(raw), "a+b%2Bc%2Fd%3D%00%FF~");
+    assert_eq!(form_decode(form_encode(raw).as_bytes()), raw.to_vec());
+}
+
+#[test]
+fn test_request_params() -> Result<()> {
+    let raw = b"POST /enroll?label=my%20phone&flag&&x=%zz HTTP/1.1\r\nHost: babi\r\n\
+                Cookie: session=\"YWJj+/==\"; bad=a b;=x; ws = y ;quo\"te=1; last=%20\r\n\
+                Content-Length: 16\r\n\r\n\
+                secret=a+b%26c=d";
+    let req = HttpRequest::from_stream(&mut BufReader::new(&raw[..]))?;
+    assert_eq!(req.get(b"label")?, b"my phone");
+    assert_eq!(req.get(b"flag")?, b"");
+    assert_eq!(req.get(b"x")?, b"%zz");
+    assert_eq!(req.get(b"secret")?, b"a b&c=d");
+    // cookie values are taken as is
+    let cookies = req.cookies();
+    assert_eq!(cookies.get(b"session").unwrap(), b"YWJj+/==");
+    assert_eq!(cookies.get(b"last").unwrap(), b"%20");
+    for k in &[&b"bad"[..], b"", b"ws", b"ws ", b"quo\"te"] {
+        assert!(cookies.get(k).is_none(), "{:?}", k);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_request_namespaces() -> Result<()> {
+    let raw = b"POST /enroll?label=q&a=1&a=2&session=q HTTP/1.1\r\nHost: babi\r\n\
+                Cookie: label=c; session=c1; session=c2\r\n\
+                Content-Length: 15\r\n\r\n\
+                label=f&b=3&b=4";
+    let req = HttpRequest::from_stream(&mut BufReader::new(&raw[..]))?;
+    assert_eq!(req.query().get(b"label").unwrap(), b"q");
+    assert_eq!(req.form().get(b"label").unwrap(), b"f");
+    assert_eq!(req.cookies().get(b"label").unwrap(), b"c");
+    // the form wins over the query and cookies are left out
+    assert_eq!(req.get(b"label")?, b"f");
+    assert_eq!(req.get(b"session")?, b"q");
+    assert!(req.form().get(b"session").is_none());
+
+    // repeated names: the last param but the first cookie
+    assert_eq!(req.get(b"a")?, b"2");
+    assert_eq!(req.query().get_all(b"a").collect::<Vec<_>>(), vec![b"1", b"2"]);
+    assert_eq!(req.form().get_all(b"b").collect::<Vec<_>>(), vec![b"3", b"4"]);
+    assert_eq!(req.cookies().get(b"session").unwrap(), b"c1");
+    assert_eq!(req.cookies().get_all(b"session").count(), 2);
+    assert_eq!(req.cookies().iter().count(), 3);
+    Ok(())
+}
+
+#[cfg(test)]
+fn multipart_request(content_type: &str, body: &[u8]) -> Result<HttpRequest> {
+    let mut raw = format!("POST /import?a=q HTTP/1.1\r\nHost: babi\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                          content_type, body.len()).into_bytes();
+    raw.extend_from_slice(body);
+    HttpRequest::from_stream(&mut BufReader::new(&raw[..]))
+}
+
+#[test]
+fn test_multipart() -> Result<()> {
+    let body = b"preamble\r\n--xyz\r\n\
+                 Content-Disposition: form-data; name=\"a\"\r\n\r\n\
+                 1&b=2\r\n--xyz  \r\n\
+                 content-disposition: form-data; name=\"backup\"; filename=\"C:\\tmp\\my keys.txt\"\r\n\
+                 Content-Type: text/plain\r\n\r\n\
+                 line\r\n--xy\r\n\x00\xff\r\n--xyz\r\n\
+                 Content-Disposition: form-data; name=\"empty\"; filename=\"\"\r\n\r\n\
+                 \r\n--xyz\r\n\
+                 Content-Disposition: form-data; name=none\r\n\r\n\
+                 \r\n--xyz\r\n\
+                 Content-Disposition: attachment; name=\"skipped\"\r\n\r\n\
+                 x\r\n--xyz--\r\nepilogue";
+    let req = multipart_request("Multipart/Form-Data; charset=utf-8; boundary=\"xyz\"", body)?;
+    assert_eq!(req.get(b"a")?, b"1&b=2");
+    assert_eq!(req.query().get(b"a").unwrap(), b"q");
+    assert!(req.form().get(b"b").is_none());
+    assert_eq!(req.form().get(b"none").unwrap(), b"");
+    assert!(req.form().get(b"skipped").is_none());
+
+    // the empty filename is no file at all
+    assert_eq!(req.files().len(), 1);
+    let file = req.file(b"backup").unwrap();
+    assert_eq!(file.filename, b"my keys.txt");
+    assert_eq!(file.content_type.as_ref().unwrap(), "text/plain");
+    assert_eq!(file.data, b"line\r\n--xy\r\n\x00\xff".to_vec());
+    assert!(req.file(b"empty").is_none());
+
+    // no preamble, and a part without headers
+    let req = multipart_request("multipart/form-data;boundary=b", b"--b\r\n\r\nx\r\n--b--")?;
+    assert!(req.form().is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_multipart_errors() {
+    let part = |i: usize| format!("--b\r\nContent-Disposition: form-data; name=\"p{}\"\r\n\r\nv\r\n", i);
+    let many: String = (0..(MAX_PARTS + 1)).map(part).collect::<String>() + "--b--";
+    assert!(multipart_request("multipart/form-data; boundary=b", &many.as_bytes()[part(0).len()..]).is_ok());
+    assert!(multipart_request("multipart/form-data; boundary=b", many.as_bytes()).is_err());
+
+    let mut big = b"--b\r\nContent-Disposition: form-data; name=\"f\"; filename=\"f\"\r\n\r\n".to_vec();
+    big.resize(big.len() + MAX_FILE_SIZE + 1, b'x');
+    big.extend_from_slice(b"\r\n--b--");
+    assert!(multipart_request("multipart/form-data; boundary=b", &big).is_err());
+
+    for (content_type, body) in &[
+        ("multipart/form-data", &b"--b\r\n\r\nx\r\n--b--"[..]),
+        ("multipart/form-data; boundary=\"\"", b"--\r\n\r\nx\r\n----"),
+        ("multipart/form-data; boundary=b", b"x"),
+        ("multipart/form-data; boundary=b", b"--b\r\n\r\nx"),
+        ("multipart/form-data; boundary=b", b"--bx\r\n\r\nx\r\n--b--"),
+        ("multipart/form-data; boundary=b", b"--b\r\nContent-Disposition: form-data\r\n--b--"),
+    ] {
+        assert!(multipart_request(content_type, body).is_err(), "{:?}", body);
+    }
+    let long = format!("multipart/form-data; boundary={}", "b".repeat(71));
+    assert!(multipart_request(&long, b"").is_err());
+}
+
+#[test]
+fn test_chunked_request() -> Result<()> {
+    let raw = b"POST /enroll HTTP/1.1\r\nHost: babi\r\n\
+                Transfer-Encoding: Chunked\r\n\r\n\
+                7\r\nlabel=a\r\n\
+                A;ext=\"x;y\"\r\n&secret=bc\r\n\
+                0\r\n\
+                Checksum: 1234\r\n\r\n\
+                GET / HTTP/1.1\r\nHost: babi\r\n\r\n";
+    let mut stream = BufReader::new(&raw[..]);
+    let req = HttpRequest::from_stream(&mut stream)?;
+    assert_eq!(req.body(), b"label=a&secret=bc");
+    assert_eq!(req.content_length(), 17);
+    assert_eq!(req.get(b"secret")?, b"bc");
+    assert_eq!(req.trailer("checksum"), Some("1234"));
+    assert_eq!(req.header("checksum"), None);
+    // the connection is left at the next request
+    assert_eq!(HttpRequest::from_stream(&mut stream)?.path(), "/");
+
+    for body in &[&b"3\r\nabc\r\n"[..], b"3\r\nab", b"3\r\nabcd\r\n0\r\n\r\n", b"x\r\n\r\n", b"+3\r\nabc\r\n0\r\n\r\n",
+                  b"1ffffffffffffffff\r\n", b"0\r\n"] {
+        let mut raw = b"POST / HTTP/1.1\r\nHost: babi\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        raw.extend_from_slice(body);
+        assert!(HttpRequest::from_stream(&mut BufReader::new(&raw[..])).is_err(), "{:?}", body);
+    }
+    let raw = b"POST / HTTP/1.1\r\nHost: babi\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n";
+    assert!(HttpRequest::from_stream(&mut BufReader::new(&raw[..])).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_streaming_response() -> Result<()> {
+    let mut resp = HttpResponse::streaming(200, |out| {
+        out.write_all(b"hello ")?;
+        out.write_all(b"")?;
+        out.write_all(&[b'x'; 20])?;
+        Ok(())
+    });
+    let mut raw = vec![];
+    resp.to_stream(&mut raw)?;
+    let raw = String::from_utf8(raw)?;
+    assert!(raw.contains("\r\nTransfer-Encoding: chunked\r\n"));
+    assert!(!raw.contains("Content-Length"));
+    assert!(raw.ends_with(&format!("\r\n\r\n6\r\nhello \r\n14\r\n{}\r\n0\r\n\r\n", "x".repeat(20))));
+
+    // a failure leaves out the last chunk
+    let mut resp = HttpResponse::streaming(200, |out| {
+        out.write_all(b"part")?;
+        bail!("failed")
+    });
+    let mut raw = vec![];
+    assert!(resp.to_stream(&mut raw).is_err());
+    assert!(raw.ends_with(b"\r\n\r\n4\r\npart\r\n"));
+    Ok(())
+}
+
+#[cfg(test)]
+fn rejected_with(raw: &[u8]) -> Option<u32> {
+    let e = HttpRequest::from_stream(&mut BufReader::new(raw)).expect_err("accepted");
+    HttpResponse::from_request_error(&e).map(|resp| resp.status)
+}
+
+#[test]
+fn test_strict_request() -> Result<()> {
+    let long_target = format!("GET /{} HTTP/1.1\r\nHost: babi\r\n\r\n", "a".repeat(MAX_REQUEST_LINE));
+    let long_header = format!("GET / HTTP/1.1\r\nHost: babi\r\nX: {}\r\n\r\n", "a".repeat(MAX_HEADER_BYTES));
+    let many_headers = format!("GET / HTTP/1.1\r\nHost: babi\r\n{}\r\n", "X: a\r\n".repeat(MAX_HEADERS));
+    let big_body = format!("POST / HTTP/1.1\r\nHost: babi\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1);
+    let big_chunk = format!("POST / HTTP/1.1\r\nHost: babi\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n", MAX_BODY + 1);
+    for (raw, status) in &[
+        (&b"GET  / HTTP/1.1\r\nHost: babi\r\n\r\n"[..], 400),
+        (b"GET / HTTP/1.1 \r\nHost: babi\r\n\r\n", 400),
+        (b"G(T / HTTP/1.1\r\nHost: babi\r\n\r\n", 400),
+        (b"GET / http/1.1\r\nHost: babi\r\n\r\n", 400),
+        (b"GET / HTTP/1.1\nHost: babi\r\n\r\n", 400),
+        (b"GET / HTTP/1.1\r\nHost: babi\r\r\n\r\n", 400),
+        (b"GET a HTTP/1.1\r\nHost: babi\r\n\r\n", 400),
+        (b"GET /\x7f HTTP/1.1\r\nHost: babi\r\n\r\n", 400),
+        (b"GET * HTTP/1.1\r\nHost: babi\r\n\r\n", 400),
+        (b"GET http:///a HTTP/1.1\r\nHost: babi\r\n\r\n", 400),
+        (b"GET / HTTP/1.1\r\n\r\n", 400),
+        (b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n", 400),
+        (b"GET / HTTP/1.1\r\nHost: babi\r\nX: a\r\n b\r\n\r\n", 400),
+        (b"GET / HTTP/1.1\r\nHost: babi\r\nX : a\r\n\r\n", 400),
+        (b"GET / HTTP/1.1\r\nHost: babi\r\nX(: a\r\n\r\n", 400),
+        (b"GET / HTTP/1.1\r\nHost: babi\r\nX: a\x00b\r\n\r\n", 400),
+        (b"GET / HTTP/1.1\r\nHost: babi\r\nX\r\n\r\n", 400),
+        (b"POST / HTTP/1.1\r\nHost: babi\r\nContent-Length: 1, 2\r\n\r\nab", 400),
+        (b"POST / HTTP/1.1\r\nHost: babi\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab", 400),
+        (b"POST / HTTP/1.1\r\nHost: babi\r\nContent-Length: +1\r\n\r\na", 400),
+        (b"POST / HTTP/1.1\r\nHost: babi\r\nContent-Length: \r\n\r\n", 400),
+        (b"POST / HTTP/1.1\r\nHost: babi\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n", 400),
+        (b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n", 400),
+        (b"POST / HTTP/1.1\r\nHost: babi\r\nTransfer-Encoding: chunked, gzip\r\n\r\n", 400),
+        (b"POST / HTTP/1.1\r\nHost: babi\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n", 400),
+        (b"POST / HTTP/1.1\r\nHost: babi\r\nTransfer-Encoding: chunked\r\n\r\n1\nx\r\n0\r\n\r\n", 400),
+        (b"POST / HTTP/1.1\r\nHost: babi\r\n\r\n", 411),
+        (b"POST / HTTP/1.1\r\nHost: babi\r\nContent-Length: 99999999999999999999999\r\n\r\n", 413),
+        (big_body.as_bytes(), 413),
+        (big_chunk.as_bytes(), 413),
+        (long_target.as_bytes(), 414),
+        (long_header.as_bytes(), 431),
+        (many_headers.as_bytes(), 431),
+        (b"POST / HTTP/1.1\r\nHost: babi\r\nTransfer-Encoding: gzip, chunked\r\n\r\n", 501),
+        (b"GET / HTTP/2.0\r\nHost: babi\r\n\r\n", 505),
+        (b"GET / HTTP/0.9\r\n\r\n", 505),
+    ] {
+        assert_eq!(rejected_with(raw), Some(*status), "{:?}", String::from_utf8_lossy(raw));
+    }
+    // nobody left to answer
+    for raw in &[&b""[..], b"GET / HTTP/1.1\r\nHost: babi\r\n", b"POST / HTTP/1.1\r\nHost: babi\r\nContent-Length: 2\r\n\r\na"] {
+        assert_eq!(rejected_with(raw), None);
+    }
+
+    let mut resp = HttpResponse::from_request_error(&ErrorKind::Http(431, "line too long".to_string()).into()).unwrap();
+    let mut raw = vec![];
+    resp.to_stream(&mut raw)?;
+    let raw = String::from_utf8(raw)?;
+    assert!(raw.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    assert!(raw.contains("\r\nConnection: close\r\n"));
+    assert!(raw.ends_with("\r\n\r\nline too long"));
+    Ok(())
+}
+
+#[test]
+fn test_request_head() -> Result<()> {
+    let parse = |raw: &[u8]| HttpRequest::from_stream(&mut BufReader::new(raw));
+    let req = parse(b"\r\nGET HTTP://Babi:47793?x=1 HTTP/1.1\r\nHost: other\r\nAccept: a\r\naccept:b \r\n\
+                      Cookie: a=1\r\nCookie: b=2\r\nContent-Length: 0, 0\r\n\r\n")?;
+    assert_eq!(req.path(), "/");
+    assert_eq!(req.get(b"x")?, b"1");
+    assert_eq!(req.version(), (1, 1));
+    assert_eq!(req.header("host"), Some("Babi:47793"));
+    assert_eq!(req.header("accept"), Some("a, b"));
+    assert_eq!(req.cookies().get(b"b").unwrap(), b"2");
+    assert!(req.keep_alive());
+    let req = parse(b"GET / HTTP/1.1\r\nHost: babi\r\nUser-Agent: caf\xe9 \xc3\xa9\r\n\r\n")?;
+    assert_eq!(req.header("user-agent"), Some("caf\u{fffd} \u{e9}"));
+    // headers stay with the request and never reach the environment
+    let req = parse(b"GET / HTTP/1.1\r\nHost: babi\r\nHTTP_BABI_SESSION_FORMAT: nope\r\n\r\n")?;
+    assert_eq!(req.header("http_babi_session_format"), Some("nope"));
+    assert!(std::env::var("BABI_SESSION_FORMAT").is_err());
+
+    let req = parse(b"OPTIONS * HTTP/1.1\r\nHost: babi\r\nConnection: foo, Close\r\n\r\n")?;
+    assert_eq!(req.path(), "*");
+    assert!(!req.keep_alive());
+    // HTTP/1.0 needs no Host and is closed unless asked not to be
+    assert!(!parse(b"GET / HTTP/1.0\r\n\r\n")?.keep_alive());
+    let req = parse(b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n")?;
+    assert_eq!(req.version(), (1, 0));
+    assert!(req.keep_alive());
+
+    // and gets a streamed body as is
+    let mut resp = HttpResponse::streaming(200, |out| Ok(out.write_all(b"abc")?));
+    assert!(resp.is_streamed());
+    let mut raw = vec![];
+    resp.unchunked().to_stream(&mut raw)?;
+    let raw = String::from_utf8(raw)?;
+    assert!(!raw.contains("Transfer-Encoding"));
+    assert!(raw.ends_with("\r\n\r\nabc"));
+    Ok(())
+}
@@ -1,5 +1,7 @@
 #[macro_use] extern crate error_chain;
 
+// error_chain checks a cfg of its own build script inside the expansion
+#[allow(unexpected_cfgs)]
 pub mod errors {
     error_chain! {
         foreign_links {
@@ -8,6 +10,9 @@ pub mod errors {
             ParseFloatError(::std::num::ParseFloatError);
             ParseIntError(::std::num::ParseIntError);
             FromUtf8Error(std::string::FromUtf8Error);
+            UnserializeError(crate::php::UnserializeError);
+            JsonError(serde_json::Error);
+            PngError(png::DecodingError);
         }
 
         errors {
@@ -19,10 +24,23 @@ pub mod errors {
                 description("invalid")
                     display("invalid")
             }
+            ExpansionLimit {
+                description("expansion limit exceeded")
+                    display("value too large once its references are expanded")
+            }
+            SessionVarName(reason: &'static str) {
+                description("session var name cannot be encoded")
+                    display("session var name {}", reason)
+            }
+            Http(status: u32, reason: String) {
+                description("bad request")
+                    display("{} {}", status, reason)
+            }
         }
     }
 }
 
 pub mod http;
 pub mod php;
+pub mod qr;
 pub mod app;
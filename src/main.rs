@@ -14,7 +14,7 @@ use babi::app;
 
 static mut UID: u32 = 65535;
 
-extern fn signal_handler(_: i32) {
+extern "C" fn signal_handler(_: i32) {
     unsafe {
         setuid(Uid::from_raw(UID)).expect("uid");
     }
@@ -25,7 +25,7 @@ extern fn signal_handler(_: i32) {
 fn main() -> io::Result<()> {
     let mut timeout = 30;
     if let Some(arg) = env::args().nth(1) {
-        timeout = u32::from_str_radix(&arg, 10).unwrap();
+        timeout = arg.parse::<u32>().unwrap();
     }
 
     unsafe {
@@ -33,12 +33,20 @@ fn main() -> io::Result<()> {
         sigaction(Signal::SIGALRM, &SigAction::new(SigHandler::Handler(signal_handler), SaFlags::empty(), SigSet::empty())).unwrap();
     }
 
+    // a bad setting stops the server before it answers anything
+    let config = app::Config::from_env().expect("config");
+
     let mut app = app::App::new();
-    app.reg("GET", Regex::new("^/$").unwrap(), app::index)
+    app.config(config)
+        .reg("GET", Regex::new("^/$").unwrap(), app::index)
         .reg("GET", Regex::new("^/list$").unwrap(), app::list)
         .reg("GET", Regex::new("^/info$").unwrap(), app::info)
+        .reg("GET", Regex::new("^/session\\.json$").unwrap(), app::session_json)
         .reg("GET", Regex::new("^/gen$").unwrap(), app::gen)
-        .reg("POST", Regex::new("^/enroll$").unwrap(), app::enroll);
+        .reg("POST", Regex::new("^/enroll$").unwrap(), app::enroll)
+        .reg("GET", Regex::new("^/import$").unwrap(), app::import_form)
+        .reg("POST", Regex::new("^/import$").unwrap(), app::import)
+        .reg("GET", Regex::new("^/export$").unwrap(), app::export);
 
     let listener = TcpListener::bind("0.0.0.0:47793").unwrap(); // 0xbab1
     let server_fd = listener.as_raw_fd();
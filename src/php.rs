@@ -1,18 +1,178 @@
 use std::fmt;
 use std::fmt::Write;
 use std::str::FromStr;
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::mem;
 
 use crate::errors::*;
 
-#[derive(PartialEq)]
+mod array;
+mod ser;
+mod de;
+mod dump;
+mod json;
+mod stream;
+mod borrowed;
+mod session;
+mod igbinary;
+mod packed;
+mod path;
+
+pub use self::array::{PhpArray, PhpKey};
+pub use self::ser::{to_var, to_vec, to_vec_with, Serializer, StructFormat};
+pub use self::de::{from_var, from_slice, Deserializer};
+pub use self::dump::{var_dump, var_dump_with, print_r, print_r_with, var_export, var_export_with};
+pub use self::json::{to_json, to_json_with, from_json, JsonOptions, BinaryStrings};
+pub use self::stream::{StreamParser, Event};
+pub use self::borrowed::{unserialize_ref, unserialize_ref_with, PhpValueRef, PhpKeyRef};
+pub use self::igbinary::{igbinary_serialize, igbinary_unserialize, igbinary_unserialize_with};
+pub use self::packed::{pack, unpack, unpack_with, Packing};
+pub use self::session::{session_decode, session_decode_ref, session_encode, SessionHandler};
+
+// how a slot shared through `PhpVar::Ref` behaves: `R:` slots are PHP
+// references (`&$x`) and writes through one of them are visible through all
+// of them. `r:` slots are object handles, unserialize copies anything else
+// an `r:` points at into a value of its own. walking a var visits a shared
+// cell once per holder, see `ExpansionLimits`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RefKind {
+    Value,
+    Shared,
+}
+
+// property names of objects are stored mangled the way PHP does it:
+// `\0*\0name` for protected and `\0Class\0name` for private properties
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Visibility {
+    Public,
+    Protected,
+    Private(Vec<u8>),
+}
+
+impl Visibility {
+    pub fn mangle(&self, name: &[u8]) -> Vec<u8> {
+        let mut t = vec![];
+        match self {
+            Visibility::Public => (),
+            Visibility::Protected => t.extend_from_slice(b"\0*\0"),
+            Visibility::Private(class) => {
+                t.push(0);
+                t.extend_from_slice(class);
+                t.push(0);
+            }
+        }
+        t.extend_from_slice(name);
+        t
+    }
+
+    pub fn demangle(name: &[u8]) -> (Visibility, &[u8]) {
+        if name.first() == Some(&0) {
+            if let Some(i) = name[1..].iter().position(|c| *c == 0) {
+                let prop = &name[(i + 2)..];
+                return match &name[1..(i + 1)] {
+                    b"*" => (Visibility::Protected, prop),
+                    class => (Visibility::Private(class.to_vec()), prop),
+                };
+            }
+        }
+        (Visibility::Public, name)
+    }
+}
+
+// objects of classes that were not allowed by `UnserializeOptions` come out
+// the way PHP has them: as this class, with the original class name stored
+// in the first property
+pub const INCOMPLETE_CLASS: &[u8] = b"__PHP_Incomplete_Class";
+pub const INCOMPLETE_CLASS_NAME: &[u8] = b"__PHP_Incomplete_Class_Name";
+
+#[derive(Clone, PartialEq)]
 pub enum PhpVar {
     Null,
     Bool(bool),
     Int(i64),
     Float(f64),
     String(Vec<u8>),
-    Array(Vec<Box<PhpVar>>, Vec<Box<PhpVar>>),
-    Ref(usize),
+    Array(PhpArray),
+    Object { class: Vec<u8>, props: Vec<(Vec<u8>, PhpVar)> },
+    Custom { class: Vec<u8>, data: Vec<u8> },
+    Enum { class: Vec<u8>, case: Vec<u8> },
+    Ref(RefKind, Rc<RefCell<PhpVar>>),
+}
+
+impl PhpVar {
+    pub fn with_value<T, F: FnOnce(&PhpVar) -> T>(&self, f: F) -> T {
+        match self {
+            PhpVar::Ref(_, v) => v.borrow().with_value(f),
+            _ => f(self),
+        }
+    }
+
+    pub fn property(&self, vis: &Visibility, name: &[u8]) -> Option<&PhpVar> {
+        if let PhpVar::Object { props, .. } = self {
+            let key = vis.mangle(name);
+            props.iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+        } else {
+            None
+        }
+    }
+
+    pub fn property_mut(&mut self, vis: &Visibility, name: &[u8]) -> Option<&mut PhpVar> {
+        if let PhpVar::Object { props, .. } = self {
+            let key = vis.mangle(name);
+            props.iter_mut().find(|(k, _)| *k == key).map(|(_, v)| v)
+        } else {
+            None
+        }
+    }
+
+    pub fn public(&self, name: &[u8]) -> Option<&PhpVar> {
+        self.property(&Visibility::Public, name)
+    }
+
+    pub fn protected(&self, name: &[u8]) -> Option<&PhpVar> {
+        self.property(&Visibility::Protected, name)
+    }
+
+    pub fn private(&self, class: &[u8], name: &[u8]) -> Option<&PhpVar> {
+        self.property(&Visibility::Private(class.to_vec()), name)
+    }
+
+    // yields (visibility, unmangled name, value) for every property
+    pub fn properties(&self) -> Vec<(Visibility, &[u8], &PhpVar)> {
+        match self {
+            PhpVar::Object { props, .. } => props.iter().map(|(k, v)| {
+                let (vis, name) = Visibility::demangle(k);
+                (vis, name, v)
+            }).collect(),
+            _ => vec![]
+        }
+    }
+
+    // the original class name of an incomplete class object
+    pub fn incomplete_class(&self) -> Option<&[u8]> {
+        match self {
+            PhpVar::Object { class, props } if class.as_slice() == INCOMPLETE_CLASS => match props.first() {
+                Some((k, PhpVar::String(name))) if k.as_slice() == INCOMPLETE_CLASS_NAME => Some(name),
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    pub fn set_property(&mut self, vis: &Visibility, name: &[u8], value: PhpVar) -> Result<()> {
+        if let PhpVar::Object { props, .. } = self {
+            let key = vis.mangle(name);
+            match props.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, v)) => *v = value,
+                None => props.push((key, value)),
+            }
+            Ok(())
+        } else {
+            bail!(ErrorKind::Invalid)
+        }
+    }
 }
 
 impl fmt::Display for PhpVar {
@@ -28,8 +188,9 @@ impl fmt::Display for PhpVar {
                 }
                 Ok(())
             },
-            PhpVar::Array(_, _) => write!(f, "Array"),
-            _ => write!(f, "undefined")
+            PhpVar::Array(_) => write!(f, "Array"),
+            PhpVar::Object { .. } | PhpVar::Custom { .. } | PhpVar::Enum { .. } => write!(f, "Object"),
+            PhpVar::Ref(_, ref v) => write!(f, "{}", v.borrow()),
         }
     }
 }
@@ -48,236 +209,1074 @@ impl fmt::Debug for PhpVar {
                 }
                 write!(f, "\"")
             },
-            PhpVar::Array(ref k, ref v) => {
-                write!(f, "array({}) {{", k.len())?;
-                for i in 0..k.len() {
-                    write!(f, "[\"{:}\"]\n=>{:?}\n", k[i], v[i])?;
+            PhpVar::Array(ref arr) => {
+                write!(f, "array({}) {{", arr.len())?;
+                for (k, v) in arr {
+                    write!(f, "[\"{:}\"]\n=>{:?}\n", k, v)?;
+                }
+                write!(f, "}}")
+            },
+            PhpVar::Object { ref class, ref props } => {
+                write!(f, "object(")?;
+                for c in class {
+                    f.write_char(*c as char)?;
+                }
+                write!(f, ") ({}) {{", props.len())?;
+                for (k, v) in props {
+                    write!(f, "[\"")?;
+                    for c in k {
+                        f.write_char(*c as char)?;
+                    }
+                    write!(f, "\"]\n=>{:?}\n", v)?;
                 }
                 write!(f, "}}")
+            },
+            PhpVar::Custom { ref class, ref data } => {
+                write!(f, "object(")?;
+                for c in class {
+                    f.write_char(*c as char)?;
+                }
+                write!(f, ") ({}) \"", data.len())?;
+                for c in data {
+                    f.write_char(*c as char)?;
+                }
+                write!(f, "\"")
+            },
+            PhpVar::Enum { ref class, ref case } => {
+                write!(f, "enum(")?;
+                for c in class {
+                    f.write_char(*c as char)?;
+                }
+                write!(f, "::")?;
+                for c in case {
+                    f.write_char(*c as char)?;
+                }
+                write!(f, ")")
+            },
+            PhpVar::Ref(_, ref v) => write!(f, "{:?}", v.borrow()),
+        }
+    }
+}
+
+// values are first parsed into a flat arena so that back-references can be
+// resolved by index instead of aliasing boxes. every var except `R:` also
+// takes a numbered slot, which is what `r:N;`/`R:N;` count. strings are
+// only borrowed from the input until the arena is built.
+#[derive(Debug)]
+enum Node<'a> {
+    Pending,
+    Value(PhpValueRef<'a>),
+    Array(Vec<(PhpKeyRef<'a>, usize)>),
+    Object(&'a [u8], Vec<(PhpKeyRef<'a>, usize)>),
+    Ref(RefKind, usize),
+}
+
+// caps on what an untrusted payload may make `unserialize` allocate, each
+// one reported as `UnserializeErrorKind::LimitExceeded` when crossed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnserializeOptions {
+    // arrays and objects nested inside each other
+    pub max_depth: usize,
+    // vars in the whole payload, array keys excluded
+    pub max_elements: usize,
+    // length of a single string, class name or `C:` payload
+    pub max_string_len: usize,
+    // length of the serialized input itself
+    pub max_input_len: usize,
+    // PHP's `allowed_classes`
+    pub allowed_classes: AllowedClasses,
+}
+
+impl Default for UnserializeOptions {
+    fn default() -> Self {
+        UnserializeOptions {
+            max_depth: 128,
+            max_elements: 1 << 16,
+            max_string_len: 1 << 20,
+            max_input_len: 1 << 22,
+            allowed_classes: AllowedClasses::All,
+        }
+    }
+}
+
+// the holders of an `R:` slot or object handle share one cell, so a payload
+// of a few hundred bytes can stand for a value of gigabytes once every holder
+// is visited on its own. whatever walks a var that way (the dumps, `to_json`,
+// the igbinary writer and `from_var`) stops with `ErrorKind::ExpansionLimit`
+// once it has seen this much, and unserialize fails with `Limit::Copies` once
+// its `r:` copies add up to it. the defaults are what `UnserializeOptions`
+// would let through without any sharing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExpansionLimits {
+    // vars visited, a shared one once for every holder
+    pub max_nodes: usize,
+    // bytes of strings, keys and class names visited, counted the same way
+    pub max_bytes: usize,
+}
+
+impl Default for ExpansionLimits {
+    fn default() -> Self {
+        ExpansionLimits::from(&UnserializeOptions::default())
+    }
+}
+
+impl From<&UnserializeOptions> for ExpansionLimits {
+    fn from(opts: &UnserializeOptions) -> Self {
+        ExpansionLimits { max_nodes: opts.max_elements, max_bytes: opts.max_input_len }
+    }
+}
+
+// what is left of an `ExpansionLimits` during one walk
+#[derive(Debug)]
+pub(crate) struct Budget {
+    nodes: Cell<usize>,
+    bytes: Cell<usize>,
+}
+
+impl Budget {
+    pub(crate) fn new(limits: &ExpansionLimits) -> Self {
+        Budget { nodes: Cell::new(limits.max_nodes), bytes: Cell::new(limits.max_bytes) }
+    }
+
+    // one var, along with the strings it holds itself
+    pub(crate) fn var(&self, var: &PhpVar) -> Result<()> {
+        self.node(match var {
+            PhpVar::String(s) => s.len(),
+            PhpVar::Object { class, .. } => class.len(),
+            PhpVar::Custom { class, data } => class.len() + data.len(),
+            PhpVar::Enum { class, case } => class.len() + case.len(),
+            _ => 0,
+        })
+    }
+
+    // one var holding `n` bytes of its own
+    pub(crate) fn node(&self, n: usize) -> Result<()> {
+        match self.nodes.get().checked_sub(1) {
+            Some(left) => self.nodes.set(left),
+            None => bail!(ErrorKind::ExpansionLimit),
+        }
+        self.bytes(n)
+    }
+
+    // everything a copy of `var` adds, the cells inside it stay shared
+    pub(crate) fn copy(&self, var: &PhpVar) -> Result<()> {
+        self.var(var)?;
+        match var {
+            PhpVar::Array(arr) => {
+                for (k, v) in arr.iter() {
+                    if let PhpKey::String(s) = k {
+                        self.bytes(s.len())?;
+                    }
+                    self.copy(v)?;
+                }
+            },
+            PhpVar::Object { props, .. } => {
+                for (name, v) in props {
+                    self.bytes(name.len())?;
+                    self.copy(v)?;
+                }
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // an array key or property name
+    pub(crate) fn bytes(&self, n: usize) -> Result<()> {
+        match self.bytes.get().checked_sub(n) {
+            Some(left) => self.bytes.set(left),
+            None => bail!(ErrorKind::ExpansionLimit),
+        }
+        Ok(())
+    }
+}
+
+// `O:` and `C:` payloads of any other class are turned into incomplete class
+// objects and `E:` cases of any other enum are rejected. class names are
+// compared case-insensitively, like PHP does.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AllowedClasses {
+    All,
+    Only(Vec<Vec<u8>>),
+}
+
+impl AllowedClasses {
+    pub fn allows(&self, class: &[u8]) -> bool {
+        match self {
+            AllowedClasses::All => true,
+            AllowedClasses::Only(classes) => classes.iter().any(|c| c.eq_ignore_ascii_case(class)),
+        }
+    }
+}
+
+// `class` with its original name as the only property so far
+fn incomplete_object(class: Vec<u8>) -> PhpVar {
+    PhpVar::Object {
+        class: INCOMPLETE_CLASS.to_vec(),
+        props: vec![(INCOMPLETE_CLASS_NAME.to_vec(), PhpVar::String(class))],
+    }
+}
+
+// objects are handles in PHP: copying a var never copies the objects in it
+fn is_object(var: &PhpVar) -> bool {
+    matches!(var, PhpVar::Object { .. } | PhpVar::Custom { .. } | PhpVar::Enum { .. })
+}
+
+// puts every object in the arrays of `var` into a cell of its own, so that
+// copies of `var` share them
+fn share_objects(var: &mut PhpVar) {
+    if let PhpVar::Array(arr) = var {
+        for (_, v) in arr.iter_mut() {
+            if is_object(v) {
+                let obj = mem::replace(v, PhpVar::Null);
+                *v = PhpVar::Ref(RefKind::Value, Rc::new(RefCell::new(obj)));
+            } else {
+                share_objects(v);
             }
-            _ => write!(f, "undefined")
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Depth,
+    Elements,
+    StringLength,
+    InputLength,
+    // what `r:` copies add on top of the parsed vars, see `ExpansionLimits`
+    Copies,
+}
+
+// why `try_unserialize` rejected its input: `offset` is the byte the parser
+// stopped at and `expected` what it was looking for there
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnserializeError {
+    pub offset: usize,
+    pub expected: &'static str,
+    pub kind: UnserializeErrorKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnserializeErrorKind {
+    // the input ended in the middle of a var
+    Truncated,
+    // a length or count that does not match the data that follows it
+    BadLength,
+    // a type tag (or key type) that PHP does not know
+    UnknownType,
+    // `r:`/`R:` pointing at a slot that does not exist or is not complete yet
+    DanglingReference,
+    // a complete var followed by more input
+    TrailingData,
+    // an enum case of a class that `allowed_classes` does not list
+    DisallowedClass,
+    // anything else that is not what the format allows at that offset
+    Malformed,
+    LimitExceeded(Limit),
+    // the reader of a `StreamParser` failed
+    Io(::std::io::ErrorKind),
+}
+
+impl fmt::Display for UnserializeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnserializeErrorKind::Truncated => write!(f, "truncated input"),
+            UnserializeErrorKind::BadLength => write!(f, "bad length"),
+            UnserializeErrorKind::UnknownType => write!(f, "unknown type tag"),
+            UnserializeErrorKind::DanglingReference => write!(f, "dangling reference"),
+            UnserializeErrorKind::TrailingData => write!(f, "trailing data"),
+            UnserializeErrorKind::DisallowedClass => write!(f, "disallowed class"),
+            UnserializeErrorKind::Malformed => write!(f, "malformed value"),
+            UnserializeErrorKind::LimitExceeded(limit) => write!(f, "{:?} limit exceeded", limit),
+            UnserializeErrorKind::Io(kind) => write!(f, "read error ({:?})", kind),
+        }
+    }
+}
+
+impl fmt::Display for UnserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}, expected {}", self.kind, self.offset, self.expected)
+    }
+}
+
+impl ::std::error::Error for UnserializeError {}
+
+type Parsed<T> = ::std::result::Result<T, UnserializeError>;
+
 #[derive(Debug)]
-struct Parser<'a> {
+struct Parser<'a, 'o> {
     cur: usize,
     len: usize,
     raw: &'a [u8],
-    has_ref: bool,
+    opts: &'o UnserializeOptions,
+    depth: usize,
+    nodes: Vec<Node<'a>>,
+    slots: Vec<usize>,
+    // per node: how it is referenced later on, if at all
+    targets: Vec<Option<RefKind>>,
+    // nodes some `r:` copies, whatever else refers to them
+    copied: HashSet<usize>,
+    shared: HashMap<usize, Rc<RefCell<PhpVar>>>,
+    // built nodes only `r:` refers to, cloned for every copy
+    copies: HashMap<usize, PhpVar>,
+    budget: Budget,
 }
 
-impl<'a> Parser<'a> {
-    pub fn new(raw: &'a [u8]) -> Self {
+impl<'a, 'o> Parser<'a, 'o> {
+    pub fn new(raw: &'a [u8], opts: &'o UnserializeOptions) -> Self {
         Parser {
             cur: 0,
             len: raw.len(),
-            raw: raw,
-            has_ref: false
+            raw,
+            opts,
+            depth: 0,
+            nodes: vec![],
+            slots: vec![],
+            targets: vec![],
+            copied: HashSet::new(),
+            shared: HashMap::new(),
+            copies: HashMap::new(),
+            budget: Budget::new(&ExpansionLimits::from(opts)),
         }
     }
 
-    fn read_byte(&mut self) -> Result<u8> {
+    fn fail<T>(&self, offset: usize, kind: UnserializeErrorKind, expected: &'static str) -> Parsed<T> {
+        Err(UnserializeError { offset, expected, kind })
+    }
+
+    fn read_byte(&mut self, expected: &'static str) -> Parsed<u8> {
         if self.cur < self.len {
-            let i = self.cur;
-            let c = self.raw[i] as u8;
+            let c = self.raw[self.cur];
             self.cur += 1;
             Ok(c)
         } else {
-            bail!(ErrorKind::Invalid)
+            self.fail(self.cur, UnserializeErrorKind::Truncated, expected)
+        }
+    }
+
+    fn read_bytes(&mut self, l: usize) -> Parsed<&'a [u8]> {
+        if l <= self.len - self.cur {
+            let s = &self.raw[self.cur..(self.cur + l)];
+            self.cur += l;
+            Ok(s)
+        } else {
+            self.fail(self.len, UnserializeErrorKind::Truncated, "more data")
+        }
+    }
+
+    // a declared length is checked before anything is sliced or allocated
+    fn read_len(&mut self) -> Parsed<usize> {
+        let start = self.cur;
+        let l = self.read_number("length")?;
+        if l > self.opts.max_string_len {
+            return self.fail(start, UnserializeErrorKind::LimitExceeded(Limit::StringLength), "shorter string");
+        }
+        Ok(l)
+    }
+
+    // a declared element count can never be trusted for preallocation, but
+    // one that cannot fit the remaining budget is rejected right away
+    fn read_count(&mut self) -> Parsed<usize> {
+        let start = self.cur;
+        let l = self.read_number("element count")?;
+        if l > self.opts.max_elements - self.nodes.len() {
+            return self.fail(start, UnserializeErrorKind::LimitExceeded(Limit::Elements), "fewer elements");
         }
+        Ok(l)
     }
 
-    fn expect_byte(&mut self, c: u8) -> Result<()> {
-        if self.read_byte()? == c {
+    fn expect_byte(&mut self, c: u8, expected: &'static str) -> Parsed<()> {
+        if self.read_byte(expected)? == c {
             Ok(())
         } else {
-            bail!(ErrorKind::Invalid)
+            self.fail(self.cur - 1, UnserializeErrorKind::Malformed, expected)
         }
     }
 
-    fn read_number(&mut self) -> Result<i64> {
-        let mut v: i64 = 0;
+    // unsigned lengths, counts and slot numbers
+    fn read_number(&mut self, expected: &'static str) -> Parsed<usize> {
+        let mut v: usize = 0;
         let mut i = self.cur;
         while i < self.len && self.raw[i].is_ascii_digit() {
-            v *= 10;
-            v += self.raw[i] as i64 - 48;
+            v = match v.checked_mul(10).and_then(|v| v.checked_add((self.raw[i] - b'0') as usize)) {
+                Some(v) => v,
+                None => return self.fail(self.cur, UnserializeErrorKind::BadLength, expected)
+            };
             i += 1;
         }
         if i != self.cur {
             self.cur = i;
             Ok(v)
+        } else if i == self.len {
+            self.fail(i, UnserializeErrorKind::Truncated, expected)
         } else {
-            bail!(ErrorKind::Invalid)
+            self.fail(i, UnserializeErrorKind::Malformed, expected)
         }
     }
 
-    fn read_bool(&mut self) -> Result<Box<PhpVar>> {
-        let v = self.read_number()?;
-        self.expect_byte(b';')?;
-        Ok(Box::new(PhpVar::Bool(v != 0)))
+    // `[+-]?[0-9]+`, out of range values are an error instead of wrapping
+    fn read_signed(&mut self) -> Parsed<i64> {
+        let start = self.cur;
+        let neg = match self.raw.get(self.cur) {
+            Some(b'-') => { self.cur += 1; true },
+            Some(b'+') => { self.cur += 1; false },
+            _ => false
+        };
+        let mut v: i64 = 0;
+        let mut i = self.cur;
+        while i < self.len && self.raw[i].is_ascii_digit() {
+            // accumulate negatively so that i64::MIN is representable
+            let d = (self.raw[i] - b'0') as i64;
+            v = match v.checked_mul(10).and_then(|v| if neg { v.checked_sub(d) } else { v.checked_add(d) }) {
+                Some(v) => v,
+                None => return self.fail(start, UnserializeErrorKind::Malformed, "64-bit integer")
+            };
+            i += 1;
+        }
+        if i != self.cur {
+            self.cur = i;
+            Ok(v)
+        } else if i == self.len {
+            self.fail(i, UnserializeErrorKind::Truncated, "integer")
+        } else {
+            self.fail(i, UnserializeErrorKind::Malformed, "integer")
+        }
     }
 
-    fn read_int(&mut self) -> Result<Box<PhpVar>> {
-        let v = self.read_number()?;
-        self.expect_byte(b';')?;
-        Ok(Box::new(PhpVar::Int(v)))
+    fn read_bool(&mut self) -> Parsed<PhpValueRef<'a>> {
+        let v = match self.read_byte("'0' or '1'")? {
+            b'0' => false,
+            b'1' => true,
+            _ => return self.fail(self.cur - 1, UnserializeErrorKind::Malformed, "'0' or '1'")
+        };
+        self.expect_byte(b';', "';'")?;
+        Ok(PhpValueRef::Bool(v))
     }
 
-    fn read_ref(&mut self) -> Result<Box<PhpVar>> {
-        let v = self.read_number()? as usize;
-        self.expect_byte(b';')?;
-        self.has_ref = true;
-        Ok(Box::new(PhpVar::Ref(v)))
+    fn read_int(&mut self) -> Parsed<PhpValueRef<'a>> {
+        let v = self.read_signed()?;
+        self.expect_byte(b';', "';'")?;
+        Ok(PhpValueRef::Int(v))
     }
 
-    fn read_float(&mut self) -> Result<Box<PhpVar>> {
+    fn read_float(&mut self) -> Parsed<PhpValueRef<'a>> {
         let mut i = self.cur;
         while i < self.len && self.raw[i] != b';' {
             i += 1;
         }
-        let s = String::from_utf8(self.raw[self.cur..i].to_vec())?;
-        let f = f64::from_str(&s)?;
+        let s = &self.raw[self.cur..i];
+        let f = match s {
+            b"NAN" => Some(f64::NAN),
+            b"INF" => Some(f64::INFINITY),
+            b"-INF" => Some(f64::NEG_INFINITY),
+            _ if valid_float(s) => f64::from_str(&String::from_utf8_lossy(s)).ok(),
+            _ => None
+        };
+        let f = match f {
+            Some(f) => f,
+            None if i == self.len => return self.fail(i, UnserializeErrorKind::Truncated, "';'"),
+            None => return self.fail(self.cur, UnserializeErrorKind::Malformed, "float")
+        };
         self.cur = i;
-        self.expect_byte(b';')?;
-        Ok(Box::new(PhpVar::Float(f)))
+        self.expect_byte(b';', "';'")?;
+        Ok(PhpValueRef::Float(f))
     }
 
-    fn read_string(&mut self) -> Result<Box<PhpVar>> {
-        let l = self.read_number()? as usize;
-        self.expect_byte(b':')?;
-        self.expect_byte(b'"')?;
+    // `<len>:"<bytes>"`
+    fn read_string_body(&mut self) -> Parsed<&'a [u8]> {
+        let l = self.read_len()?;
+        self.expect_byte(b':', "':'")?;
+        self.expect_byte(b'"', "'\"'")?;
 
-        let s = self.raw[self.cur..(self.cur + l)].to_vec();
-        self.cur += l;
+        let s = self.read_bytes(l)?;
 
-        self.expect_byte(b'"')?;
-        self.expect_byte(b';')?;
-        Ok(Box::new(PhpVar::String(s)))
+        // the closing quote is the only way to tell that the length was off
+        match self.read_byte("'\"'")? {
+            b'"' => Ok(s),
+            _ => self.fail(self.cur - 1, UnserializeErrorKind::BadLength, "'\"'")
+        }
+    }
+
+    fn read_string(&mut self) -> Parsed<&'a [u8]> {
+        let s = self.read_string_body()?;
+        self.expect_byte(b';', "';'")?;
+        Ok(s)
+    }
+
+    // `r:N;` and `R:N;` may only point at a var that has been completely
+    // parsed: forward references, self references and references to an
+    // enclosing array are rejected
+    fn read_ref(&mut self, kind: RefKind) -> Parsed<Node<'a>> {
+        let start = self.cur;
+        let v = self.read_number("slot number")?;
+        self.expect_byte(b';', "';'")?;
+        if v == 0 || v > self.slots.len() {
+            return self.fail(start, UnserializeErrorKind::DanglingReference, "slot number");
+        }
+        let mut target = self.slots[v - 1];
+        match self.nodes[target] {
+            Node::Pending => return self.fail(start, UnserializeErrorKind::DanglingReference, "slot number"),
+            Node::Ref(_, t) => target = t,
+            _ => ()
+        }
+        self.refer(target, kind);
+        Ok(Node::Ref(kind, target))
     }
 
-    fn read_array(&mut self) -> Result<Box<PhpVar>> {
-        let l = self.read_number()? as usize;
-        self.expect_byte(b':')?;
-        self.expect_byte(b'{')?;
+    // `R:` wins over `r:`, the target only stays a plain value for copies if
+    // nothing shares it
+    fn refer(&mut self, target: usize, kind: RefKind) {
+        if kind == RefKind::Shared || self.targets[target].is_none() {
+            self.targets[target] = Some(kind);
+        }
+        if kind == RefKind::Value {
+            self.copied.insert(target);
+        }
+    }
 
-        let mut k = vec![];
-        let mut v = vec![];
+    fn read_array(&mut self) -> Parsed<Node<'a>> {
+        let l = self.read_count()?;
+        self.expect_byte(b':', "':'")?;
+        self.expect_byte(b'{', "'{'")?;
 
+        let mut items = vec![];
         for _i in 0..l {
-            k.push(self.read_var()?);
-            v.push(self.read_var()?);
+            let k = self.read_key()?;
+            let v = self.read_var()?;
+            items.push((k, v));
         }
 
-        self.expect_byte(b'}')?;
+        self.expect_close()?;
+        Ok(Node::Array(items))
+    }
 
-        Ok(Box::new(PhpVar::Array(k, v)))
+    // a `}` anywhere else means fewer elements than declared
+    fn expect_close(&mut self) -> Parsed<()> {
+        match self.read_byte("'}'")? {
+            b'}' => Ok(()),
+            _ => self.fail(self.cur - 1, UnserializeErrorKind::BadLength, "'}'")
+        }
     }
 
-    fn read_var(&mut self) -> Result<Box<PhpVar>> {
-        let _type = self.read_byte()?;
+    // `<len>:"<class>":`, shared by `O:` and `C:`
+    fn read_class(&mut self) -> Parsed<&'a [u8]> {
+        let start = self.cur;
+        let class = self.read_string_body()?;
+        self.expect_byte(b':', "':'")?;
+        if !valid_class_name(class) {
+            return self.fail(start, UnserializeErrorKind::Malformed, "class name");
+        }
+        Ok(class)
+    }
 
-        if _type == b'N' {
-            self.expect_byte(b';')?;
-            Ok(Box::new(PhpVar::Null))
-        } else {
-            self.expect_byte(b':')?;
-            match _type {
-                b'b' => self.read_bool(),
-                b'i' => self.read_int(),
-                b'd' => self.read_float(),
-                b's' => self.read_string(),
-                b'a' => self.read_array(),
-                b'r' => self.read_ref(),
-                _ => bail!(ErrorKind::Unknown)
-            }
+    fn read_object(&mut self) -> Parsed<Node<'a>> {
+        let class = self.read_class()?;
+        let l = self.read_count()?;
+        self.expect_byte(b':', "':'")?;
+        self.expect_byte(b'{', "'{'")?;
+
+        let mut props = vec![];
+        for _i in 0..l {
+            let name = self.read_key()?;
+            let v = self.read_var()?;
+            props.push((name, v));
         }
+
+        self.expect_close()?;
+        Ok(Node::Object(class, props))
     }
 
-    fn fixup(mut obj: Box<PhpVar>) -> Result<Box<PhpVar>> {
-        let mut vars = vec![];
-        fn travel(obj: &mut Box<PhpVar>, vars: &mut Vec<*mut PhpVar>) {
-            vars.push(&mut **obj as *mut PhpVar);
-            if let PhpVar::Array(_, ref mut v) = **obj {
-                for v in v {
-                    travel(v, vars);
+    fn read_custom(&mut self) -> Parsed<Node<'a>> {
+        let class = self.read_class()?;
+        let l = self.read_len()?;
+        self.expect_byte(b':', "':'")?;
+        self.expect_byte(b'{', "'{'")?;
+
+        let data = self.read_bytes(l)?;
+
+        self.expect_close()?;
+        // there is nothing to restore the payload with, so it is dropped and
+        // the object built like an `O:` one without properties
+        if !self.opts.allowed_classes.allows(class) {
+            return Ok(Node::Object(class, vec![]));
+        }
+        Ok(Node::Value(PhpValueRef::Custom { class, data }))
+    }
+
+    // `<len>:"<class>:<case>";`
+    fn read_enum(&mut self) -> Parsed<PhpValueRef<'a>> {
+        let start = self.cur;
+        let name = self.read_string()?;
+        match name.iter().position(|c| *c == b':') {
+            Some(i) if valid_class_name(&name[..i]) && valid_class_name(&name[(i + 1)..])
+                && !name[(i + 1)..].contains(&b'\\') => {
+                if !self.opts.allowed_classes.allows(&name[..i]) {
+                    return self.fail(start, UnserializeErrorKind::DisallowedClass, "allowed class");
                 }
-            }
+                Ok(PhpValueRef::Enum { class: &name[..i], case: &name[(i + 1)..] })
+            },
+            _ => self.fail(start, UnserializeErrorKind::Malformed, "enum case name")
         }
-        travel(&mut obj, &mut vars);
+    }
 
-        fn translate(obj: Box<PhpVar>, vars: &Vec<*mut PhpVar>) -> Result<Box<PhpVar>> {
-            match *obj {
-                PhpVar::Ref(id) => {
-                    let id = id - 1;
-                    if id >= vars.len() || vars[id] as *const PhpVar == &*obj {
-                        bail!(ErrorKind::Invalid)
-                    } else {
-                        Ok(unsafe{ Box::from_raw(vars[id]) })
-                    }
-                },
-                PhpVar::Array(k, v) => {
-                    let mut _k = vec![];
-                    for k in k {
-                        _k.push(translate(k, vars)?);
-                    }
-                    let mut _v = vec![];
-                    for v in v {
-                        _v.push(translate(v, vars)?);
+    // keys do not occupy a var slot and can only be ints or strings
+    fn read_key(&mut self) -> Parsed<PhpKeyRef<'a>> {
+        let _type = self.read_byte("key")?;
+        match _type {
+            b'i' => {
+                self.expect_byte(b':', "':'")?;
+                let i = self.read_signed()?;
+                self.expect_byte(b';', "';'")?;
+                Ok(PhpKeyRef::Int(i))
+            },
+            b's' => {
+                self.expect_byte(b':', "':'")?;
+                Ok(PhpKeyRef::from_bytes(self.read_string()?))
+            },
+            b'}' => self.fail(self.cur - 1, UnserializeErrorKind::BadLength, "key"),
+            _ => self.fail(self.cur - 1, UnserializeErrorKind::UnknownType, "'i' or 's' key")
+        }
+    }
+
+    // nested arrays and objects go one level deeper
+    fn read_nested(&mut self, f: fn(&mut Self) -> Parsed<Node<'a>>) -> Parsed<Node<'a>> {
+        if self.depth >= self.opts.max_depth {
+            return self.fail(self.cur - 2, UnserializeErrorKind::LimitExceeded(Limit::Depth), "shallower nesting");
+        }
+        self.depth += 1;
+        let node = f(self)?;
+        self.depth -= 1;
+        Ok(node)
+    }
+
+    fn read_var(&mut self) -> Parsed<usize> {
+        let id = self.nodes.len();
+        if id >= self.opts.max_elements {
+            return self.fail(self.cur, UnserializeErrorKind::LimitExceeded(Limit::Elements), "fewer elements");
+        }
+        self.nodes.push(Node::Pending);
+        self.targets.push(None);
+
+        let _type = self.read_byte("type tag")?;
+        if _type != b'R' {
+            self.slots.push(id);
+        }
+
+        let node = if _type == b'N' {
+            self.expect_byte(b';', "';'")?;
+            Node::Value(PhpValueRef::Null)
+        } else {
+            match _type {
+                b'b' | b'i' | b'd' | b's' | b'a' | b'O' | b'C' | b'E' | b'r' | b'R' => (),
+                b'}' => return self.fail(self.cur - 1, UnserializeErrorKind::BadLength, "value"),
+                _ => return self.fail(self.cur - 1, UnserializeErrorKind::UnknownType, "type tag")
+            }
+            self.expect_byte(b':', "':'")?;
+            match _type {
+                b'b' => Node::Value(self.read_bool()?),
+                b'i' => Node::Value(self.read_int()?),
+                b'd' => Node::Value(self.read_float()?),
+                b's' => Node::Value(PhpValueRef::String(self.read_string()?)),
+                b'a' => self.read_nested(Self::read_array)?,
+                b'O' => self.read_nested(Self::read_object)?,
+                b'C' => self.read_custom()?,
+                b'E' => Node::Value(self.read_enum()?),
+                b'r' => self.read_ref(RefKind::Value)?,
+                _ => self.read_ref(RefKind::Shared)?,
+            }
+        };
+        self.nodes[id] = node;
+        Ok(id)
+    }
+
+    // nodes are consumed in parse order, so a referenced var has always been
+    // built (and wrapped into its shared cell) before anything refers to it.
+    // `r:` gets a copy of its own unless it points at an object, copies share
+    // the objects inside them though, as those are handles in PHP.
+    fn build(&mut self, id: usize) -> Parsed<Box<PhpVar>> {
+        let mut var = match mem::replace(&mut self.nodes[id], Node::Pending) {
+            // scalars hold no references, so this is just a copy
+            Node::Value(v) => v.into_owned(),
+            Node::Array(items) => {
+                // duplicate keys overwrite the earlier value in place
+                let mut arr = PhpArray::new();
+                for (key, id) in items {
+                    arr.insert(key.into_owned(), *self.build(id)?);
+                }
+                PhpVar::Array(arr)
+            },
+            Node::Object(class, items) => {
+                let mut var = if self.opts.allowed_classes.allows(class) {
+                    PhpVar::Object { class: class.to_vec(), props: vec![] }
+                } else {
+                    incomplete_object(class.to_vec())
+                };
+                if let PhpVar::Object { ref mut props, .. } = var {
+                    for (name, id) in items {
+                        props.push((name.to_bytes(), *self.build(id)?));
                     }
-                    Ok(Box::new(PhpVar::Array(_k, _v)))
                 }
-                _ => Ok(obj)
+                var
+            },
+            Node::Ref(kind, target) => {
+                if let Some(v) = self.copies.get(&target) {
+                    return self.copy(v).map(Box::new);
+                }
+                let v = match self.shared.get(&target) {
+                    Some(v) => v.clone(),
+                    None => return self.fail(self.cur, UnserializeErrorKind::DanglingReference, "slot number")
+                };
+                if kind == RefKind::Value && !is_object(&v.borrow()) {
+                    return self.copy(&v.borrow()).map(Box::new);
+                }
+                return Ok(Box::new(PhpVar::Ref(kind, v)));
+            },
+            Node::Pending => return self.fail(self.cur, UnserializeErrorKind::DanglingReference, "slot number")
+        };
+
+        if !is_object(&var) && self.copied.contains(&id) {
+            share_objects(&mut var);
+        }
+        match self.targets[id] {
+            Some(RefKind::Value) if !is_object(&var) => {
+                self.copies.insert(id, var.clone());
+                Ok(Box::new(var))
+            },
+            Some(kind) => {
+                let v = Rc::new(RefCell::new(var));
+                self.shared.insert(id, v.clone());
+                Ok(Box::new(PhpVar::Ref(kind, v)))
+            },
+            None => Ok(Box::new(var)),
+        }
+    }
+
+    fn copy(&self, var: &PhpVar) -> Parsed<PhpVar> {
+        match self.budget.copy(var) {
+            Ok(()) => Ok(var.clone()),
+            Err(_) => self.fail(self.cur, UnserializeErrorKind::LimitExceeded(Limit::Copies), "fewer copies"),
+        }
+    }
+}
+
+// `[+-]?` followed by digits with at most one `.` (at least one digit in
+// total) and an optional `[eE][+-]?[0-9]+` exponent
+fn valid_float(s: &[u8]) -> bool {
+    let s = match s.first() {
+        Some(b'+') | Some(b'-') => &s[1..],
+        _ => s
+    };
+    let (mantissa, exp) = match s.iter().position(|c| *c == b'e' || *c == b'E') {
+        Some(i) => (&s[..i], Some(&s[(i + 1)..])),
+        None => (s, None)
+    };
+    let mut digits = 0;
+    let mut dots = 0;
+    for c in mantissa {
+        match c {
+            b'0'..=b'9' => digits += 1,
+            b'.' => dots += 1,
+            _ => return false
+        }
+    }
+    if digits == 0 || dots > 1 {
+        return false;
+    }
+    match exp {
+        Some(exp) => {
+            let exp = match exp.first() {
+                Some(b'+') | Some(b'-') => &exp[1..],
+                _ => exp
+            };
+            !exp.is_empty() && exp.iter().all(|c| c.is_ascii_digit())
+        },
+        None => true
+    }
+}
+
+// PHP's serialize_precision = -1 output: the shortest digits that round-trip,
+// in exponential notation when the exponent is below -4 or above 16
+pub fn format_float(d: f64) -> String {
+    gcvt(d, None)
+}
+
+// PHP's `precision` setting (14 unless changed), which is what echo and
+// print_r go through: `precision` significant digits, exponential notation
+// once the exponent goes past them
+pub fn format_float_precision(d: f64, precision: usize) -> String {
+    gcvt(d, Some(precision.max(1)))
+}
+
+fn gcvt(d: f64, precision: Option<usize>) -> String {
+    if d.is_nan() {
+        return "NAN".to_string();
+    }
+    if d.is_infinite() {
+        return (if d > 0.0 { "INF" } else { "-INF" }).to_string();
+    }
+
+    let mut t = String::new();
+    if d.is_sign_negative() {
+        t.push('-');
+    }
+    if d == 0.0 {
+        t.push('0');
+        return t;
+    }
+
+    let e = match precision {
+        Some(p) => format!("{:.*e}", p - 1, d.abs()),
+        None => format!("{:e}", d.abs()),
+    };
+    let (mantissa, exp) = e.split_at(e.find('e').unwrap());
+    let digits = mantissa.replace('.', "");
+    let digits = digits.trim_end_matches('0');
+    // position of the decimal point relative to the digits
+    let decpt = exp[1..].parse::<i32>().unwrap() + 1;
+    let ndigit = precision.unwrap_or(17) as i32;
+
+    if !(-3..=ndigit).contains(&decpt) {
+        t.push_str(&digits[..1]);
+        t.push('.');
+        t.push_str(if digits.len() > 1 { &digits[1..] } else { "0" });
+        t.push_str(&format!("E{}{}", if decpt > 0 { '+' } else { '-' }, (decpt - 1).abs()));
+    } else if decpt <= 0 {
+        t.push_str("0.");
+        for _ in decpt..0 {
+            t.push('0');
+        }
+        t.push_str(digits);
+    } else {
+        let decpt = decpt as usize;
+        if digits.len() > decpt {
+            t.push_str(&digits[..decpt]);
+            t.push('.');
+            t.push_str(&digits[decpt..]);
+        } else {
+            t.push_str(digits);
+            for _ in digits.len()..decpt {
+                t.push('0');
             }
         }
-        // println!("total vars = {}", vars.len());
-        translate(obj, &vars)
     }
+    t
+}
+
+// PHP only accepts identifier characters and namespace separators
+fn valid_class_name(class: &[u8]) -> bool {
+    !class.is_empty() && class.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_' || *c == b'\\' || *c >= 0x80)
+}
+
+// reads the whole input into the arena, returning the id of the root node
+fn parse_nodes<'a, 'o>(raw: &'a [u8], opts: &'o UnserializeOptions) -> Parsed<(Parser<'a, 'o>, usize)> {
+    let mut parser = Parser::new(raw, opts);
+    if raw.len() > opts.max_input_len {
+        return parser.fail(opts.max_input_len, UnserializeErrorKind::LimitExceeded(Limit::InputLength), "shorter input");
+    }
+    let id = parser.read_var()?;
+    if parser.len != parser.cur {
+        return parser.fail(parser.cur, UnserializeErrorKind::TrailingData, "end of input");
+    }
+    Ok((parser, id))
+}
+
+fn parse(raw: &[u8], opts: &UnserializeOptions) -> Parsed<Box<PhpVar>> {
+    let (mut parser, id) = parse_nodes(raw, opts)?;
+    parser.build(id)
 }
 
 pub fn unserialize(raw: &[u8]) -> Box<PhpVar> {
-    let mut parser = Parser::new(raw);
-    if let Ok(v) = parser.read_var() {
-        if parser.len == parser.cur {
-            if !parser.has_ref {
-                return v;
-            } else {
-                if let Ok(v) = Parser::fixup(v) {
-                    return v;
+    parse(raw, &UnserializeOptions::default()).unwrap_or_else(|_| Box::new(PhpVar::Bool(false)))
+}
+
+pub fn unserialize_with(raw: &[u8], opts: &UnserializeOptions) -> ::std::result::Result<PhpVar, UnserializeError> {
+    Ok(*parse(raw, opts)?)
+}
+
+pub fn try_unserialize(raw: &[u8]) -> ::std::result::Result<PhpVar, UnserializeError> {
+    unserialize_with(raw, &UnserializeOptions::default())
+}
+
+// how `serialize_with` writes vars. by default it is exactly what PHP's
+// serialize() would write for the same values
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SerializeOptions {
+    // string keys PHP casts to integers ("12") are written as integer keys.
+    // a key that then collides with an existing one replaces its value in
+    // place, like it would in a PHP array literal
+    pub normalize_keys: bool,
+    // array entries sorted by key (integers in numeric order, then strings
+    // byte-wise) and object properties by name, instead of insertion order
+    pub sort_keys: bool,
+    // one text per float value: -0 is written as 0
+    pub canonical_floats: bool,
+}
+
+impl SerializeOptions {
+    // equal sessions serialize to the same bytes, for hashing and signing
+    pub fn canonical() -> Self {
+        SerializeOptions {
+            normalize_keys: true,
+            sort_keys: true,
+            canonical_floats: true,
+        }
+    }
+}
+
+// numbers the var slots the same way PHP does, so that the first occurrence
+// of a shared cell is written out in full and every later one as `r:`/`R:`
+struct Writer {
+    out: Vec<u8>,
+    slot: usize,
+    seen: HashMap<*const RefCell<PhpVar>, usize>,
+    opts: SerializeOptions,
+}
+
+impl Writer {
+    fn new() -> Self {
+        Writer::with_options(SerializeOptions::default())
+    }
+
+    fn with_options(opts: SerializeOptions) -> Self {
+        Writer {
+            out: vec![],
+            slot: 0,
+            seen: HashMap::new(),
+            opts,
+        }
+    }
+
+    // the entries of `arr` in the order and with the keys they are written
+    // with. slots follow the written order, so refs stay consistent
+    fn entries<'v>(&self, arr: &'v PhpArray) -> Vec<(PhpKey, &'v PhpVar)> {
+        let mut entries: Vec<(PhpKey, &PhpVar)> = vec![];
+        let mut index: HashMap<PhpKey, usize> = HashMap::new();
+        for (k, v) in arr {
+            let k = match k {
+                PhpKey::String(s) if self.opts.normalize_keys => PhpKey::from_bytes(s),
+                k => k.clone(),
+            };
+            match index.get(&k) {
+                Some(&i) => entries[i].1 = v,
+                None => {
+                    index.insert(k.clone(), entries.len());
+                    entries.push((k, v));
                 }
             }
         }
+        if self.opts.sort_keys {
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+        entries
     }
-    Box::new(PhpVar::Bool(false))
-}
 
-pub fn serialize(var: &PhpVar) -> Result<Vec<u8>> {
-    match var {
-        PhpVar::Null => Ok(b"N;".to_vec()),
-        PhpVar::Bool(b) => Ok((if *b { b"b:1;" } else { b"b:0;" }).to_vec()),
-        PhpVar::Int(i) => Ok(format!("i:{};", i).as_bytes().to_vec()),
-        PhpVar::Float(f) => Ok(format!("d:{};", f).as_bytes().to_vec()),
-        PhpVar::Ref(r) => Ok(format!("r:{};", r).as_bytes().to_vec()),
-        PhpVar::String(s) => {
-            let mut t = format!("s:{}:\"", s.len()).as_bytes().to_vec();
-            t.extend_from_slice(&s);
-            t.push(34); // "
-            t.push(59); // ;
-            Ok(t)
-        },
-        PhpVar::Array(k, v) => {
-            let mut t = format!("a:{}:{{", k.len()).as_bytes().to_vec();
-            for i in 0..k.len() {
-                t.extend_from_slice(&serialize(&k[i])?);
-                t.extend_from_slice(&serialize(&v[i])?);
+    fn write_var(&mut self, var: &PhpVar) -> Result<()> {
+        self.slot += 1;
+        match var {
+            PhpVar::Null => self.out.extend_from_slice(b"N;"),
+            PhpVar::Bool(b) => self.out.extend_from_slice(if *b { b"b:1;" } else { b"b:0;" }),
+            PhpVar::Int(i) => self.out.extend_from_slice(format!("i:{};", i).as_bytes()),
+            PhpVar::Float(f) if *f == 0.0 && self.opts.canonical_floats => self.out.extend_from_slice(b"d:0;"),
+            PhpVar::Float(f) => self.out.extend_from_slice(format!("d:{};", format_float(*f)).as_bytes()),
+            PhpVar::String(s) => self.write_bytes(s),
+            PhpVar::Array(arr) if self.opts.normalize_keys || self.opts.sort_keys => {
+                let entries = self.entries(arr);
+                self.out.extend_from_slice(format!("a:{}:{{", entries.len()).as_bytes());
+                for (k, v) in entries {
+                    self.write_key(&k);
+                    self.write_var(v)?;
+                }
+                self.out.push(125); // }
+            },
+            PhpVar::Array(arr) => {
+                self.out.extend_from_slice(format!("a:{}:{{", arr.len()).as_bytes());
+                for (k, v) in arr {
+                    self.write_key(k);
+                    self.write_var(v)?;
+                }
+                self.out.push(125); // }
+            },
+            PhpVar::Object { class, props } => {
+                // incomplete class objects go back out under the class they
+                // were read as
+                let (class, props) = match var.incomplete_class() {
+                    Some(name) => (name, &props[1..]),
+                    None => (class.as_slice(), &props[..]),
+                };
+                let mut props: Vec<_> = props.iter().collect();
+                if self.opts.sort_keys {
+                    props.sort_by(|a, b| a.0.cmp(&b.0));
+                }
+                self.out.extend_from_slice(format!("O:{}:\"", class.len()).as_bytes());
+                self.out.extend_from_slice(class);
+                self.out.extend_from_slice(format!("\":{}:{{", props.len()).as_bytes());
+                for (name, v) in props {
+                    self.write_bytes(name);
+                    self.write_var(v)?;
+                }
+                self.out.push(125); // }
+            },
+            PhpVar::Custom { class, data } => {
+                self.out.extend_from_slice(format!("C:{}:\"", class.len()).as_bytes());
+                self.out.extend_from_slice(class);
+                self.out.extend_from_slice(format!("\":{}:{{", data.len()).as_bytes());
+                self.out.extend_from_slice(data);
+                self.out.push(125); // }
+            },
+            PhpVar::Enum { class, case } => {
+                self.out.extend_from_slice(format!("E:{}:\"", class.len() + case.len() + 1).as_bytes());
+                self.out.extend_from_slice(class);
+                self.out.push(58); // :
+                self.out.extend_from_slice(case);
+                self.out.push(34); // "
+                self.out.push(59); // ;
+            },
+            PhpVar::Ref(kind, v) => {
+                let ptr = Rc::as_ptr(v);
+                if let Some(&n) = self.seen.get(&ptr) {
+                    match kind {
+                        RefKind::Value => self.out.extend_from_slice(format!("r:{};", n).as_bytes()),
+                        RefKind::Shared => {
+                            // `R:` does not take a slot of its own
+                            self.out.extend_from_slice(format!("R:{};", n).as_bytes());
+                            self.slot -= 1;
+                        }
+                    }
+                } else {
+                    self.seen.insert(ptr, self.slot);
+                    self.slot -= 1;
+                    self.write_var(&v.borrow())?;
+                }
             }
-            t.push(125); // }
-            Ok(t)
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, s: &[u8]) {
+        self.out.extend_from_slice(format!("s:{}:\"", s.len()).as_bytes());
+        self.out.extend_from_slice(s);
+        self.out.push(34); // "
+        self.out.push(59); // ;
+    }
+
+    fn write_key(&mut self, key: &PhpKey) {
+        match key {
+            PhpKey::Int(i) => self.out.extend_from_slice(format!("i:{};", i).as_bytes()),
+            PhpKey::String(s) => self.write_bytes(s),
         }
     }
 }
 
+pub fn serialize(var: &PhpVar) -> Result<Vec<u8>> {
+    serialize_with(var, &SerializeOptions::default())
+}
+
+pub fn serialize_with(var: &PhpVar, opts: &SerializeOptions) -> Result<Vec<u8>> {
+    let mut ser = Writer::with_options(opts.clone());
+    ser.write_var(var)?;
+    Ok(ser.out)
+}
+
 #[test]
 fn test_basic_unserialize() {
     assert_ne!(*unserialize(b"N;"), PhpVar::Bool(false));
@@ -296,9 +1295,41 @@ fn test_basic_serialize() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_canonical_serialize() -> Result<()> {
+    let canonical = SerializeOptions::canonical();
+    // the same array built in two orders, once with a key PHP would cast
+    let mut a = PhpArray::new();
+    a.insert("b", PhpVar::Float(-0.0));
+    a.insert(PhpKey::String(b"10".to_vec()), PhpVar::Int(1));
+    a.insert(2, PhpVar::Null);
+    let mut b = PhpArray::new();
+    b.insert(2, PhpVar::Null);
+    b.insert(10, PhpVar::Int(1));
+    b.insert("b", PhpVar::Float(0.0));
+    assert_ne!(serialize(&PhpVar::Array(a.clone()))?, serialize(&PhpVar::Array(b.clone()))?);
+    assert_eq!(serialize_with(&PhpVar::Array(a.clone()), &canonical)?, b"a:3:{i:2;N;i:10;i:1;s:1:\"b\";d:0;}".to_vec());
+    assert_eq!(serialize_with(&PhpVar::Array(b), &canonical)?, serialize_with(&PhpVar::Array(a.clone()), &canonical)?);
+
+    // a cast key colliding with an existing one keeps the later value
+    a.insert(10, PhpVar::Int(2));
+    let opts = SerializeOptions { normalize_keys: true, ..Default::default() };
+    assert_eq!(serialize_with(&PhpVar::Array(a), &opts)?, b"a:3:{s:1:\"b\";d:-0;i:10;i:2;i:2;N;}".to_vec());
+
+    // slots are numbered in the sorted order
+    let var = unserialize_with(b"a:3:{s:1:\"z\";a:0:{}s:1:\"y\";R:2;s:1:\"x\";i:1;}", &UnserializeOptions::default()).unwrap();
+    let raw = serialize_with(&var, &canonical)?;
+    assert_eq!(raw, b"a:3:{s:1:\"x\";i:1;s:1:\"y\";a:0:{}s:1:\"z\";R:3;}".to_vec());
+    assert_eq!(serialize_with(&unserialize_with(&raw, &UnserializeOptions::default()).unwrap(), &canonical)?, raw);
+
+    let var = unserialize_with(b"O:3:\"Foo\":2:{s:1:\"b\";i:1;s:1:\"a\";i:2;}", &UnserializeOptions::default()).unwrap();
+    assert_eq!(serialize_with(&var, &canonical)?, b"O:3:\"Foo\":2:{s:1:\"a\";i:2;s:1:\"b\";i:1;}".to_vec());
+    Ok(())
+}
+
 #[cfg(test)]
 fn serialize_then_unserialize(raw: &[u8]) -> Result<()> {
-    let var = unserialize(&raw);
+    let var = unserialize(raw);
     let res = serialize(&var)?;
     println!("{:?} {:?}", raw, res);
     assert_eq!(raw.to_vec(), res);
@@ -317,11 +1348,494 @@ fn test_unserialize_serialize() -> Result<()> {
     Ok(())
 }
 
-/* NOTE: do not test because Ref is buggy
 #[test]
 fn test_unserialize_ref() -> Result<()> {
     assert_eq!(*unserialize(b"a:2:{i:0;a:0:{}i:1;r:3;}"), PhpVar::Bool(false));
     assert_eq!(serialize(&unserialize(b"a:2:{i:0;a:0:{}i:1;r:2;}"))?, b"a:2:{i:0;a:0:{}i:1;a:0:{}}");
+    if let PhpVar::Array(arr) = *unserialize(b"a:2:{i:0;a:0:{}i:1;r:2;}") {
+        assert_eq!(arr.get(0), Some(&PhpVar::Array(PhpArray::new())));
+        assert_eq!(arr.get(1), Some(&PhpVar::Array(PhpArray::new())));
+    } else {
+        panic!("not an array");
+    }
+
+    // objects are handles, so copies still share them
+    serialize_then_unserialize(b"a:2:{i:0;O:8:\"stdClass\":0:{}i:1;r:2;}")?;
+    let raw = b"a:2:{i:0;a:1:{i:0;O:8:\"stdClass\":0:{}}i:1;r:2;}";
+    assert_eq!(serialize(&unserialize(raw))?, b"a:2:{i:0;a:1:{i:0;O:8:\"stdClass\":0:{}}i:1;a:1:{i:0;r:3;}}");
+    Ok(())
+}
+
+#[test]
+fn test_unserialize_shared_ref() -> Result<()> {
+    // $x = 1; $a = [&$x, &$x, 5, $x]; serialize($a);
+    serialize_then_unserialize(b"a:4:{i:0;i:1;i:1;R:2;i:2;i:5;i:3;i:1;}")?;
+    // `R:` takes no slot, so r:3 is a copy of the int 5
+    assert_eq!(serialize(&unserialize(b"a:4:{i:0;i:1;i:1;R:2;i:2;i:5;i:3;r:3;}"))?, b"a:4:{i:0;i:1;i:1;R:2;i:2;i:5;i:3;i:5;}");
+    assert_eq!(serialize(&unserialize(b"a:3:{i:0;a:0:{}i:1;R:2;i:2;r:2;}"))?, b"a:3:{i:0;a:0:{}i:1;R:2;i:2;a:0:{}}");
+
+    if let PhpVar::Array(arr) = *unserialize(b"a:4:{i:0;i:1;i:1;R:2;i:2;i:5;i:3;r:3;}") {
+        assert!(arr.get(3).unwrap().with_value(|v| *v == PhpVar::Int(5)));
+        match (arr.get(0).unwrap(), arr.get(1).unwrap()) {
+            (PhpVar::Ref(RefKind::Shared, a), PhpVar::Ref(RefKind::Shared, b)) => {
+                *a.borrow_mut() = PhpVar::Int(2);
+                assert_eq!(*b.borrow(), PhpVar::Int(2));
+            },
+            _ => panic!("not a reference")
+        }
+    } else {
+        panic!("not an array");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_unserialize_bad_ref() {
+    // self, enclosing array, forward and out of range references
+    assert_eq!(*unserialize(b"r:1;"), PhpVar::Bool(false));
+    assert_eq!(*unserialize(b"a:1:{i:0;r:1;}"), PhpVar::Bool(false));
+    assert_eq!(*unserialize(b"a:1:{i:0;R:1;}"), PhpVar::Bool(false));
+    assert_eq!(*unserialize(b"a:2:{i:0;r:3;i:1;i:1;}"), PhpVar::Bool(false));
+    assert_eq!(*unserialize(b"a:1:{i:0;r:0;}"), PhpVar::Bool(false));
+    assert_eq!(*unserialize(b"a:2:{i:0;i:1;i:1;R:3;}"), PhpVar::Bool(false));
+}
+
+#[cfg(test)]
+use serde::Deserialize;
+
+#[test]
+fn test_unserialize_ref_expansion() -> Result<()> {
+    // every level holds the previous one twice, which `R:` shares instead of
+    // expanding and `r:` has to copy
+    let expanding = |levels: usize, tag: char| {
+        let mut raw = format!("a:{}:{{i:0;a:0:{{}}", levels).into_bytes();
+        let (mut prev, mut next) = (2, 3);
+        for i in 1..levels {
+            raw.extend_from_slice(format!("i:{};a:2:{{i:0;{}:{};i:1;{}:{};}}", i, tag, prev, tag, prev).as_bytes());
+            prev = next;
+            next += if tag == 'r' { 3 } else { 1 };
+        }
+        raw.push(b'}');
+        raw
+    };
+    let opts = UnserializeOptions::default();
+    assert_eq!(exceeded(&expanding(64, 'r'), &opts), Some(Limit::Copies));
+    let err = unserialize_ref_with(&expanding(64, 'r'), &opts).unwrap_err();
+    assert_eq!(err.kind, UnserializeErrorKind::LimitExceeded(Limit::Copies));
+    assert_eq!(serialize(&unserialize(&expanding(3, 'r')))?,
+               b"a:3:{i:0;a:0:{}i:1;a:2:{i:0;a:0:{}i:1;a:0:{}}i:2;a:2:{i:0;a:2:{i:0;a:0:{}i:1;a:0:{}}i:1;a:2:{i:0;a:0:{}i:1;a:0:{}}}}".to_vec());
+    let raw = expanding(64, 'R');
+    serialize_then_unserialize(&raw)?;
+
+    // everything else reads each holder on its own and has to give up
+    let var = unserialize_with(&raw, &opts)?;
+    for res in [var_dump(&var), print_r(&var), var_export(&var)] {
+        assert!(matches!(res.unwrap_err().kind(), ErrorKind::ExpansionLimit));
+    }
+    assert!(matches!(to_json(&var).unwrap_err().kind(), ErrorKind::ExpansionLimit));
+    let err = from_var::<serde_json::Value>(var).unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::ExpansionLimit), "{}", err);
+    let small = unserialize_with(b"a:2:{i:0;a:1:{i:0;s:1:\"x\";}i:1;r:2;}", &UnserializeOptions::default())?;
+    assert_eq!(from_var::<Vec<Vec<String>>>(small.clone())?, vec![vec!["x"], vec!["x"]]);
+    let limits = ExpansionLimits { max_nodes: 3, ..Default::default() };
+    let err = <Vec<Vec<String>>>::deserialize(Deserializer::with_limits(small, &limits)).unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::ExpansionLimit), "{}", err);
+    Ok(())
+}
+
+#[cfg(test)]
+fn exceeded(raw: &[u8], opts: &UnserializeOptions) -> Option<Limit> {
+    match unserialize_with(raw, opts) {
+        Err(UnserializeError { kind: UnserializeErrorKind::LimitExceeded(limit), .. }) => Some(limit),
+        _ => None
+    }
+}
+
+#[test]
+fn test_unserialize_limits() -> Result<()> {
+    let opts = UnserializeOptions::default();
+    // declared lengths and counts far beyond the input
+    assert_eq!(exceeded(b"s:18446744073709551611:\"\";", &opts), Some(Limit::StringLength));
+    assert_eq!(exceeded(b"C:8:\"stdClass\":18446744073709551611:{}", &opts), Some(Limit::StringLength));
+    assert_eq!(exceeded(b"a:18446744073709551615:{}", &opts), Some(Limit::Elements));
+    assert_eq!(exceeded(b"O:8:\"stdClass\":4294967296:{}", &opts), Some(Limit::Elements));
+    // within the limits but still truncated
+    assert_eq!(exceeded(b"s:5:\"\";", &opts), None);
+    assert_eq!(*unserialize(b"a:1000:{}"), PhpVar::Bool(false));
+
+    let deep = |n: usize| {
+        let mut raw = b"a:1:{i:0;".repeat(n);
+        raw.extend_from_slice(b"N;");
+        raw.extend_from_slice(&b"}".repeat(n));
+        raw
+    };
+    unserialize_with(&deep(opts.max_depth), &opts)?;
+    assert_eq!(exceeded(&deep(opts.max_depth + 1), &opts), Some(Limit::Depth));
+    assert_eq!(exceeded(&deep(100_000), &opts), Some(Limit::Depth));
+
+    let opts = UnserializeOptions {
+        max_elements: 2,
+        max_string_len: 2,
+        max_input_len: 18,
+        ..Default::default()
+    };
+    unserialize_with(b"a:1:{i:0;N;}", &opts)?;
+    assert_eq!(exceeded(b"a:2:{}", &opts), Some(Limit::Elements));
+    assert_eq!(exceeded(b"a:1:{i:0;a:1:{}}", &opts), Some(Limit::Elements));
+    unserialize_with(b"s:2:\"ab\";", &opts)?;
+    assert_eq!(exceeded(b"s:3:\"abc\";", &opts), Some(Limit::StringLength));
+    assert_eq!(exceeded(b"a:1:{s:3:\"abc\";N;}", &opts), Some(Limit::StringLength));
+    assert_eq!(exceeded(b"a:1:{i:0;s:2:\"ab\";}", &opts), Some(Limit::InputLength));
+    Ok(())
+}
+
+#[test]
+fn test_unserialize_errors() {
+    use self::UnserializeErrorKind::*;
+    let cases: &[(&[u8], usize, UnserializeErrorKind)] = &[
+        (b"", 0, Truncated),
+        (b"a:2:{i:0;N;", 11, Truncated),
+        (b"s:3:\"ab", 7, Truncated),
+        (b"s:1:\"ab\";", 6, BadLength),
+        (b"a:2:{i:0;N;}", 11, BadLength),
+        (b"a:1:{i:0;N;i:1;N;}", 11, BadLength),
+        (b"x:1;", 0, UnknownType),
+        (b"a:1:{N;N;}", 5, UnknownType),
+        (b"a:2:{i:0;N;i:1;r:5;}", 17, DanglingReference),
+        (b"a:1:{i:0;R:1;}", 11, DanglingReference),
+        (b"i:1;i:2;", 4, TrailingData),
+        (b"b:2;", 2, Malformed),
+        (b"i:1x", 3, Malformed),
+        (b"d:1.2.3;", 2, Malformed),
+        (b"O:1:\"-\":0:{}", 2, Malformed),
+        (b"i:99999999999999999999;", 2, Malformed),
+    ];
+    for (raw, offset, kind) in cases {
+        let e = try_unserialize(raw).unwrap_err();
+        assert_eq!((e.offset, e.kind), (*offset, *kind), "{}", String::from_utf8_lossy(raw));
+    }
+
+    // b:0; is still told apart from a rejected payload
+    assert_eq!(try_unserialize(b"b:0;"), Ok(PhpVar::Bool(false)));
+    assert_eq!(try_unserialize(b"i:1").unwrap_err().to_string(), "truncated input at offset 3, expected ';'");
+}
+
+#[test]
+fn test_allowed_classes() -> Result<()> {
+    let raw = b"a:2:{i:0;O:3:\"Foo\":1:{s:1:\"a\";i:1;}i:1;r:3;}";
+    let opts = UnserializeOptions { allowed_classes: AllowedClasses::Only(vec![]), ..Default::default() };
+    let var = unserialize_with(raw, &opts)?;
+    let obj = match &var {
+        PhpVar::Array(arr) => arr.get(0).unwrap(),
+        _ => panic!()
+    };
+    // php > var_dump(unserialize('O:3:"Foo":1:{s:1:"a";i:1;}', ['allowed_classes' => false]));
+    // object(__PHP_Incomplete_Class)#1 (2) {
+    //   ["__PHP_Incomplete_Class_Name"]=>
+    //   string(3) "Foo"
+    //   ["a"]=>
+    //   int(1)
+    // }
+    match obj {
+        PhpVar::Object { class, props } => {
+            assert_eq!(class.as_slice(), INCOMPLETE_CLASS);
+            assert_eq!(props.len(), 2);
+        },
+        _ => panic!()
+    }
+    assert_eq!(obj.incomplete_class(), Some(&b"Foo"[..]));
+    assert_eq!(obj.public(b"a").unwrap().with_value(|a| a.clone()), PhpVar::Int(1));
+    // the original class comes back on serialize, the `r:` copy is written out
+    assert_eq!(serialize(&var)?, b"a:2:{i:0;O:3:\"Foo\":1:{s:1:\"a\";i:1;}i:1;i:1;}".to_vec());
+
+    let opts = UnserializeOptions { allowed_classes: AllowedClasses::Only(vec![b"foo".to_vec()]), ..Default::default() };
+    match unserialize_with(raw, &opts)? {
+        PhpVar::Array(arr) => assert_eq!(arr.get(0).unwrap().incomplete_class(), None),
+        _ => panic!()
+    }
+
+    // custom payloads cannot be restored and enums have no incomplete form
+    let opts = UnserializeOptions { allowed_classes: AllowedClasses::Only(vec![]), ..Default::default() };
+    let var = unserialize_with(b"C:11:\"ArrayObject\":4:{abcd}", &opts)?;
+    assert_eq!(var.incomplete_class(), Some(&b"ArrayObject"[..]));
+    assert_eq!(serialize(&var)?, b"O:11:\"ArrayObject\":0:{}".to_vec());
+    assert_eq!(unserialize_with(b"E:7:\"Foo:Bar\";", &opts).unwrap_err().kind, UnserializeErrorKind::DisallowedClass);
+    Ok(())
+}
+
+#[test]
+fn test_unserialize_object() -> Result<()> {
+    serialize_then_unserialize(b"O:8:\"stdClass\":0:{}")?;
+    serialize_then_unserialize(b"O:8:\"stdClass\":2:{s:1:\"a\";i:1;s:1:\"b\";a:0:{}}")?;
+    serialize_then_unserialize(b"C:11:\"ArrayObject\":21:{x:i:0;a:0:{};m:a:0:{}}")?;
+    serialize_then_unserialize(b"O:14:\"App\\Model\\User\":0:{}")?;
+    // the same object twice is a shared handle
+    serialize_then_unserialize(b"a:2:{i:0;O:8:\"stdClass\":1:{s:1:\"a\";i:1;}i:1;r:2;}")?;
+
+    assert_eq!(*unserialize(b"O:0:\"\":0:{}"), PhpVar::Bool(false));
+    assert_eq!(*unserialize(b"O:3:\"a-b\":0:{}"), PhpVar::Bool(false));
+    assert_eq!(*unserialize(b"O:8:\"stdClass\":1:{a:0:{}i:1;}"), PhpVar::Bool(false));
+    assert_eq!(*unserialize(b"C:8:\"stdClass\":3:{ab}"), PhpVar::Bool(false));
+    assert_eq!(*unserialize(b"C:8:\"stdClass\":30:{ab}"), PhpVar::Bool(false));
+    Ok(())
+}
+
+#[test]
+fn test_object_properties() -> Result<()> {
+    let mut obj = *unserialize(b"O:4:\"User\":3:{s:4:\"name\";s:3:\"bob\";s:7:\"\0*\0role\";i:1;s:10:\"\0User\0pass\";s:2:\"pw\";}");
+    assert_eq!(obj.public(b"name"), Some(&PhpVar::String(b"bob".to_vec())));
+    assert_eq!(obj.protected(b"role"), Some(&PhpVar::Int(1)));
+    assert_eq!(obj.private(b"User", b"pass"), Some(&PhpVar::String(b"pw".to_vec())));
+    assert_eq!(obj.public(b"pass"), None);
+    assert_eq!(obj.private(b"Admin", b"pass"), None);
+
+    let props = obj.properties();
+    assert_eq!(props[1].0, Visibility::Protected);
+    assert_eq!(props[2].0, Visibility::Private(b"User".to_vec()));
+    assert_eq!(props[2].1, b"pass");
+
+    obj.set_property(&Visibility::Protected, b"role", PhpVar::Int(2))?;
+    obj.set_property(&Visibility::Public, b"id", PhpVar::Int(7))?;
+    assert_eq!(serialize(&obj)?, b"O:4:\"User\":4:{s:4:\"name\";s:3:\"bob\";s:7:\"\0*\0role\";i:2;s:10:\"\0User\0pass\";s:2:\"pw\";s:2:\"id\";i:7;}".to_vec());
+    Ok(())
+}
+
+#[test]
+fn test_unserialize_enum() -> Result<()> {
+    serialize_then_unserialize(b"E:11:\"Suit:Hearts\";")?;
+    serialize_then_unserialize(b"a:2:{i:0;E:11:\"Suit:Hearts\";i:1;E:15:\"App\\Suit:Spades\";}")?;
+    assert_eq!(*unserialize(b"E:11:\"Suit:Hearts\";"),
+               PhpVar::Enum { class: b"Suit".to_vec(), case: b"Hearts".to_vec() });
+
+    assert_eq!(*unserialize(b"E:4:\"Suit\";"), PhpVar::Bool(false));
+    assert_eq!(*unserialize(b"E:5:\"Suit:\";"), PhpVar::Bool(false));
+    assert_eq!(*unserialize(b"E:7:\":Hearts\";"), PhpVar::Bool(false));
+    assert_eq!(*unserialize(b"E:12:\"Suit:He:arts\";"), PhpVar::Bool(false));
+    Ok(())
+}
+
+#[test]
+fn test_scalar_fixtures() -> Result<()> {
+    // php -d serialize_precision=-1 -r 'echo serialize($v);'
+    let fixtures: Vec<(&[u8], PhpVar)> = vec![
+        (b"i:0;", PhpVar::Int(0)),
+        (b"i:-5;", PhpVar::Int(-5)),
+        (b"i:9223372036854775807;", PhpVar::Int(i64::MAX)),
+        (b"i:-9223372036854775808;", PhpVar::Int(i64::MIN)),
+        (b"b:0;", PhpVar::Bool(false)),
+        (b"d:0;", PhpVar::Float(0.0)),
+        (b"d:-0;", PhpVar::Float(-0.0)),
+        (b"d:1;", PhpVar::Float(1.0)),
+        (b"d:-1.5;", PhpVar::Float(-1.5)),
+        (b"d:0.1;", PhpVar::Float(0.1)),
+        (b"d:0.30000000000000004;", PhpVar::Float(0.1 + 0.2)),
+        (b"d:3.141592653589793;", PhpVar::Float(std::f64::consts::PI)),
+        (b"d:0.0001;", PhpVar::Float(0.0001)),
+        (b"d:1.0E-5;", PhpVar::Float(0.00001)),
+        (b"d:1.5E-7;", PhpVar::Float(1.5e-7)),
+        (b"d:123456.789;", PhpVar::Float(123456.789)),
+        (b"d:1000000000000000;", PhpVar::Float(1e15)),
+        (b"d:1.0E+17;", PhpVar::Float(1e17)),
+        (b"d:10000000000000000;", PhpVar::Float(1e16)),
+        (b"d:1.0E+25;", PhpVar::Float(1e25)),
+        (b"d:-2.5E+100;", PhpVar::Float(-2.5e100)),
+        (b"d:1.2345678901234568E+17;", PhpVar::Float(123456789012345678.0)),
+        (b"d:1.7976931348623157E+308;", PhpVar::Float(f64::MAX)),
+        (b"d:INF;", PhpVar::Float(f64::INFINITY)),
+        (b"d:-INF;", PhpVar::Float(f64::NEG_INFINITY)),
+    ];
+    for (raw, var) in fixtures {
+        assert_eq!(*unserialize(raw), var);
+        assert_eq!(serialize(&var)?, raw);
+    }
+
+    match *unserialize(b"d:NAN;") {
+        PhpVar::Float(f) => assert!(f.is_nan()),
+        _ => panic!("not a float")
+    }
+    assert_eq!(serialize(&PhpVar::Float(f64::NAN))?, b"d:NAN;");
+    Ok(())
+}
+
+#[test]
+fn test_scalar_lenient() {
+    // accepted by PHP even though serialize never writes them
+    assert_eq!(*unserialize(b"i:+5;"), PhpVar::Int(5));
+    assert_eq!(*unserialize(b"i:007;"), PhpVar::Int(7));
+    assert_eq!(*unserialize(b"d:.5;"), PhpVar::Float(0.5));
+    assert_eq!(*unserialize(b"d:5.;"), PhpVar::Float(5.0));
+    assert_eq!(*unserialize(b"d:-1.5e-3;"), PhpVar::Float(-0.0015));
+    assert_eq!(*unserialize(b"d:1E5;"), PhpVar::Float(100000.0));
+    assert_eq!(*unserialize(b"d:12;"), PhpVar::Float(12.0));
+}
+
+#[test]
+fn test_scalar_invalid() {
+    let invalid: Vec<&[u8]> = vec![
+        b"i:;", b"i:-;", b"i:1.5;", b"i: 1;",
+        b"i:9223372036854775808;", b"i:-9223372036854775809;", b"i:99999999999999999999;",
+        b"b:2;", b"b:01;", b"b:;",
+        b"d:;", b"d:.;", b"d:1e;", b"d:1e+;", b"d:1.2.3;", b"d:0x10;",
+        b"d:inf;", b"d:Infinity;", b"d:+INF;", b"d:-NAN;", b"d:nan;",
+        b"s:-1:\"\";", b"s:+1:\"a\";", b"s:99999999999999999999:\"\";",
+        b"a:-1:{}", b"r:-1;",
+    ];
+    for raw in invalid {
+        assert_eq!(*unserialize(raw), PhpVar::Bool(false), "{}", String::from_utf8_lossy(raw));
+    }
+}
+
+#[test]
+fn test_unserialize_array_keys() -> Result<()> {
+    // numeric string keys become int keys and later duplicates win
+    let var = *unserialize(b"a:4:{s:1:\"1\";s:1:\"a\";s:2:\"01\";s:1:\"b\";i:1;s:1:\"c\";s:1:\"x\";s:1:\"d\";}");
+    assert_eq!(serialize(&var)?, b"a:3:{i:1;s:1:\"c\";s:2:\"01\";s:1:\"b\";s:1:\"x\";s:1:\"d\";}".to_vec());
+    if let PhpVar::Array(arr) = var {
+        assert_eq!(arr.get("1"), Some(&PhpVar::String(b"c".to_vec())));
+        assert_eq!(arr.get("01"), Some(&PhpVar::String(b"b".to_vec())));
+        assert_eq!(arr.next_index(), 2);
+    } else {
+        panic!("not an array");
+    }
+    Ok(())
+}
+
+// random vars for round-trip properties. a shared cell is only ever
+// referenced with one kind and at least twice, the way PHP writes them (a
+// single `&` is not distinguishable from a plain value), and never from
+// inside itself. `r:` cells only hold objects, anything else is copied
+#[cfg(test)]
+struct VarGen {
+    rng: rand::prng::XorShiftRng,
+    cells: Vec<(RefKind, Rc<RefCell<PhpVar>>, usize)>,
+}
+
+#[cfg(test)]
+impl VarGen {
+    fn new(seed: u32) -> Self {
+        use rand::SeedableRng;
+        let mut bytes = [0; 16];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = (seed.wrapping_mul(0x9e37_79b9) >> (i % 4 * 8)) as u8 ^ (i as u8).wrapping_mul(41) ^ 0x5a;
+        }
+        VarGen { rng: rand::prng::XorShiftRng::from_seed(bytes), cells: vec![] }
+    }
+
+    fn bytes(&mut self) -> Vec<u8> {
+        use rand::Rng;
+        let l = self.rng.gen_range(0, 8);
+        // quotes, `;` and `}` in strings must not confuse the lengths
+        (0..l).map(|_| *self.rng.choose(b"ab0\";:{}\x00\xff").unwrap()).collect()
+    }
+
+    fn int(&mut self) -> i64 {
+        use rand::Rng;
+        if self.rng.gen() { self.rng.gen_range(-100, 100) } else { self.rng.gen() }
+    }
+
+    fn class(&mut self) -> Vec<u8> {
+        use rand::Rng;
+        self.rng.choose(&["Foo", "stdClass", "A\\B", "_x1"]).unwrap().as_bytes().to_vec()
+    }
+
+    fn array(&mut self, depth: usize) -> PhpArray {
+        use rand::Rng;
+        let mut arr = PhpArray::new();
+        for _i in 0..self.rng.gen_range(0, 5) {
+            let key = if self.rng.gen() { PhpKey::Int(self.int()) } else { PhpKey::from_bytes(&self.bytes()) };
+            // an overwritten ref would not be written at all
+            if !arr.contains_key(&key) {
+                let v = self.var(depth);
+                arr.insert(key, v);
+            }
+        }
+        arr
+    }
+
+    fn var(&mut self, depth: usize) -> PhpVar {
+        use rand::Rng;
+        if !self.rng.gen_bool(0.2) {
+            return self.value(depth);
+        }
+        if !self.cells.is_empty() && self.rng.gen() {
+            let i = self.rng.gen_range(0, self.cells.len());
+            let (kind, cell, uses) = &mut self.cells[i];
+            *uses += 1;
+            return PhpVar::Ref(*kind, cell.clone());
+        }
+        let value = self.value(depth);
+        let kind = if is_object(&value) && self.rng.gen() { RefKind::Value } else { RefKind::Shared };
+        let cell = Rc::new(RefCell::new(value));
+        self.cells.push((kind, cell.clone(), 1));
+        PhpVar::Ref(kind, cell)
+    }
+
+    fn value(&mut self, depth: usize) -> PhpVar {
+        use rand::Rng;
+        match self.rng.gen_range(0, if depth == 0 { 7 } else { 10 }) {
+            0 => PhpVar::Null,
+            1 => PhpVar::Bool(self.rng.gen()),
+            2 => PhpVar::Int(self.int()),
+            3 => match self.rng.gen_range(0, 4) {
+                0 => PhpVar::Float(*self.rng.choose(&[0.0, -0.0, 0.1, 1e100, f64::INFINITY, f64::NEG_INFINITY]).unwrap()),
+                1 => PhpVar::Float(self.int() as f64 / 8.0),
+                // any finite double, subnormals included
+                _ => loop {
+                    let f = f64::from_bits(self.rng.gen());
+                    if f.is_finite() {
+                        break PhpVar::Float(f);
+                    }
+                }
+            },
+            4 => PhpVar::String(self.bytes()),
+            5 => PhpVar::Custom { class: self.class(), data: self.bytes() },
+            6 => PhpVar::Enum { class: self.class(), case: self.rng.choose(&[&b"Bar"[..], b"CASE_2"]).unwrap().to_vec() },
+            7 | 8 => PhpVar::Array(self.array(depth - 1)),
+            _ => {
+                let mut props: Vec<(Vec<u8>, PhpVar)> = vec![];
+                for _i in 0..self.rng.gen_range(0, 4) {
+                    let name = self.bytes();
+                    if props.iter().all(|(n, _)| *n != name) {
+                        let v = self.var(depth - 1);
+                        props.push((name, v));
+                    }
+                }
+                PhpVar::Object { class: self.class(), props }
+            }
+        }
+    }
+
+    // an array holding references to cells that are only used once more
+    fn root(&mut self, depth: usize) -> PhpVar {
+        let mut arr = self.array(depth);
+        for (kind, cell, uses) in &self.cells {
+            if *uses == 1 {
+                let _ = arr.push(PhpVar::Ref(*kind, cell.clone()));
+            }
+        }
+        self.cells.clear();
+        PhpVar::Array(arr)
+    }
+}
+
+#[test]
+fn test_round_trip_arbitrary() -> Result<()> {
+    let runs = if cfg!(miri) { 8 } else { 512 };
+    for seed in 0..runs {
+        let var = VarGen::new(seed).root(4);
+        let raw = serialize(&var)?;
+        let back = match unserialize_with(&raw, &UnserializeOptions::default()) {
+            Ok(back) => back,
+            Err(e) => panic!("{}: {}", String::from_utf8_lossy(&raw), e)
+        };
+        assert_eq!(back, var, "{}", String::from_utf8_lossy(&raw));
+        // equal values could still have lost their sharing
+        assert_eq!(serialize(&back)?, raw);
+
+        // sorting must keep the slot numbers of refs consistent
+        let canonical = serialize_with(&var, &SerializeOptions::canonical())?;
+        let back = unserialize_with(&canonical, &UnserializeOptions::default()).unwrap();
+        assert_eq!(serialize_with(&back, &SerializeOptions::canonical())?, canonical);
+    }
     Ok(())
 }
-*/
@@ -0,0 +1,297 @@
+use std::fmt;
+use std::fmt::Write;
+use std::collections::HashMap;
+use std::iter::FromIterator;
+
+use crate::errors::*;
+use super::PhpVar;
+
+// PHP stores decimal integer strings ("12", "-3", but not "012", "+3" or
+// "-0") as integer keys
+pub(super) fn int_key(s: &[u8]) -> Option<i64> {
+    let digits = match s.first() {
+        Some(b'-') => &s[1..],
+        _ => s
+    };
+    let canonical = match digits {
+        [] => false,
+        [b'0'] => digits.len() == s.len(),
+        [b'1'..=b'9', rest @ ..] => rest.iter().all(|c| c.is_ascii_digit()),
+        _ => false
+    };
+    if canonical {
+        String::from_utf8_lossy(s).parse::<i64>().ok()
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PhpKey {
+    Int(i64),
+    String(Vec<u8>),
+}
+
+impl PhpKey {
+    pub fn from_bytes(s: &[u8]) -> PhpKey {
+        match int_key(s) {
+            Some(i) => PhpKey::Int(i),
+            None => PhpKey::String(s.to_vec())
+        }
+    }
+
+    pub fn to_var(&self) -> PhpVar {
+        match self {
+            PhpKey::Int(i) => PhpVar::Int(*i),
+            PhpKey::String(s) => PhpVar::String(s.clone()),
+        }
+    }
+
+    // PHP casts bools and floats to integer keys and null to ""
+    pub fn from_var(var: &PhpVar) -> Result<PhpKey> {
+        match var {
+            PhpVar::Null => Ok(PhpKey::String(vec![])),
+            PhpVar::Bool(b) => Ok(PhpKey::Int(*b as i64)),
+            PhpVar::Int(i) => Ok(PhpKey::Int(*i)),
+            PhpVar::Float(f) if f.is_finite() => Ok(PhpKey::Int(*f as i64)),
+            PhpVar::String(s) => Ok(PhpKey::from_bytes(s)),
+            PhpVar::Ref(_, v) => PhpKey::from_var(&v.borrow()),
+            _ => bail!(ErrorKind::Invalid)
+        }
+    }
+}
+
+impl From<i64> for PhpKey {
+    fn from(i: i64) -> Self {
+        PhpKey::Int(i)
+    }
+}
+
+impl From<&[u8]> for PhpKey {
+    fn from(s: &[u8]) -> Self {
+        PhpKey::from_bytes(s)
+    }
+}
+
+impl From<Vec<u8>> for PhpKey {
+    fn from(s: Vec<u8>) -> Self {
+        PhpKey::from_bytes(&s)
+    }
+}
+
+impl From<&str> for PhpKey {
+    fn from(s: &str) -> Self {
+        PhpKey::from_bytes(s.as_bytes())
+    }
+}
+
+impl From<&PhpKey> for PhpKey {
+    fn from(k: &PhpKey) -> Self {
+        k.clone()
+    }
+}
+
+impl fmt::Display for PhpKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PhpKey::Int(i) => write!(f, "{}", i),
+            PhpKey::String(s) => {
+                for c in s {
+                    f.write_char(*c as char)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// an insertion ordered hash map with PHP array semantics
+#[derive(Clone, Debug, Default)]
+pub struct PhpArray {
+    entries: Vec<(PhpKey, PhpVar)>,
+    index: HashMap<PhpKey, usize>,
+    next: i64,
+}
+
+impl PhpArray {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get<K: Into<PhpKey>>(&self, k: K) -> Option<&PhpVar> {
+        self.index.get(&k.into()).map(|i| &self.entries[*i].1)
+    }
+
+    pub fn get_mut<K: Into<PhpKey>>(&mut self, k: K) -> Option<&mut PhpVar> {
+        match self.index.get(&k.into()) {
+            Some(i) => Some(&mut self.entries[*i].1),
+            None => None
+        }
+    }
+
+    pub fn contains_key<K: Into<PhpKey>>(&self, k: K) -> bool {
+        self.index.contains_key(&k.into())
+    }
+
+    // overwrites in place, keeping the original position of the key
+    pub fn insert<K: Into<PhpKey>>(&mut self, k: K, v: PhpVar) -> Option<PhpVar> {
+        let k = k.into();
+        if let Some(i) = self.index.get(&k) {
+            return Some(std::mem::replace(&mut self.entries[*i].1, v));
+        }
+        if let PhpKey::Int(i) = k {
+            if i >= self.next {
+                self.next = i.saturating_add(1);
+            }
+        }
+        self.index.insert(k.clone(), self.entries.len());
+        self.entries.push((k, v));
+        None
+    }
+
+    // `$a[] = v`, fails once the next index would overflow like in PHP
+    pub fn push(&mut self, v: PhpVar) -> Result<PhpKey> {
+        let k = PhpKey::Int(self.next);
+        if self.index.contains_key(&k) {
+            bail!(ErrorKind::Invalid)
+        }
+        self.insert(k.clone(), v);
+        Ok(k)
+    }
+
+    pub fn remove<K: Into<PhpKey>>(&mut self, k: K) -> Option<PhpVar> {
+        let i = self.index.remove(&k.into())?;
+        let (_, v) = self.entries.remove(i);
+        for (k, _) in &self.entries[i..] {
+            if let Some(j) = self.index.get_mut(k) {
+                *j -= 1;
+            }
+        }
+        Some(v)
+    }
+
+    pub fn next_index(&self) -> i64 {
+        self.next
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PhpKey, &PhpVar)> {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&PhpKey, &mut PhpVar)> {
+        self.entries.iter_mut().map(|(k, v)| (&*k, v))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &PhpKey> {
+        self.entries.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &PhpVar> {
+        self.entries.iter().map(|(_, v)| v)
+    }
+}
+
+// same keys and values in the same order, like PHP's `===`
+impl PartialEq for PhpArray {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl<K: Into<PhpKey>> FromIterator<(K, PhpVar)> for PhpArray {
+    fn from_iter<I: IntoIterator<Item = (K, PhpVar)>>(iter: I) -> Self {
+        let mut arr = PhpArray::new();
+        for (k, v) in iter {
+            arr.insert(k, v);
+        }
+        arr
+    }
+}
+
+impl FromIterator<PhpVar> for PhpArray {
+    fn from_iter<I: IntoIterator<Item = PhpVar>>(iter: I) -> Self {
+        let mut arr = PhpArray::new();
+        for v in iter {
+            let _ = arr.push(v);
+        }
+        arr
+    }
+}
+
+impl IntoIterator for PhpArray {
+    type Item = (PhpKey, PhpVar);
+    type IntoIter = std::vec::IntoIter<(PhpKey, PhpVar)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a PhpArray {
+    type Item = (&'a PhpKey, &'a PhpVar);
+    type IntoIter = Box<dyn Iterator<Item = (&'a PhpKey, &'a PhpVar)> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.iter())
+    }
+}
+
+#[test]
+fn test_key_coercion() {
+    assert_eq!(PhpKey::from("1"), PhpKey::Int(1));
+    assert_eq!(PhpKey::from("-12"), PhpKey::Int(-12));
+    assert_eq!(PhpKey::from("0"), PhpKey::Int(0));
+    assert_eq!(PhpKey::from("9223372036854775807"), PhpKey::Int(i64::MAX));
+    assert_eq!(PhpKey::from("9223372036854775808"), PhpKey::String(b"9223372036854775808".to_vec()));
+    for s in &["01", "-0", "+1", "1.0", " 1", "1 ", "", "-", "0x1", "abc"] {
+        assert_eq!(PhpKey::from(*s), PhpKey::String(s.as_bytes().to_vec()));
+    }
+    assert_eq!(PhpKey::from_var(&PhpVar::Bool(true)).unwrap(), PhpKey::Int(1));
+    assert_eq!(PhpKey::from_var(&PhpVar::Float(2.7)).unwrap(), PhpKey::Int(2));
+    assert_eq!(PhpKey::from_var(&PhpVar::Null).unwrap(), PhpKey::String(vec![]));
+    assert!(PhpKey::from_var(&PhpVar::Array(PhpArray::new())).is_err());
+}
+
+#[test]
+fn test_array_ops() -> Result<()> {
+    let mut arr = PhpArray::new();
+    assert_eq!(arr.push(PhpVar::Int(0))?, PhpKey::Int(0));
+    arr.insert("a", PhpVar::Int(1));
+    arr.insert("5", PhpVar::Int(2));
+    assert_eq!(arr.push(PhpVar::Int(3))?, PhpKey::Int(6));
+    assert_eq!(arr.next_index(), 7);
+
+    // last write wins and keeps the position
+    assert_eq!(arr.insert(0, PhpVar::Int(4)), Some(PhpVar::Int(0)));
+    assert_eq!(arr.get("5"), Some(&PhpVar::Int(2)));
+    assert_eq!(arr.get(5), Some(&PhpVar::Int(2)));
+    assert_eq!(arr.keys().cloned().collect::<Vec<_>>(),
+               vec![PhpKey::Int(0), PhpKey::from("a"), PhpKey::Int(5), PhpKey::Int(6)]);
+
+    assert_eq!(arr.remove("a"), Some(PhpVar::Int(1)));
+    assert_eq!(arr.remove("a"), None);
+    assert_eq!(arr.get(6), Some(&PhpVar::Int(3)));
+    assert_eq!(arr.len(), 3);
+
+    // removing does not rewind the next index
+    arr.remove(6);
+    assert_eq!(arr.push(PhpVar::Null)?, PhpKey::Int(7));
+
+    // negative keys do not move the next index
+    let mut arr = PhpArray::new();
+    arr.insert(-5, PhpVar::Null);
+    assert_eq!(arr.push(PhpVar::Null)?, PhpKey::Int(0));
+
+    let mut arr = PhpArray::new();
+    arr.insert(i64::MAX, PhpVar::Null);
+    assert!(arr.push(PhpVar::Null).is_err());
+    Ok(())
+}
@@ -0,0 +1,386 @@
+use std::fmt;
+use std::fmt::Write;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+use super::array::int_key;
+use super::{parse_nodes, Budget, ExpansionLimits, Limit, Node, Parsed, Parser, PhpArray, PhpKey, PhpVar, RefKind,
+            UnserializeError, UnserializeErrorKind, UnserializeOptions, INCOMPLETE_CLASS, INCOMPLETE_CLASS_NAME};
+
+// an array key or property name that still points into the input
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PhpKeyRef<'a> {
+    Int(i64),
+    String(&'a [u8]),
+}
+
+impl<'a> PhpKeyRef<'a> {
+    // the same integer key casts as `PhpKey::from_bytes`
+    pub fn from_bytes(s: &'a [u8]) -> Self {
+        match int_key(s) {
+            Some(i) => PhpKeyRef::Int(i),
+            None => PhpKeyRef::String(s)
+        }
+    }
+
+    pub fn into_owned(self) -> PhpKey {
+        match self {
+            PhpKeyRef::Int(i) => PhpKey::Int(i),
+            PhpKeyRef::String(s) => PhpKey::String(s.to_vec()),
+        }
+    }
+
+    // property names are always strings, whatever the payload used
+    pub fn to_bytes(self) -> Vec<u8> {
+        match self {
+            PhpKeyRef::Int(i) => i.to_string().into_bytes(),
+            PhpKeyRef::String(s) => s.to_vec(),
+        }
+    }
+}
+
+impl<'a> fmt::Display for PhpKeyRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PhpKeyRef::Int(i) => write!(f, "{}", i),
+            PhpKeyRef::String(s) => {
+                for c in s.iter() {
+                    f.write_char(*c as char)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// a `PhpVar` whose strings and class names are slices of the serialized
+// input. only the string copies are saved: the nodes are not kept in one
+// buffer, every array and object is a vector of its own and every shared
+// slot an `Rc`. it can only be read: a shared slot is one `Rc` for every
+// place that refers to it, `R:` or `r:` alike.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PhpValueRef<'a> {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(&'a [u8]),
+    // in insertion order, duplicate keys already collapsed
+    Array(Vec<(PhpKeyRef<'a>, PhpValueRef<'a>)>),
+    Object { class: &'a [u8], props: Vec<(PhpKeyRef<'a>, PhpValueRef<'a>)> },
+    Custom { class: &'a [u8], data: &'a [u8] },
+    Enum { class: &'a [u8], case: &'a [u8] },
+    Ref(RefKind, Rc<PhpValueRef<'a>>),
+}
+
+impl<'a> PhpValueRef<'a> {
+    // the value behind any number of references
+    pub fn value(&self) -> &PhpValueRef<'a> {
+        match self {
+            PhpValueRef::Ref(_, v) => v.value(),
+            _ => self
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self.value() {
+            PhpValueRef::String(s) => Some(s),
+            _ => None
+        }
+    }
+
+    // array entries, or nothing for any other value
+    pub fn entries(&self) -> &[(PhpKeyRef<'a>, PhpValueRef<'a>)] {
+        match self.value() {
+            PhpValueRef::Array(items) => items,
+            _ => &[]
+        }
+    }
+
+    pub fn get(&self, key: PhpKeyRef<'_>) -> Option<&PhpValueRef<'a>> {
+        self.entries().iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+    }
+
+    // the original class name of an incomplete class object
+    pub fn incomplete_class(&self) -> Option<&'a [u8]> {
+        match self {
+            PhpValueRef::Object { class, props } if *class == INCOMPLETE_CLASS => match props.first() {
+                Some((PhpKeyRef::String(k), PhpValueRef::String(name))) if *k == INCOMPLETE_CLASS_NAME => Some(name),
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    // copies everything out of the input. vars that shared a slot still share
+    // one cell afterwards, so this gives exactly what `unserialize_with` does.
+    pub fn into_owned(self) -> PhpVar {
+        owned(&self, &mut HashMap::new())
+    }
+}
+
+fn owned<'a>(v: &PhpValueRef<'a>, cells: &mut HashMap<*const PhpValueRef<'a>, Rc<RefCell<PhpVar>>>) -> PhpVar {
+    match v {
+        PhpValueRef::Null => PhpVar::Null,
+        PhpValueRef::Bool(b) => PhpVar::Bool(*b),
+        PhpValueRef::Int(i) => PhpVar::Int(*i),
+        PhpValueRef::Float(d) => PhpVar::Float(*d),
+        PhpValueRef::String(s) => PhpVar::String(s.to_vec()),
+        PhpValueRef::Array(items) => {
+            PhpVar::Array(items.iter().map(|(k, v)| (k.into_owned(), owned(v, cells))).collect::<PhpArray>())
+        },
+        PhpValueRef::Object { class, props } => PhpVar::Object {
+            class: class.to_vec(),
+            props: props.iter().map(|(k, v)| (k.to_bytes(), owned(v, cells))).collect(),
+        },
+        PhpValueRef::Custom { class, data } => PhpVar::Custom { class: class.to_vec(), data: data.to_vec() },
+        PhpValueRef::Enum { class, case } => PhpVar::Enum { class: class.to_vec(), case: case.to_vec() },
+        // a copy of its own, as `unserialize_with` would have built it
+        PhpValueRef::Ref(RefKind::Value, target) if !is_object(target) => owned(target, cells),
+        PhpValueRef::Ref(kind, target) => {
+            let key = Rc::as_ptr(target);
+            let cell = match cells.get(&key) {
+                Some(cell) => cell.clone(),
+                None => {
+                    let cell = Rc::new(RefCell::new(owned(target, cells)));
+                    cells.insert(key, cell.clone());
+                    cell
+                }
+            };
+            PhpVar::Ref(*kind, cell)
+        },
+    }
+}
+
+impl<'a> fmt::Display for PhpValueRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            PhpValueRef::Null => Ok(()),
+            PhpValueRef::Bool(b) => write!(f, "{}", b as u8),
+            PhpValueRef::Int(i) => write!(f, "{}", i),
+            PhpValueRef::Float(d) => write!(f, "{}", d),
+            PhpValueRef::String(s) => {
+                for c in s {
+                    f.write_char(*c as char)?;
+                }
+                Ok(())
+            },
+            PhpValueRef::Array(_) => write!(f, "Array"),
+            PhpValueRef::Object { .. } | PhpValueRef::Custom { .. } | PhpValueRef::Enum { .. } => write!(f, "Object"),
+            PhpValueRef::Ref(_, ref v) => write!(f, "{}", v),
+        }
+    }
+}
+
+// builds the arena left by the parser without copying any bytes
+pub(super) struct RefBuilder<'a, 'o> {
+    nodes: Vec<Node<'a>>,
+    targets: Vec<Option<RefKind>>,
+    copied: HashSet<usize>,
+    shared: HashMap<usize, Rc<PhpValueRef<'a>>>,
+    // `r:` copies are not made here, but they count as if they were
+    budget: Budget,
+    // reused for every array to collapse duplicate keys
+    seen: HashMap<PhpKeyRef<'a>, usize>,
+    opts: &'o UnserializeOptions,
+    end: usize,
+}
+
+impl<'a, 'o> RefBuilder<'a, 'o> {
+    pub(super) fn new(parser: &mut Parser<'a, 'o>) -> Self {
+        RefBuilder {
+            nodes: mem::take(&mut parser.nodes),
+            targets: mem::take(&mut parser.targets),
+            copied: mem::take(&mut parser.copied),
+            shared: HashMap::new(),
+            budget: Budget::new(&ExpansionLimits::from(parser.opts)),
+            seen: HashMap::new(),
+            opts: parser.opts,
+            end: parser.cur,
+        }
+    }
+
+    fn dangling<T>(&self) -> Parsed<T> {
+        Err(UnserializeError { offset: self.end, expected: "slot number", kind: UnserializeErrorKind::DanglingReference })
+    }
+
+    // later duplicates overwrite the earlier value in place
+    pub(super) fn collapse(&mut self, values: Vec<(PhpKeyRef<'a>, PhpValueRef<'a>)>) -> PhpValueRef<'a> {
+        self.seen.clear();
+        let mut arr: Vec<(PhpKeyRef<'a>, PhpValueRef<'a>)> = Vec::with_capacity(values.len());
+        for (key, v) in values {
+            match self.seen.get(&key) {
+                Some(&i) => arr[i].1 = v,
+                None => {
+                    self.seen.insert(key, arr.len());
+                    arr.push((key, v));
+                }
+            }
+        }
+        PhpValueRef::Array(arr)
+    }
+
+    pub(super) fn build(&mut self, id: usize) -> Parsed<PhpValueRef<'a>> {
+        let mut var = match mem::replace(&mut self.nodes[id], Node::Pending) {
+            Node::Value(v) => v,
+            Node::Array(items) => {
+                let mut values = Vec::with_capacity(items.len());
+                for (key, id) in items {
+                    values.push((key, self.build(id)?));
+                }
+                // children are built first, the map is free again by now
+                self.collapse(values)
+            },
+            Node::Object(class, items) => {
+                let mut props = Vec::with_capacity(items.len() + 1);
+                let class = if self.opts.allowed_classes.allows(class) {
+                    class
+                } else {
+                    props.push((PhpKeyRef::String(INCOMPLETE_CLASS_NAME), PhpValueRef::String(class)));
+                    INCOMPLETE_CLASS
+                };
+                for (name, id) in items {
+                    props.push((name, self.build(id)?));
+                }
+                PhpValueRef::Object { class, props }
+            },
+            Node::Ref(kind, target) => {
+                let v = match self.shared.get(&target) {
+                    Some(v) => v.clone(),
+                    None => return self.dangling()
+                };
+                if kind == RefKind::Value && !is_object(&v) && spend_copy(&self.budget, &v).is_err() {
+                    return Err(UnserializeError {
+                        offset: self.end,
+                        expected: "fewer copies",
+                        kind: UnserializeErrorKind::LimitExceeded(Limit::Copies),
+                    });
+                }
+                return Ok(PhpValueRef::Ref(kind, v));
+            },
+            Node::Pending => return self.dangling()
+        };
+
+        if !is_object(&var) && self.copied.contains(&id) {
+            share_objects(&mut var);
+        }
+        if let Some(kind) = self.targets[id] {
+            let v = Rc::new(var);
+            self.shared.insert(id, v.clone());
+            Ok(PhpValueRef::Ref(kind, v))
+        } else {
+            Ok(var)
+        }
+    }
+}
+
+fn is_object(v: &PhpValueRef<'_>) -> bool {
+    matches!(v, PhpValueRef::Object { .. } | PhpValueRef::Custom { .. } | PhpValueRef::Enum { .. })
+}
+
+// the objects in the arrays of a copied var get a cell of their own, like
+// `unserialize_with` does
+fn share_objects(v: &mut PhpValueRef<'_>) {
+    if let PhpValueRef::Array(items) = v {
+        for (_, v) in items.iter_mut() {
+            if is_object(v) {
+                let obj = mem::replace(v, PhpValueRef::Null);
+                *v = PhpValueRef::Ref(RefKind::Value, Rc::new(obj));
+            } else {
+                share_objects(v);
+            }
+        }
+    }
+}
+
+// `Budget::copy` for the copy `into_owned` would make of `v`
+fn spend_copy(budget: &Budget, v: &PhpValueRef<'_>) -> crate::errors::Result<()> {
+    match v {
+        PhpValueRef::Ref(RefKind::Value, target) if !is_object(target) => return spend_copy(budget, target),
+        PhpValueRef::String(s) => budget.node(s.len())?,
+        PhpValueRef::Array(items) => {
+            budget.node(0)?;
+            for (k, v) in items {
+                if let PhpKeyRef::String(s) = k {
+                    budget.bytes(s.len())?;
+                }
+                spend_copy(budget, v)?;
+            }
+        },
+        PhpValueRef::Object { class, props } => {
+            budget.node(class.len())?;
+            for (name, v) in props {
+                budget.bytes(name.to_bytes().len())?;
+                spend_copy(budget, v)?;
+            }
+        },
+        PhpValueRef::Custom { class, data } => budget.node(class.len() + data.len())?,
+        PhpValueRef::Enum { class, case } => budget.node(class.len() + case.len())?,
+        _ => budget.node(0)?,
+    }
+    Ok(())
+}
+
+// like `unserialize_with`, but without copying a single string out of `raw`
+pub fn unserialize_ref_with<'a>(raw: &'a [u8], opts: &UnserializeOptions) -> Result<PhpValueRef<'a>, UnserializeError> {
+    let (mut parser, id) = parse_nodes(raw, opts)?;
+    RefBuilder::new(&mut parser).build(id)
+}
+
+pub fn unserialize_ref(raw: &[u8]) -> Result<PhpValueRef<'_>, UnserializeError> {
+    unserialize_ref_with(raw, &UnserializeOptions::default())
+}
+
+#[cfg(test)]
+use super::{try_unserialize, unserialize_with, AllowedClasses};
+
+#[test]
+fn test_unserialize_ref() {
+    let cases: &[&[u8]] = &[
+        b"N;",
+        b"d:0.5;",
+        b"a:3:{i:0;s:1:\"a\";s:2:\"12\";b:1;s:1:\"x\";a:1:{i:-1;N;}}",
+        b"a:2:{s:1:\"a\";i:1;s:1:\"a\";i:2;}",
+        b"O:3:\"Foo\":2:{s:1:\"a\";i:1;i:7;s:4:\"\0*\0b\";}",
+        b"C:3:\"Foo\":3:{abc}",
+        b"E:7:\"Foo:Bar\";",
+        b"a:3:{i:0;s:1:\"a\";i:1;r:2;i:2;R:2;}",
+        b"a:2:{i:0;a:1:{i:0;i:1;}i:1;R:2;}",
+        b"a:2:{i:0;a:1:{i:0;O:8:\"stdClass\":0:{}}i:1;r:2;}",
+        b"a:3:{i:0;a:0:{}i:1;R:2;i:2;r:2;}",
+    ];
+    for raw in cases {
+        assert_eq!(unserialize_ref(raw).unwrap().into_owned(), try_unserialize(raw).unwrap(), "{:?}", raw);
+    }
+
+    let opts = UnserializeOptions { allowed_classes: AllowedClasses::Only(vec![]), ..Default::default() };
+    for raw in &[&b"O:3:\"Foo\":1:{s:1:\"a\";i:1;}"[..], b"C:3:\"Foo\":3:{abc}"] {
+        let v = unserialize_ref_with(raw, &opts).unwrap();
+        assert_eq!(v.incomplete_class(), Some(&b"Foo"[..]));
+        assert_eq!(v.into_owned(), unserialize_with(raw, &opts).unwrap());
+    }
+
+    assert_eq!(unserialize_ref(b"a:1:{i:0;R:3;}").unwrap_err(), try_unserialize(b"a:1:{i:0;R:3;}").unwrap_err());
+}
+
+#[test]
+fn test_unserialize_ref_borrows() {
+    let raw = b"a:2:{s:5:\"label\";s:6:\"secret\";i:1;R:2;}";
+    let v = unserialize_ref(raw).unwrap();
+    let range = raw.as_ptr_range();
+    let secret = v.get(PhpKeyRef::from_bytes(b"label")).unwrap();
+    assert_eq!(secret.as_bytes(), Some(&b"secret"[..]));
+    assert!(range.contains(&secret.as_bytes().unwrap().as_ptr()));
+    assert_eq!(v.get(PhpKeyRef::Int(1)).unwrap().to_string(), "secret");
+
+    // both halves of the reference set still share one cell
+    match v.into_owned() {
+        PhpVar::Array(arr) => match (arr.get("label"), arr.get(1)) {
+            (Some(PhpVar::Ref(_, a)), Some(PhpVar::Ref(_, b))) => assert!(Rc::ptr_eq(a, b)),
+            _ => panic!("references were lost")
+        },
+        _ => panic!("not an array")
+    }
+}
@@ -0,0 +1,416 @@
+use std::fmt::Display;
+use std::rc::Rc;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+
+use crate::errors::*;
+use super::{parse, Budget, ExpansionLimits, PhpKey, PhpVar, UnserializeOptions, Visibility};
+
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        ErrorKind::Msg(msg.to_string()).into()
+    }
+}
+
+// reads any `Deserialize` value out of a `PhpVar` tree. arrays are read as
+// sequences when they are lists and as maps otherwise, objects as maps of
+// their unmangled property names. shared slots are read once per holder,
+// within `ExpansionLimits`.
+pub struct Deserializer {
+    var: PhpVar,
+    budget: Rc<Budget>,
+}
+
+impl Deserializer {
+    pub fn new(var: PhpVar) -> Self {
+        Deserializer::with_limits(var, &ExpansionLimits::default())
+    }
+
+    pub fn with_limits(var: PhpVar, limits: &ExpansionLimits) -> Self {
+        Deserializer { var: resolve(var), budget: Rc::new(Budget::new(limits)) }
+    }
+
+    // a var inside this one, paid for out of the same budget
+    fn child(budget: &Rc<Budget>, var: PhpVar) -> Result<Self> {
+        let var = resolve(var);
+        budget.var(&var)?;
+        Ok(Deserializer { var, budget: budget.clone() })
+    }
+}
+
+pub fn from_var<T: DeserializeOwned>(var: PhpVar) -> Result<T> {
+    T::deserialize(Deserializer::new(var))
+}
+
+pub fn from_slice<T: DeserializeOwned>(raw: &[u8]) -> Result<T> {
+    from_var(*parse(raw, &UnserializeOptions::default())?)
+}
+
+// `r:`/`R:` slots are read as the value they hold
+fn resolve(var: PhpVar) -> PhpVar {
+    match var {
+        PhpVar::Ref(_, v) => resolve(v.borrow().clone()),
+        v => v
+    }
+}
+
+fn entries(var: PhpVar) -> Option<Vec<(PhpKey, PhpVar)>> {
+    match var {
+        PhpVar::Array(arr) => Some(arr.into_iter().collect()),
+        PhpVar::Object { props, .. } => Some(props.into_iter().map(|(k, v)| {
+            (PhpKey::String(Visibility::demangle(&k).1.to_vec()), v)
+        }).collect()),
+        _ => None
+    }
+}
+
+fn is_list(var: &PhpVar) -> bool {
+    match var {
+        PhpVar::Array(arr) => arr.keys().enumerate().all(|(i, k)| *k == PhpKey::Int(i as i64)),
+        _ => false
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if is_list(&self.var) {
+            return self.deserialize_seq(visitor);
+        }
+        match self.var {
+            PhpVar::Null => visitor.visit_unit(),
+            PhpVar::Bool(b) => visitor.visit_bool(b),
+            PhpVar::Int(i) => visitor.visit_i64(i),
+            PhpVar::Float(f) => visitor.visit_f64(f),
+            PhpVar::String(s) => match String::from_utf8(s) {
+                Ok(s) => visitor.visit_string(s),
+                Err(e) => visitor.visit_byte_buf(e.into_bytes()),
+            },
+            PhpVar::Enum { case, .. } => visitor.visit_string(String::from_utf8(case)?),
+            PhpVar::Custom { class, .. } => {
+                bail!("cannot deserialize custom serialized {}", String::from_utf8_lossy(&class))
+            },
+            var @ PhpVar::Array(_) | var @ PhpVar::Object { .. } => {
+                visitor.visit_map(MapDeserializer::new(entries(var).unwrap_or_default(), self.budget))
+            },
+            PhpVar::Ref(_, v) => Deserializer::child(&self.budget, v.borrow().clone())?.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.var {
+            PhpVar::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.var {
+            PhpVar::String(s) => visitor.visit_byte_buf(s),
+            _ => self.deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_bytes(visitor)
+    }
+
+    // any array is accepted as a sequence of its values
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.var {
+            PhpVar::Array(arr) => visitor.visit_seq(SeqDeserializer {
+                iter: arr.into_iter().map(|(_, v)| v).collect::<Vec<_>>().into_iter(),
+                budget: self.budget,
+            }),
+            _ => self.deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize,
+                                                 visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match entries(self.var) {
+            Some(entries) => visitor.visit_map(MapDeserializer::new(entries, self.budget)),
+            None => bail!("expected an array or object")
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, _fields: &'static [&'static str],
+                                           visitor: V) -> Result<V::Value> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str],
+                                         visitor: V) -> Result<V::Value> {
+        match self.var {
+            PhpVar::String(s) | PhpVar::Enum { case: s, .. } => {
+                visitor.visit_enum(EnumDeserializer { variant: String::from_utf8(s)?, value: None, budget: self.budget })
+            },
+            PhpVar::Array(arr) if arr.len() == 1 => {
+                let (k, v) = arr.into_iter().next().unwrap();
+                visitor.visit_enum(EnumDeserializer { variant: k.to_string(), value: Some(v), budget: self.budget })
+            },
+            _ => bail!("expected an enum variant")
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        unit unit_struct identifier ignored_any
+    }
+}
+
+struct SeqDeserializer {
+    iter: std::vec::IntoIter<PhpVar>,
+    budget: Rc<Budget>,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqDeserializer {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        match self.iter.next() {
+            Some(v) => seed.deserialize(Deserializer::child(&self.budget, v)?).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct MapDeserializer {
+    iter: std::vec::IntoIter<(PhpKey, PhpVar)>,
+    value: Option<PhpVar>,
+    budget: Rc<Budget>,
+}
+
+impl MapDeserializer {
+    fn new(entries: Vec<(PhpKey, PhpVar)>, budget: Rc<Budget>) -> Self {
+        MapDeserializer { iter: entries.into_iter(), value: None, budget }
+    }
+}
+
+impl<'de> de::MapAccess<'de> for MapDeserializer {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        match self.iter.next() {
+            Some((k, v)) => {
+                if let PhpKey::String(s) = &k {
+                    self.budget.bytes(s.len())?;
+                }
+                self.value = Some(v);
+                seed.deserialize(KeyDeserializer { key: k }).map(Some)
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        match self.value.take() {
+            Some(v) => seed.deserialize(Deserializer::child(&self.budget, v)?),
+            None => bail!(ErrorKind::Invalid)
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+// int keys can be read as strings too, since PHP turns "1" into 1
+struct KeyDeserializer {
+    key: PhpKey,
+}
+
+impl<'de> de::Deserializer<'de> for KeyDeserializer {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.key {
+            PhpKey::Int(i) => visitor.visit_i64(i),
+            PhpKey::String(s) => match String::from_utf8(s) {
+                Ok(s) => visitor.visit_string(s),
+                Err(e) => visitor.visit_byte_buf(e.into_bytes()),
+            },
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.key {
+            PhpKey::Int(i) => visitor.visit_string(i.to_string()),
+            _ => self.deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf
+        option unit unit_struct seq tuple tuple_struct map struct enum ignored_any
+    }
+}
+
+struct EnumDeserializer {
+    variant: String,
+    value: Option<PhpVar>,
+    budget: Rc<Budget>,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumDeserializer {
+    type Error = Error;
+    type Variant = VariantDeserializer;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, VariantDeserializer)> {
+        let variant = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(self.variant))?;
+        Ok((variant, VariantDeserializer { value: self.value, budget: self.budget }))
+    }
+}
+
+struct VariantDeserializer {
+    value: Option<PhpVar>,
+    budget: Rc<Budget>,
+}
+
+impl VariantDeserializer {
+    fn value(self) -> Result<Deserializer> {
+        Deserializer::child(&self.budget, self.value.unwrap_or(PhpVar::Null))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for VariantDeserializer {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        match self.value {
+            None | Some(PhpVar::Null) => Ok(()),
+            Some(_) => bail!("expected a unit variant")
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self.value()?)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_seq(self.value()?, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_map(self.value()?, visitor)
+    }
+}
+
+#[cfg(test)]
+use std::collections::BTreeMap;
+#[cfg(test)]
+use serde::{Serialize, Deserialize};
+#[cfg(test)]
+use super::{to_vec, to_vec_with, StructFormat};
+
+#[cfg(test)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+enum Kind {
+    Totp,
+    Hotp(u64),
+    Custom { digits: u8, period: u32 },
+}
+
+#[cfg(test)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Account {
+    label: String,
+    secret: Vec<u8>,
+    digits: u8,
+    issuer: Option<String>,
+    kind: Kind,
+    tags: Vec<String>,
+}
+
+#[cfg(test)]
+fn account() -> Account {
+    Account {
+        label: "demo".to_string(),
+        secret: vec![1, 2],
+        digits: 6,
+        issuer: None,
+        kind: Kind::Totp,
+        tags: vec!["a".to_string()],
+    }
+}
+
+#[test]
+fn test_struct_as_object() -> Result<()> {
+    let raw = to_vec(&account())?;
+    assert_eq!(raw, b"O:7:\"Account\":6:{s:5:\"label\";s:4:\"demo\";s:6:\"secret\";a:2:{i:0;i:1;i:1;i:2;}\
+s:6:\"digits\";i:6;s:6:\"issuer\";N;s:4:\"kind\";s:4:\"Totp\";s:4:\"tags\";a:1:{i:0;s:1:\"a\";}}".to_vec());
+    assert_eq!(from_slice::<Account>(&raw)?, account());
+    Ok(())
+}
+
+#[test]
+fn test_struct_as_array() -> Result<()> {
+    let mut acc = account();
+    acc.issuer = Some("babi".to_string());
+    acc.kind = Kind::Custom { digits: 8, period: 60 };
+    let raw = to_vec_with(&acc, StructFormat::Array)?;
+    assert!(raw.starts_with(b"a:6:{s:5:\"label\";"));
+    assert_eq!(from_slice::<Account>(&raw)?, acc);
+
+    acc.kind = Kind::Hotp(3);
+    assert_eq!(from_slice::<Account>(&to_vec(&acc)?)?, acc);
+    Ok(())
+}
+
+#[test]
+fn test_deserialize_php_arrays() -> Result<()> {
+    // int keys can be read back as string keys
+    let map: BTreeMap<String, i64> = from_slice(b"a:2:{i:0;i:1;s:1:\"a\";i:2;}")?;
+    assert_eq!(map.get("0"), Some(&1));
+    assert_eq!(map.get("a"), Some(&2));
+
+    let mut map = BTreeMap::new();
+    map.insert("2".to_string(), true);
+    assert_eq!(to_vec(&map)?, b"a:1:{i:2;b:1;}".to_vec());
+
+    // protected and private properties are read by their plain name
+    #[derive(Deserialize)]
+    struct User {
+        name: String,
+        pass: String,
+    }
+    let user: User = from_slice(b"O:4:\"User\":2:{s:7:\"\0*\0name\";s:3:\"bob\";s:10:\"\0User\0pass\";s:2:\"pw\";}")?;
+    assert_eq!((user.name.as_str(), user.pass.as_str()), ("bob", "pw"));
+
+    let refs: Vec<Vec<i64>> = from_slice(b"a:2:{i:0;a:1:{i:0;i:7;}i:1;r:2;}")?;
+    assert_eq!(refs, vec![vec![7], vec![7]]);
+
+    assert!(from_slice::<u64>(b"i:-1;").is_err());
+    assert!(from_slice::<String>(b"i:1").is_err());
+    assert!(to_vec(&u64::MAX).is_err());
+    Ok(())
+}
@@ -0,0 +1,515 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::errors::*;
+use super::{format_float, format_float_precision, Budget, ExpansionLimits, PhpKey, PhpVar, RefKind, Visibility};
+
+// byte-exact ports of PHP 8's var_dump, print_r and var_export. they only
+// differ from PHP where the tree does not carry the information: `C:`
+// payloads show up without properties and enums as pure (unbacked) enums.
+// shared slots are printed once per holder, like PHP does, so the output is
+// bounded by `ExpansionLimits` instead of by the size of the tree.
+struct Dumper {
+    out: Vec<u8>,
+    budget: Budget,
+    // object handles (`#N`), handed out in dump order like a fresh
+    // unserialize would
+    handles: HashMap<*const PhpVar, usize>,
+    // arrays and objects currently being printed, for *RECURSION*
+    active: Vec<*const PhpVar>,
+}
+
+impl Dumper {
+    fn new(limits: &ExpansionLimits) -> Self {
+        Dumper { out: vec![], budget: Budget::new(limits), handles: HashMap::new(), active: vec![] }
+    }
+
+    fn spaces(&mut self, n: usize) {
+        self.out.resize(self.out.len() + n, b' ');
+    }
+
+    fn handle(&mut self, var: &PhpVar) -> usize {
+        let next = self.handles.len() + 1;
+        *self.handles.entry(var as *const PhpVar).or_insert(next)
+    }
+
+    // true if `var` is already being printed further up
+    fn enter(&mut self, var: &PhpVar) -> bool {
+        let ptr = var as *const PhpVar;
+        if self.active.contains(&ptr) {
+            return false;
+        }
+        self.active.push(ptr);
+        true
+    }
+
+    fn leave(&mut self) {
+        self.active.pop();
+    }
+
+    fn dump(&mut self, var: &PhpVar, level: usize) -> Result<()> {
+        if level > 1 {
+            self.spaces(level - 1);
+        }
+        self.dump_value(var, level, "")
+    }
+
+    fn dump_value(&mut self, var: &PhpVar, level: usize, common: &str) -> Result<()> {
+        self.budget.var(var)?;
+        match var {
+            PhpVar::Ref(kind, v) => {
+                // only references that something else still points at
+                // are marked
+                let common = if *kind == RefKind::Shared && Rc::strong_count(v) > 1 { "&" } else { "" };
+                self.dump_value(&v.borrow(), level, common)?;
+            },
+            PhpVar::Null => self.out.extend_from_slice(format!("{}NULL\n", common).as_bytes()),
+            PhpVar::Bool(b) => self.out.extend_from_slice(format!("{}bool({})\n", common, b).as_bytes()),
+            PhpVar::Int(i) => self.out.extend_from_slice(format!("{}int({})\n", common, i).as_bytes()),
+            PhpVar::Float(f) => {
+                self.out.extend_from_slice(format!("{}float({})\n", common, format_float(*f)).as_bytes())
+            },
+            PhpVar::String(s) => {
+                self.out.extend_from_slice(format!("{}string({}) \"", common, s.len()).as_bytes());
+                self.out.extend_from_slice(s);
+                self.out.extend_from_slice(b"\"\n");
+            },
+            PhpVar::Array(arr) => {
+                if !self.enter(var) {
+                    self.out.extend_from_slice(b"*RECURSION*\n");
+                    return Ok(());
+                }
+                self.out.extend_from_slice(format!("{}array({}) {{\n", common, arr.len()).as_bytes());
+                for (k, v) in arr {
+                    self.spaces(level + 1);
+                    match k {
+                        PhpKey::Int(i) => self.out.extend_from_slice(format!("[{}]=>\n", i).as_bytes()),
+                        PhpKey::String(s) => {
+                            self.budget.bytes(s.len())?;
+                            self.out.extend_from_slice(b"[\"");
+                            self.out.extend_from_slice(s);
+                            self.out.extend_from_slice(b"\"]=>\n");
+                        }
+                    }
+                    self.dump(v, level + 2)?;
+                }
+                self.leave();
+                self.close(level);
+            },
+            PhpVar::Object { class, props } => {
+                if !self.enter(var) {
+                    self.out.extend_from_slice(b"*RECURSION*\n");
+                    return Ok(());
+                }
+                let handle = self.handle(var);
+                self.out.extend_from_slice(format!("{}object(", common).as_bytes());
+                self.out.extend_from_slice(class);
+                self.out.extend_from_slice(format!(")#{} ({}) {{\n", handle, props.len()).as_bytes());
+                for (k, v) in props {
+                    self.spaces(level + 1);
+                    self.out.extend_from_slice(b"[\"");
+                    self.budget.bytes(k.len())?;
+                    let (vis, name) = Visibility::demangle(k);
+                    self.out.extend_from_slice(name);
+                    match vis {
+                        Visibility::Public => self.out.extend_from_slice(b"\"]=>\n"),
+                        Visibility::Protected => self.out.extend_from_slice(b"\":protected]=>\n"),
+                        Visibility::Private(class) => {
+                            self.out.extend_from_slice(b"\":\"");
+                            self.out.extend_from_slice(&class);
+                            self.out.extend_from_slice(b"\":private]=>\n");
+                        }
+                    }
+                    self.dump(v, level + 2)?;
+                }
+                self.leave();
+                self.close(level);
+            },
+            PhpVar::Custom { class, .. } => {
+                let handle = self.handle(var);
+                self.out.extend_from_slice(format!("{}object(", common).as_bytes());
+                self.out.extend_from_slice(class);
+                self.out.extend_from_slice(format!(")#{} (0) {{\n", handle).as_bytes());
+                self.close(level);
+            },
+            PhpVar::Enum { class, case } => {
+                self.out.extend_from_slice(format!("{}enum(", common).as_bytes());
+                self.out.extend_from_slice(class);
+                self.out.extend_from_slice(b"::");
+                self.out.extend_from_slice(case);
+                self.out.extend_from_slice(b")\n");
+            },
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, level: usize) {
+        if level > 1 {
+            self.spaces(level - 1);
+        }
+        self.out.extend_from_slice(b"}\n");
+    }
+
+    fn print_r(&mut self, var: &PhpVar, indent: usize) -> Result<()> {
+        self.budget.var(var)?;
+        match var {
+            PhpVar::Ref(_, v) => self.print_r(&v.borrow(), indent)?,
+            PhpVar::Null | PhpVar::Bool(false) => (),
+            PhpVar::Bool(true) => self.out.push(b'1'),
+            PhpVar::Int(i) => self.out.extend_from_slice(i.to_string().as_bytes()),
+            PhpVar::Float(f) => self.out.extend_from_slice(format_float_precision(*f, 14).as_bytes()),
+            PhpVar::String(s) => self.out.extend_from_slice(s),
+            PhpVar::Array(arr) => {
+                self.out.extend_from_slice(b"Array\n");
+                if !self.enter(var) {
+                    self.out.extend_from_slice(b" *RECURSION*");
+                    return Ok(());
+                }
+                let entries = arr.iter().map(|(k, v)| (match k {
+                    PhpKey::Int(i) => i.to_string().into_bytes(),
+                    PhpKey::String(s) => s.clone(),
+                }, v)).collect();
+                self.print_hash(entries, indent)?;
+                self.leave();
+            },
+            PhpVar::Object { class, props } => {
+                self.out.extend_from_slice(class);
+                self.out.extend_from_slice(b" Object\n");
+                if !self.enter(var) {
+                    self.out.extend_from_slice(b" *RECURSION*");
+                    return Ok(());
+                }
+                let entries = props.iter().map(|(k, v)| {
+                    let (vis, name) = Visibility::demangle(k);
+                    let mut name = name.to_vec();
+                    match vis {
+                        Visibility::Public => (),
+                        Visibility::Protected => name.extend_from_slice(b":protected"),
+                        Visibility::Private(class) => {
+                            name.push(b':');
+                            name.extend_from_slice(&class);
+                            name.extend_from_slice(b":private");
+                        }
+                    }
+                    (name, v)
+                }).collect();
+                self.print_hash(entries, indent)?;
+                self.leave();
+            },
+            PhpVar::Custom { class, .. } => {
+                self.out.extend_from_slice(class);
+                self.out.extend_from_slice(b" Object\n");
+                self.print_hash(vec![], indent)?;
+            },
+            PhpVar::Enum { class, case } => {
+                self.out.extend_from_slice(class);
+                self.out.extend_from_slice(b" Enum\n");
+                let name = PhpVar::String(case.clone());
+                self.print_hash(vec![(b"name".to_vec(), &name)], indent)?;
+            },
+        }
+        Ok(())
+    }
+
+    fn print_hash(&mut self, entries: Vec<(Vec<u8>, &PhpVar)>, indent: usize) -> Result<()> {
+        self.spaces(indent);
+        self.out.extend_from_slice(b"(\n");
+        for (k, v) in entries {
+            self.budget.bytes(k.len())?;
+            self.spaces(indent + 4);
+            self.out.push(b'[');
+            self.out.extend_from_slice(&k);
+            self.out.extend_from_slice(b"] => ");
+            self.print_r(v, indent + 8)?;
+            self.out.push(b'\n');
+        }
+        self.spaces(indent);
+        self.out.extend_from_slice(b")\n");
+        Ok(())
+    }
+
+    // `'` and `\` are escaped and NUL bytes are spliced in as `"\0"`
+    fn quote(&mut self, s: &[u8]) {
+        self.out.push(b'\'');
+        for c in s {
+            match c {
+                b'\'' | b'\\' => {
+                    self.out.push(b'\\');
+                    self.out.push(*c);
+                },
+                0 => self.out.extend_from_slice(b"' . \"\\0\" . '"),
+                _ => self.out.push(*c),
+            }
+        }
+        self.out.push(b'\'');
+    }
+
+    // arrays and objects nested in another one start on a line of their own
+    fn export_break(&mut self, level: usize) {
+        if level > 1 {
+            self.out.push(b'\n');
+            self.spaces(level - 1);
+        }
+    }
+
+    fn export(&mut self, var: &PhpVar, level: usize) -> Result<()> {
+        self.budget.var(var)?;
+        match var {
+            PhpVar::Ref(_, v) => self.export(&v.borrow(), level)?,
+            PhpVar::Null => self.out.extend_from_slice(b"NULL"),
+            PhpVar::Bool(b) => self.out.extend_from_slice(if *b { b"true" } else { b"false" }),
+            // the literal -9223372036854775808 would be a float
+            PhpVar::Int(i) if *i == i64::MIN => {
+                self.out.extend_from_slice(format!("{}-1", i64::MIN + 1).as_bytes())
+            },
+            PhpVar::Int(i) => self.out.extend_from_slice(i.to_string().as_bytes()),
+            PhpVar::Float(f) => {
+                let mut t = format_float(*f);
+                if f.is_finite() && !t.contains(['.', 'E']) {
+                    t.push_str(".0");
+                }
+                self.out.extend_from_slice(t.as_bytes());
+            },
+            PhpVar::String(s) => self.quote(s),
+            PhpVar::Array(arr) => {
+                if !self.enter(var) {
+                    self.out.extend_from_slice(b"NULL");
+                    return Ok(());
+                }
+                self.export_break(level);
+                self.out.extend_from_slice(b"array (\n");
+                for (k, v) in arr {
+                    self.spaces(level + 1);
+                    match k {
+                        PhpKey::Int(i) => self.out.extend_from_slice(i.to_string().as_bytes()),
+                        PhpKey::String(s) => {
+                            self.budget.bytes(s.len())?;
+                            self.quote(s)
+                        },
+                    }
+                    self.out.extend_from_slice(b" => ");
+                    self.export(v, level + 2)?;
+                    self.out.extend_from_slice(b",\n");
+                }
+                self.leave();
+                if level > 1 {
+                    self.spaces(level - 1);
+                }
+                self.out.push(b')');
+            },
+            PhpVar::Object { class, props } => {
+                if !self.enter(var) {
+                    self.out.extend_from_slice(b"NULL");
+                    return Ok(());
+                }
+                self.export_object(class, props, level)?;
+                self.leave();
+            },
+            PhpVar::Custom { class, .. } => self.export_object(class, &[], level)?,
+            PhpVar::Enum { class, case } => {
+                self.export_break(level);
+                self.out.push(b'\\');
+                self.out.extend_from_slice(class);
+                self.out.extend_from_slice(b"::");
+                self.out.extend_from_slice(case);
+            },
+        }
+        Ok(())
+    }
+
+    fn export_object(&mut self, class: &[u8], props: &[(Vec<u8>, PhpVar)], level: usize) -> Result<()> {
+        // stdClass has no __set_state and is cast from an array instead
+        let std = class.eq_ignore_ascii_case(b"stdClass");
+        self.export_break(level);
+        if std {
+            self.out.extend_from_slice(b"(object) array(\n");
+        } else {
+            self.out.push(b'\\');
+            self.out.extend_from_slice(class);
+            self.out.extend_from_slice(b"::__set_state(array(\n");
+        }
+        for (k, v) in props {
+            self.budget.bytes(k.len())?;
+            self.spaces(level + 2);
+            self.quote(Visibility::demangle(k).1);
+            self.out.extend_from_slice(b" => ");
+            self.export(v, level + 2)?;
+            self.out.extend_from_slice(b",\n");
+        }
+        if level > 1 {
+            self.spaces(level - 1);
+        }
+        self.out.extend_from_slice(if std { b")" } else { b"))" });
+        Ok(())
+    }
+}
+
+pub fn var_dump_with(var: &PhpVar, limits: &ExpansionLimits) -> Result<Vec<u8>> {
+    let mut d = Dumper::new(limits);
+    d.dump(var, 1)?;
+    Ok(d.out)
+}
+
+pub fn var_dump(var: &PhpVar) -> Result<Vec<u8>> {
+    var_dump_with(var, &ExpansionLimits::default())
+}
+
+pub fn print_r_with(var: &PhpVar, limits: &ExpansionLimits) -> Result<Vec<u8>> {
+    let mut d = Dumper::new(limits);
+    d.print_r(var, 0)?;
+    Ok(d.out)
+}
+
+pub fn print_r(var: &PhpVar) -> Result<Vec<u8>> {
+    print_r_with(var, &ExpansionLimits::default())
+}
+
+pub fn var_export_with(var: &PhpVar, limits: &ExpansionLimits) -> Result<Vec<u8>> {
+    let mut d = Dumper::new(limits);
+    d.export(var, 1)?;
+    Ok(d.out)
+}
+
+pub fn var_export(var: &PhpVar) -> Result<Vec<u8>> {
+    var_export_with(var, &ExpansionLimits::default())
+}
+
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use super::{unserialize, PhpArray};
+
+#[cfg(test)]
+fn dump_all(raw: &[u8]) -> (String, String, String) {
+    let var = unserialize(raw);
+    (String::from_utf8_lossy(&var_dump(&var).unwrap()).into_owned(),
+     String::from_utf8_lossy(&print_r(&var).unwrap()).into_owned(),
+     String::from_utf8_lossy(&var_export(&var).unwrap()).into_owned())
+}
+
+#[test]
+fn test_dump_array() {
+    let (dump, r, export) = dump_all(b"a:4:{i:0;i:1;s:1:\"a\";a:1:{i:0;b:1;}s:1:\"f\";d:0.1;s:1:\"n\";N;}");
+    assert_eq!(dump, "array(4) {
+  [0]=>
+  int(1)
+  [\"a\"]=>
+  array(1) {
+    [0]=>
+    bool(true)
+  }
+  [\"f\"]=>
+  float(0.1)
+  [\"n\"]=>
+  NULL
+}
+");
+    assert_eq!(r, "Array
+(
+    [0] => 1
+    [a] => Array
+        (
+            [0] => 1
+        )
+
+    [f] => 0.1
+    [n] => 
+)
+");
+    assert_eq!(export, "array (
+  0 => 1,
+  'a' => 
+  array (
+    0 => true,
+  ),
+  'f' => 0.1,
+  'n' => NULL,
+)");
+}
+
+#[test]
+fn test_dump_object() {
+    let (dump, r, export) = dump_all(
+        b"O:3:\"Foo\":3:{s:1:\"a\";i:1;s:4:\"\0*\0b\";s:1:\"x\";s:6:\"\0Foo\0c\";O:8:\"stdClass\":0:{}}");
+    assert_eq!(dump, "object(Foo)#1 (3) {
+  [\"a\"]=>
+  int(1)
+  [\"b\":protected]=>
+  string(1) \"x\"
+  [\"c\":\"Foo\":private]=>
+  object(stdClass)#2 (0) {
+  }
+}
+");
+    assert_eq!(r, "Foo Object
+(
+    [a] => 1
+    [b:protected] => x
+    [c:Foo:private] => stdClass Object
+        (
+        )
+
+)
+");
+    assert_eq!(export, "\\Foo::__set_state(array(
+   'a' => 1,
+   'b' => 'x',
+   'c' => 
+  (object) array(
+  ),
+))");
+
+    // r: to an object is the same object, so the same handle
+    let (dump, _, _) = dump_all(b"a:2:{i:0;O:8:\"stdClass\":0:{}i:1;r:2;}");
+    assert_eq!(dump, "array(2) {
+  [0]=>
+  object(stdClass)#1 (0) {
+  }
+  [1]=>
+  object(stdClass)#1 (0) {
+  }
+}
+");
+
+    let (dump, r, export) = dump_all(b"a:1:{s:1:\"a\";E:11:\"Suit:Hearts\";}");
+    assert_eq!(dump, "array(1) {\n  [\"a\"]=>\n  enum(Suit::Hearts)\n}\n");
+    assert_eq!(r, "Array\n(\n    [a] => Suit Enum\n        (\n            [name] => Hearts\n        )\n\n)\n");
+    assert_eq!(export, "array (\n  'a' => \n  \\Suit::Hearts,\n)");
+}
+
+#[test]
+fn test_dump_scalars() {
+    assert_eq!(dump_all(b"a:2:{i:0;i:1;i:1;R:2;}").0, "array(2) {\n  [0]=>\n  &int(1)\n  [1]=>\n  &int(1)\n}\n");
+    assert_eq!(dump_all(b"s:5:\"a'\\\0b\";").2, "'a\\'\\\\' . \"\\0\" . 'b'");
+    assert_eq!(dump_all(b"i:-9223372036854775808;").2, "-9223372036854775807-1");
+
+    // var_dump and var_export use serialize_precision, print_r precision
+    let cases: &[(&[u8], &str, &str, &str)] = &[
+        (b"d:1;", "float(1)\n", "1", "1.0"),
+        (b"d:-0;", "float(-0)\n", "-0", "-0.0"),
+        (b"d:0.30000000000000004;", "float(0.30000000000000004)\n", "0.3", "0.30000000000000004"),
+        (b"d:1.0E+15;", "float(1000000000000000)\n", "1.0E+15", "1000000000000000.0"),
+        (b"d:123456789012345.67;", "float(123456789012345.67)\n", "1.2345678901235E+14", "123456789012345.67"),
+        (b"d:1.0E+100;", "float(1.0E+100)\n", "1.0E+100", "1.0E+100"),
+        (b"d:-INF;", "float(-INF)\n", "-INF", "-INF"),
+        (b"d:NAN;", "float(NAN)\n", "NAN", "NAN"),
+    ];
+    for (raw, dump, r, export) in cases {
+        assert_eq!(dump_all(raw), (dump.to_string(), r.to_string(), export.to_string()));
+    }
+}
+
+#[test]
+fn test_dump_recursion() {
+    let cell = Rc::new(RefCell::new(PhpVar::Null));
+    let mut arr = PhpArray::new();
+    arr.insert(0, PhpVar::Ref(RefKind::Shared, cell.clone()));
+    *cell.borrow_mut() = PhpVar::Array(arr);
+    let var = PhpVar::Ref(RefKind::Shared, cell.clone());
+
+    assert_eq!(var_dump(&var).unwrap(), b"&array(1) {\n  [0]=>\n  *RECURSION*\n}\n".to_vec());
+    assert_eq!(print_r(&var).unwrap(), b"Array\n(\n    [0] => Array\n *RECURSION*\n)\n".to_vec());
+    assert_eq!(var_export(&var).unwrap(), b"array (\n  0 => NULL,\n)".to_vec());
+    // break the cycle so the cell is freed
+    *cell.borrow_mut() = PhpVar::Null;
+}
@@ -0,0 +1,486 @@
+use std::collections::HashMap;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::errors::*;
+use super::{valid_class_name, Budget, ExpansionLimits, Limit, Node, Parsed, Parser, PhpKey, PhpKeyRef, PhpValueRef, PhpVar, RefKind,
+            UnserializeError, UnserializeErrorKind, UnserializeOptions};
+
+// every payload starts with the format version as a big endian u32.
+// version 1 only differed in how it hashed strings, so it reads the same.
+const VERSION: u32 = 2;
+
+// type tags. the 8/16/32 variants of a type follow each other and differ
+// only in the width of the length, count or id after them.
+const NULL: u8 = 0x00;
+const REF8: u8 = 0x01;
+const REF32: u8 = 0x03;
+const BOOL_FALSE: u8 = 0x04;
+const BOOL_TRUE: u8 = 0x05;
+// 8p, 8n, 16p, 16n, 32p, 32n: the magnitude and its sign
+const LONG8P: u8 = 0x06;
+const LONG32N: u8 = 0x0b;
+const DOUBLE: u8 = 0x0c;
+const STRING_EMPTY: u8 = 0x0d;
+const STRING_ID8: u8 = 0x0e;
+const STRING_ID32: u8 = 0x10;
+const STRING8: u8 = 0x11;
+const STRING32: u8 = 0x13;
+const ARRAY8: u8 = 0x14;
+const ARRAY32: u8 = 0x16;
+const OBJECT8: u8 = 0x17;
+const OBJECT_ID8: u8 = 0x1a;
+const OBJECT_ID32: u8 = 0x1c;
+const OBJECT_SER8: u8 = 0x1d;
+const OBJECT_SER32: u8 = 0x1f;
+const LONG64P: u8 = 0x20;
+const LONG64N: u8 = 0x21;
+const OBJREF8: u8 = 0x22;
+const OBJREF32: u8 = 0x24;
+// a PHP reference (`&`) to the value that follows
+const REF: u8 = 0x25;
+
+// igbinary numbers two kinds of things for back-references: strings
+// (values, keys, property and class names alike) get an id the first time
+// they are seen, and so do arrays, objects and `&` values
+struct Reader<'a, 'o> {
+    p: Parser<'a, 'o>,
+    strings: Vec<&'a [u8]>,
+    refs: Vec<usize>,
+}
+
+impl<'a, 'o> Reader<'a, 'o> {
+    // big endian, 1 << (tag - base) bytes wide
+    fn read_sized(&mut self, tag: u8, base: u8) -> Parsed<usize> {
+        let mut v: u64 = 0;
+        for c in self.p.read_bytes(1 << (tag - base))? {
+            v = v << 8 | *c as u64;
+        }
+        Ok(v as usize)
+    }
+
+    fn read_long(&mut self, tag: u8) -> Parsed<i64> {
+        let start = self.p.cur - 1;
+        let (width, neg) = match tag {
+            LONG64P | LONG64N => (8, tag == LONG64N),
+            _ => (1 << ((tag - LONG8P) / 2), (tag - LONG8P) % 2 == 1),
+        };
+        let mut v: u64 = 0;
+        for c in self.p.read_bytes(width)? {
+            v = v << 8 | *c as u64;
+        }
+        match (neg, v) {
+            (false, v) if v <= i64::MAX as u64 => Ok(v as i64),
+            // the magnitude of i64::MIN is one more than i64::MAX
+            (true, v) if v <= 1 << 63 => Ok((v as i64).wrapping_neg()),
+            _ => self.p.fail(start, UnserializeErrorKind::Malformed, "64-bit integer")
+        }
+    }
+
+    fn read_string(&mut self, tag: u8) -> Parsed<&'a [u8]> {
+        let start = self.p.cur - 1;
+        match tag {
+            STRING_EMPTY => Ok(b""),
+            STRING_ID8..=STRING_ID32 => {
+                let id = self.read_sized(tag, STRING_ID8)?;
+                match self.strings.get(id) {
+                    Some(s) => Ok(s),
+                    None => self.p.fail(start, UnserializeErrorKind::DanglingReference, "string id")
+                }
+            },
+            _ => {
+                let l = self.read_sized(tag, STRING8)?;
+                self.read_new_string(start, l)
+            }
+        }
+    }
+
+    fn read_new_string(&mut self, start: usize, l: usize) -> Parsed<&'a [u8]> {
+        if l > self.p.opts.max_string_len {
+            return self.p.fail(start, UnserializeErrorKind::LimitExceeded(Limit::StringLength), "shorter string");
+        }
+        let s = self.p.read_bytes(l)?;
+        self.strings.push(s);
+        Ok(s)
+    }
+
+    fn read_count(&mut self, tag: u8, base: u8) -> Parsed<usize> {
+        let start = self.p.cur - 1;
+        let l = self.read_sized(tag, base)?;
+        if l > self.p.opts.max_elements - self.p.nodes.len() {
+            return self.p.fail(start, UnserializeErrorKind::LimitExceeded(Limit::Elements), "fewer elements");
+        }
+        Ok(l)
+    }
+
+    // keys do not take a node and are not numbered
+    fn read_key(&mut self) -> Parsed<PhpKeyRef<'a>> {
+        match self.p.read_byte("key")? {
+            tag @ LONG8P..=LONG32N | tag @ LONG64P..=LONG64N => Ok(PhpKeyRef::Int(self.read_long(tag)?)),
+            tag @ STRING_EMPTY..=STRING32 => Ok(PhpKeyRef::from_bytes(self.read_string(tag)?)),
+            _ => self.p.fail(self.p.cur - 1, UnserializeErrorKind::UnknownType, "integer or string key")
+        }
+    }
+
+    fn read_entries(&mut self, tag: u8) -> Parsed<Vec<(PhpKeyRef<'a>, usize)>> {
+        let l = self.read_count(tag, ARRAY8)?;
+        let mut items = vec![];
+        for _i in 0..l {
+            let k = self.read_key()?;
+            let v = self.read_var()?;
+            items.push((k, v));
+        }
+        Ok(items)
+    }
+
+    fn read_object(&mut self, id: usize, tag: u8) -> Parsed<Node<'a>> {
+        let start = self.p.cur - 1;
+        let class = if tag >= OBJECT_ID8 {
+            let n = self.read_sized(tag, OBJECT_ID8)?;
+            match self.strings.get(n) {
+                Some(s) => s,
+                None => return self.p.fail(start, UnserializeErrorKind::DanglingReference, "string id")
+            }
+        } else {
+            let l = self.read_sized(tag, OBJECT8)?;
+            self.read_new_string(start, l)?
+        };
+        if !valid_class_name(class) {
+            return self.p.fail(start, UnserializeErrorKind::Malformed, "class name");
+        }
+        self.refs.push(id);
+
+        match self.p.read_byte("properties")? {
+            tag @ ARRAY8..=ARRAY32 => Ok(Node::Object(class, self.read_entries(tag)?)),
+            tag @ OBJECT_SER8..=OBJECT_SER32 => {
+                let l = self.read_sized(tag, OBJECT_SER8)?;
+                if l > self.p.opts.max_string_len {
+                    return self.p.fail(start, UnserializeErrorKind::LimitExceeded(Limit::StringLength), "shorter string");
+                }
+                let data = self.p.read_bytes(l)?;
+                // same as `C:`: without the class the payload means nothing
+                if !self.p.opts.allowed_classes.allows(class) {
+                    return Ok(Node::Object(class, vec![]));
+                }
+                Ok(Node::Value(PhpValueRef::Custom { class, data }))
+            },
+            _ => self.p.fail(self.p.cur - 1, UnserializeErrorKind::UnknownType, "properties")
+        }
+    }
+
+    // only vars that are complete can be pointed at, like with `R:`, and
+    // object references only at objects
+    fn read_ref(&mut self, tag: u8, base: u8, kind: RefKind) -> Parsed<Node<'a>> {
+        let start = self.p.cur - 1;
+        let n = self.read_sized(tag, base)?;
+        let target = match self.refs.get(n) {
+            Some(&t) => t,
+            None => return self.p.fail(start, UnserializeErrorKind::DanglingReference, "reference id")
+        };
+        match self.p.nodes[target] {
+            Node::Pending => return self.p.fail(start, UnserializeErrorKind::DanglingReference, "reference id"),
+            Node::Object(..) | Node::Value(PhpValueRef::Custom { .. }) => (),
+            _ if kind == RefKind::Value => return self.p.fail(start, UnserializeErrorKind::Malformed, "object id"),
+            _ => (),
+        }
+        self.p.refer(target, kind);
+        Ok(Node::Ref(kind, target))
+    }
+
+    fn read_nested(&mut self, id: usize, tag: u8) -> Parsed<Node<'a>> {
+        if self.p.depth >= self.p.opts.max_depth {
+            return self.p.fail(self.p.cur - 1, UnserializeErrorKind::LimitExceeded(Limit::Depth), "shallower nesting");
+        }
+        self.p.depth += 1;
+        let node = if tag <= ARRAY32 {
+            self.refs.push(id);
+            Node::Array(self.read_entries(tag)?)
+        } else {
+            self.read_object(id, tag)?
+        };
+        self.p.depth -= 1;
+        Ok(node)
+    }
+
+    fn read_var(&mut self) -> Parsed<usize> {
+        let id = self.p.nodes.len();
+        if id >= self.p.opts.max_elements {
+            return self.p.fail(self.p.cur, UnserializeErrorKind::LimitExceeded(Limit::Elements), "fewer elements");
+        }
+        self.p.nodes.push(Node::Pending);
+        self.p.targets.push(None);
+
+        let mut tag = self.p.read_byte("type tag")?;
+        let is_ref = tag == REF;
+        if is_ref {
+            tag = self.p.read_byte("type tag")?;
+        }
+        let node = match tag {
+            NULL => Node::Value(PhpValueRef::Null),
+            BOOL_FALSE => Node::Value(PhpValueRef::Bool(false)),
+            BOOL_TRUE => Node::Value(PhpValueRef::Bool(true)),
+            LONG8P..=LONG32N | LONG64P..=LONG64N => Node::Value(PhpValueRef::Int(self.read_long(tag)?)),
+            DOUBLE => {
+                let bits = self.p.read_bytes(8)?.iter().fold(0, |v, c| v << 8 | *c as u64);
+                Node::Value(PhpValueRef::Float(f64::from_bits(bits)))
+            },
+            STRING_EMPTY..=STRING32 => Node::Value(PhpValueRef::String(self.read_string(tag)?)),
+            ARRAY8..=OBJECT_ID32 => self.read_nested(id, tag)?,
+            REF8..=REF32 if !is_ref => self.read_ref(tag, REF8, RefKind::Shared)?,
+            OBJREF8..=OBJREF32 if !is_ref => self.read_ref(tag, OBJREF8, RefKind::Value)?,
+            _ => return self.p.fail(self.p.cur - 1, UnserializeErrorKind::UnknownType, "type tag")
+        };
+        // arrays and objects took their number when they started
+        if is_ref && !(ARRAY8..=OBJECT_ID32).contains(&tag) {
+            self.refs.push(id);
+        }
+        self.p.nodes[id] = node;
+        Ok(id)
+    }
+}
+
+// the igbinary counterpart of `parse_nodes`
+pub(super) fn igbinary_nodes<'a, 'o>(raw: &'a [u8], opts: &'o UnserializeOptions) -> Parsed<(Parser<'a, 'o>, usize)> {
+    let mut reader = Reader { p: Parser::new(raw, opts), strings: vec![], refs: vec![] };
+    if raw.len() > opts.max_input_len {
+        return reader.p.fail(opts.max_input_len, UnserializeErrorKind::LimitExceeded(Limit::InputLength), "shorter input");
+    }
+    let version = reader.p.read_bytes(4)?.iter().fold(0, |v, c| v << 8 | *c as u32);
+    if version != 1 && version != VERSION {
+        return reader.p.fail(0, UnserializeErrorKind::Malformed, "igbinary version");
+    }
+    let id = reader.read_var()?;
+    if reader.p.len != reader.p.cur {
+        return reader.p.fail(reader.p.cur, UnserializeErrorKind::TrailingData, "end of input");
+    }
+    Ok((reader.p, id))
+}
+
+// igbinary_unserialize(), with the same options and errors as `unserialize_with`
+pub fn igbinary_unserialize_with(raw: &[u8], opts: &UnserializeOptions) -> ::std::result::Result<PhpVar, UnserializeError> {
+    let (mut parser, id) = igbinary_nodes(raw, opts)?;
+    Ok(*parser.build(id)?)
+}
+
+pub fn igbinary_unserialize(raw: &[u8]) -> ::std::result::Result<PhpVar, UnserializeError> {
+    igbinary_unserialize_with(raw, &UnserializeOptions::default())
+}
+
+struct Writer {
+    out: Vec<u8>,
+    strings: HashMap<Vec<u8>, usize>,
+    // the number the next array, object or `&` value gets
+    refs: usize,
+    seen: HashMap<*const RefCell<PhpVar>, usize>,
+    // only objects and `&` values are written once, copies of anything
+    // else are written out again for every holder
+    budget: Budget,
+}
+
+impl Writer {
+    // the narrowest of the three variants starting at `base`
+    fn write_sized(&mut self, base: u8, n: usize) -> Result<()> {
+        if n <= 0xff {
+            self.out.push(base);
+            self.out.push(n as u8);
+        } else if n <= 0xffff {
+            self.out.push(base + 1);
+            self.out.extend_from_slice(&(n as u16).to_be_bytes());
+        } else if n <= 0xffff_ffff {
+            self.out.push(base + 2);
+            self.out.extend_from_slice(&(n as u32).to_be_bytes());
+        } else {
+            bail!("{} does not fit igbinary's 32-bit lengths", n)
+        }
+        Ok(())
+    }
+
+    fn write_long(&mut self, i: i64) {
+        let neg = (i < 0) as u8;
+        let v = i.unsigned_abs();
+        if v <= 0xff {
+            self.out.push(LONG8P + neg);
+            self.out.push(v as u8);
+        } else if v <= 0xffff {
+            self.out.push(LONG8P + 2 + neg);
+            self.out.extend_from_slice(&(v as u16).to_be_bytes());
+        } else if v <= 0xffff_ffff {
+            self.out.push(LONG8P + 4 + neg);
+            self.out.extend_from_slice(&(v as u32).to_be_bytes());
+        } else {
+            self.out.push(LONG64P + neg);
+            self.out.extend_from_slice(&v.to_be_bytes());
+        }
+    }
+
+    fn write_string(&mut self, s: &[u8]) -> Result<()> {
+        if s.is_empty() {
+            self.out.push(STRING_EMPTY);
+        } else if let Some(&id) = self.strings.get(s) {
+            self.write_sized(STRING_ID8, id)?;
+        } else {
+            self.strings.insert(s.to_vec(), self.strings.len());
+            self.write_sized(STRING8, s.len())?;
+            self.out.extend_from_slice(s);
+        }
+        Ok(())
+    }
+
+    fn write_class(&mut self, class: &[u8]) -> Result<()> {
+        self.refs += 1;
+        if let Some(&id) = self.strings.get(class) {
+            self.write_sized(OBJECT_ID8, id)?;
+        } else {
+            self.strings.insert(class.to_vec(), self.strings.len());
+            self.write_sized(OBJECT8, class.len())?;
+            self.out.extend_from_slice(class);
+        }
+        Ok(())
+    }
+
+    fn write_var(&mut self, var: &PhpVar) -> Result<()> {
+        self.budget.var(var)?;
+        match var {
+            PhpVar::Null => self.out.push(NULL),
+            PhpVar::Bool(b) => self.out.push(if *b { BOOL_TRUE } else { BOOL_FALSE }),
+            PhpVar::Int(i) => self.write_long(*i),
+            PhpVar::Float(f) => {
+                self.out.push(DOUBLE);
+                self.out.extend_from_slice(&f.to_bits().to_be_bytes());
+            },
+            PhpVar::String(s) => self.write_string(s)?,
+            PhpVar::Array(arr) => {
+                self.refs += 1;
+                self.write_sized(ARRAY8, arr.len())?;
+                for (k, v) in arr {
+                    match k {
+                        PhpKey::Int(i) => self.write_long(*i),
+                        PhpKey::String(s) => self.write_string(s)?,
+                    }
+                    self.write_var(v)?;
+                }
+            },
+            PhpVar::Object { class, props } => {
+                // incomplete class objects go back out under their own class
+                let (class, props) = match var.incomplete_class() {
+                    Some(name) => (name, &props[1..]),
+                    None => (class.as_slice(), &props[..]),
+                };
+                self.write_class(class)?;
+                self.write_sized(ARRAY8, props.len())?;
+                for (name, v) in props {
+                    self.write_string(name)?;
+                    self.write_var(v)?;
+                }
+            },
+            PhpVar::Custom { class, data } => {
+                self.write_class(class)?;
+                self.write_sized(OBJECT_SER8, data.len())?;
+                self.out.extend_from_slice(data);
+            },
+            PhpVar::Enum { .. } => bail!("enum cases have no igbinary encoding here"),
+            PhpVar::Ref(kind, v) => {
+                let ptr = Rc::as_ptr(v);
+                let v = v.borrow();
+                let object = matches!(*v, PhpVar::Object { .. } | PhpVar::Custom { .. });
+                let numbered = object || matches!(*v, PhpVar::Array(_));
+                match (self.seen.get(&ptr), kind) {
+                    (Some(&id), RefKind::Shared) => self.write_sized(REF8, id)?,
+                    (Some(&id), RefKind::Value) if object => self.write_sized(OBJREF8, id)?,
+                    // copies of anything but objects are just written again
+                    (Some(_), RefKind::Value) => self.write_var(&v)?,
+                    (None, _) => {
+                        if *kind == RefKind::Shared || numbered {
+                            self.seen.insert(ptr, self.refs);
+                        }
+                        if *kind == RefKind::Shared {
+                            self.out.push(REF);
+                            if !numbered {
+                                self.refs += 1;
+                            }
+                        }
+                        self.write_var(&v)?;
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+// igbinary_serialize()
+pub fn igbinary_serialize(var: &PhpVar) -> Result<Vec<u8>> {
+    let mut ser = Writer {
+        out: VERSION.to_be_bytes().to_vec(),
+        strings: HashMap::new(),
+        refs: 0,
+        seen: HashMap::new(),
+        budget: Budget::new(&ExpansionLimits::default()),
+    };
+    ser.write_var(var)?;
+    Ok(ser.out)
+}
+
+#[cfg(test)]
+use super::{serialize, try_unserialize, AllowedClasses};
+
+#[test]
+fn test_igbinary() -> Result<()> {
+    // a PHP value and what igbinary_serialize() makes of it
+    let cases: &[(&[u8], &[u8])] = &[
+        (b"N;", b"\x00\x00\x00\x02\x00"),
+        (b"b:1;", b"\x00\x00\x00\x02\x05"),
+        (b"i:-1;", b"\x00\x00\x00\x02\x07\x01"),
+        (b"i:300;", b"\x00\x00\x00\x02\x08\x01\x2c"),
+        (b"i:-9223372036854775808;", b"\x00\x00\x00\x02\x21\x80\x00\x00\x00\x00\x00\x00\x00"),
+        (b"d:0.5;", b"\x00\x00\x00\x02\x0c\x3f\xe0\x00\x00\x00\x00\x00\x00"),
+        (b"s:0:\"\";", b"\x00\x00\x00\x02\x0d"),
+        (b"s:3:\"foo\";", b"\x00\x00\x00\x02\x11\x03foo"),
+        (b"a:2:{i:0;i:1;i:1;i:2;}", b"\x00\x00\x00\x02\x14\x02\x06\x00\x06\x01\x06\x01\x06\x02"),
+        // the second "a" is only the id of the first one
+        (b"a:1:{s:1:\"a\";s:1:\"a\";}", b"\x00\x00\x00\x02\x14\x01\x11\x01a\x0e\x00"),
+        (b"O:3:\"Foo\":1:{s:1:\"a\";i:1;}", b"\x00\x00\x00\x02\x17\x03Foo\x14\x01\x11\x01a\x06\x01"),
+        (b"C:3:\"Foo\":2:{ab}", b"\x00\x00\x00\x02\x17\x03Foo\x1d\x02ab"),
+        (b"a:2:{i:0;O:3:\"Foo\":0:{}i:1;r:2;}", b"\x00\x00\x00\x02\x14\x02\x06\x00\x17\x03Foo\x14\x00\x06\x01\x22\x01"),
+        (b"a:2:{i:0;O:3:\"Foo\":0:{}i:1;O:3:\"Foo\":0:{}}",
+         b"\x00\x00\x00\x02\x14\x02\x06\x00\x17\x03Foo\x14\x00\x06\x01\x1a\x00\x14\x00"),
+        // `$a = 1; $b = [&$a, &$a];`
+        (b"a:2:{i:0;i:1;i:1;R:2;}", b"\x00\x00\x00\x02\x14\x02\x06\x00\x25\x06\x01\x06\x01\x01\x01"),
+        (b"a:2:{i:0;a:0:{}i:1;R:2;}", b"\x00\x00\x00\x02\x14\x02\x06\x00\x25\x14\x00\x06\x01\x01\x01"),
+    ];
+    for (php, ig) in cases {
+        let var = try_unserialize(php).unwrap();
+        assert_eq!(igbinary_serialize(&var)?, ig.to_vec(), "{}", String::from_utf8_lossy(php));
+        assert_eq!(serialize(&igbinary_unserialize(ig).unwrap())?, php.to_vec());
+    }
+    assert!(igbinary_serialize(&PhpVar::Enum { class: b"Foo".to_vec(), case: b"Bar".to_vec() }).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_igbinary_errors() {
+    let cases: &[(&[u8], usize, UnserializeErrorKind)] = &[
+        (b"", 0, UnserializeErrorKind::Truncated),
+        (b"\x00\x00\x00\x03\x00", 0, UnserializeErrorKind::Malformed),
+        (b"\x00\x00\x00\x02\x00\x00", 5, UnserializeErrorKind::TrailingData),
+        (b"\x00\x00\x00\x02\x11\x03fo", 8, UnserializeErrorKind::Truncated),
+        (b"\x00\x00\x00\x02\x0e\x00", 4, UnserializeErrorKind::DanglingReference),
+        (b"\x00\x00\x00\x02\x14\x01\x06\x00\x01\x00", 8, UnserializeErrorKind::DanglingReference),
+        (b"\x00\x00\x00\x02\x14\x01\x00\x00", 6, UnserializeErrorKind::UnknownType),
+        (b"\x00\x00\x00\x02\x20\x80\x00\x00\x00\x00\x00\x00\x00", 4, UnserializeErrorKind::Malformed),
+        (b"\x00\x00\x00\x02\x30", 4, UnserializeErrorKind::UnknownType),
+        // igbinary only numbers objects for object references
+        (b"\x00\x00\x00\x02\x14\x02\x06\x00\x14\x00\x06\x01\x22\x01", 12, UnserializeErrorKind::Malformed),
+    ];
+    for (raw, offset, kind) in cases {
+        let err = igbinary_unserialize(raw).unwrap_err();
+        assert_eq!((err.offset, err.kind), (*offset, *kind), "{:?}", raw);
+    }
+
+    let opts = UnserializeOptions { allowed_classes: AllowedClasses::Only(vec![]), ..Default::default() };
+    let var = igbinary_unserialize_with(b"\x00\x00\x00\x02\x17\x03Foo\x1d\x02ab", &opts).unwrap();
+    assert_eq!(var.incomplete_class(), Some(&b"Foo"[..]));
+    let opts = UnserializeOptions { max_depth: 1, ..Default::default() };
+    let err = igbinary_unserialize_with(b"\x00\x00\x00\x02\x14\x01\x06\x00\x14\x00", &opts).unwrap_err();
+    assert_eq!(err.kind, UnserializeErrorKind::LimitExceeded(Limit::Depth));
+}
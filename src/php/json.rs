@@ -0,0 +1,188 @@
+use serde_json::{Map, Number, Value};
+
+use crate::errors::*;
+use super::{Budget, ExpansionLimits, PhpArray, PhpKey, PhpVar, Visibility};
+
+// what json_encode does with strings that are not valid UTF-8
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryStrings {
+    // the default: the whole encode fails
+    Reject,
+    // JSON_INVALID_UTF8_SUBSTITUTE: every bad sequence becomes U+FFFD
+    Substitute,
+    // every byte is taken as a Latin-1 code point, i.e. json_encode(utf8_encode($s))
+    Latin1,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JsonOptions {
+    pub binary_strings: BinaryStrings,
+    // json_encode's $depth
+    pub max_depth: usize,
+    // shared slots are encoded once per holder, like json_encode does
+    pub expansion: ExpansionLimits,
+}
+
+impl Default for JsonOptions {
+    fn default() -> Self {
+        JsonOptions {
+            binary_strings: BinaryStrings::Reject,
+            max_depth: 512,
+            expansion: ExpansionLimits::default(),
+        }
+    }
+}
+
+fn encode_string(s: &[u8], opts: &JsonOptions) -> Result<String> {
+    match (String::from_utf8(s.to_vec()), opts.binary_strings) {
+        (Ok(s), _) => Ok(s),
+        (Err(_), BinaryStrings::Substitute) => Ok(String::from_utf8_lossy(s).into_owned()),
+        (Err(_), BinaryStrings::Latin1) => Ok(s.iter().map(|c| *c as char).collect()),
+        (Err(e), BinaryStrings::Reject) => Err(e.into()),
+    }
+}
+
+// arrays with the keys 0..n in order are lists, everything else an object
+fn is_list(arr: &PhpArray) -> bool {
+    arr.keys().enumerate().all(|(i, k)| *k == PhpKey::Int(i as i64))
+}
+
+fn encode(var: &PhpVar, opts: &JsonOptions, budget: &Budget, depth: usize) -> Result<Value> {
+    if depth > opts.max_depth {
+        bail!("maximum stack depth exceeded")
+    }
+    budget.var(var)?;
+    Ok(match var {
+        PhpVar::Null => Value::Null,
+        PhpVar::Bool(b) => Value::Bool(*b),
+        PhpVar::Int(i) => Value::Number((*i).into()),
+        PhpVar::Float(f) => match Number::from_f64(*f) {
+            Some(n) => Value::Number(n),
+            None => bail!("INF and NAN cannot be JSON encoded")
+        },
+        PhpVar::String(s) => Value::String(encode_string(s, opts)?),
+        PhpVar::Array(arr) if is_list(arr) => {
+            Value::Array(arr.values().map(|v| encode(v, opts, budget, depth + 1)).collect::<Result<_>>()?)
+        },
+        PhpVar::Array(arr) => {
+            let mut map = Map::new();
+            for (k, v) in arr {
+                let k = match k {
+                    PhpKey::Int(i) => i.to_string(),
+                    PhpKey::String(s) => {
+                        budget.bytes(s.len())?;
+                        encode_string(s, opts)?
+                    },
+                };
+                map.insert(k, encode(v, opts, budget, depth + 1)?);
+            }
+            Value::Object(map)
+        },
+        // only public properties are visible to json_encode
+        PhpVar::Object { .. } => {
+            let mut map = Map::new();
+            for (vis, name, v) in var.properties() {
+                if vis == Visibility::Public {
+                    budget.bytes(name.len())?;
+                    map.insert(encode_string(name, opts)?, encode(v, opts, budget, depth + 1)?);
+                }
+            }
+            Value::Object(map)
+        },
+        PhpVar::Custom { .. } => Value::Object(Map::new()),
+        // the backing value of an enum is not part of the serialized form
+        PhpVar::Enum { .. } => bail!("non-backed enums have no JSON representation"),
+        PhpVar::Ref(_, v) => encode(&v.borrow(), opts, budget, depth)?,
+    })
+}
+
+pub fn to_json_with(var: &PhpVar, opts: &JsonOptions) -> Result<Value> {
+    encode(var, opts, &Budget::new(&opts.expansion), 0)
+}
+
+// json_encode($var)
+pub fn to_json(var: &PhpVar) -> Result<Value> {
+    to_json_with(var, &JsonOptions::default())
+}
+
+// json_decode($json, true): objects become arrays whose keys go through the
+// usual array key casts, integers too large for an int become floats
+pub fn from_json(value: &Value) -> PhpVar {
+    match value {
+        Value::Null => PhpVar::Null,
+        Value::Bool(b) => PhpVar::Bool(*b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => PhpVar::Int(i),
+            None => PhpVar::Float(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(s) => PhpVar::String(s.as_bytes().to_vec()),
+        Value::Array(items) => PhpVar::Array(items.iter().map(from_json).collect()),
+        Value::Object(map) => PhpVar::Array(map.iter().map(|(k, v)| (k.as_bytes(), from_json(v))).collect()),
+    }
+}
+
+#[cfg(test)]
+use super::unserialize;
+
+#[cfg(test)]
+fn encode_str(raw: &[u8], opts: &JsonOptions) -> Result<String> {
+    Ok(serde_json::to_string(&to_json_with(&unserialize(raw), opts)?)?)
+}
+
+#[test]
+fn test_to_json() -> Result<()> {
+    let opts = JsonOptions::default();
+    // php > echo json_encode(unserialize('...'));
+    let cases: &[(&[u8], &str)] = &[
+        (b"N;", "null"),
+        (b"b:1;", "true"),
+        (b"i:-3;", "-3"),
+        (b"d:0.1;", "0.1"),
+        (b"d:1;", "1.0"),
+        (b"s:2:\"\xc3\xa9\";", "\"\u{e9}\""),
+        (b"a:0:{}", "[]"),
+        (b"a:2:{i:0;s:1:\"a\";i:1;s:1:\"b\";}", "[\"a\",\"b\"]"),
+        (b"a:2:{i:1;s:1:\"a\";i:0;s:1:\"b\";}", "{\"1\":\"a\",\"0\":\"b\"}"),
+        (b"a:2:{s:1:\"z\";i:1;s:1:\"a\";a:1:{i:1;N;}}", "{\"z\":1,\"a\":{\"1\":null}}"),
+        (b"O:3:\"Foo\":3:{s:1:\"a\";i:1;s:4:\"\0*\0b\";i:2;s:6:\"\0Foo\0c\";i:3;}", "{\"a\":1}"),
+        (b"a:2:{i:0;i:1;i:1;R:2;}", "[1,1]"),
+    ];
+    for (raw, json) in cases {
+        assert_eq!(encode_str(raw, &opts)?, *json);
+    }
+
+    assert!(encode_str(b"d:INF;", &opts).is_err());
+    assert!(encode_str(b"E:7:\"Foo:Bar\";", &opts).is_err());
+    assert!(encode_str(b"s:2:\"\xff\xfe\";", &opts).is_err());
+    let opts = JsonOptions { binary_strings: BinaryStrings::Substitute, ..Default::default() };
+    assert_eq!(encode_str(b"s:3:\"a\xffb\";", &opts)?, "\"a\u{fffd}b\"");
+    let opts = JsonOptions { binary_strings: BinaryStrings::Latin1, ..Default::default() };
+    assert_eq!(encode_str(b"s:3:\"a\xffb\";", &opts)?, "\"a\u{ff}b\"");
+    let opts = JsonOptions { max_depth: 1, ..Default::default() };
+    assert!(encode_str(b"a:1:{i:0;a:1:{i:0;a:0:{}}}", &opts).is_err());
+
+    // every holder of a shared slot counts
+    let raw = b"a:3:{i:0;a:2:{i:0;i:1;i:1;i:2;}i:1;r:2;i:2;r:2;}";
+    assert_eq!(encode_str(raw, &JsonOptions::default())?, "[[1,2],[1,2],[1,2]]");
+    let opts = JsonOptions { expansion: ExpansionLimits { max_nodes: 9, ..Default::default() }, ..Default::default() };
+    let err = encode_str(raw, &opts).unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::ExpansionLimit), "{}", err);
+    Ok(())
+}
+
+#[test]
+fn test_from_json() -> Result<()> {
+    // php > echo serialize(json_decode('...', true));
+    let cases: &[(&str, &[u8])] = &[
+        ("null", b"N;"),
+        ("[1,2.5,\"a\",true]", b"a:4:{i:0;i:1;i:1;d:2.5;i:2;s:1:\"a\";i:3;b:1;}"),
+        ("{\"b\":{},\"1\":[],\"-0\":null}", b"a:3:{s:1:\"b\";a:0:{}i:1;a:0:{}s:2:\"-0\";N;}"),
+        ("18446744073709551615", b"d:1.8446744073709552E+19;"),
+        ("1.0", b"d:1;"),
+    ];
+    for (json, raw) in cases {
+        let value: Value = serde_json::from_str(json)?;
+        assert_eq!(super::serialize(&from_json(&value))?, raw.to_vec(), "{}", json);
+    }
+    Ok(())
+}
@@ -0,0 +1,717 @@
+use std::collections::HashMap;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::errors::*;
+use super::{valid_class_name, Limit, Node, Parsed, Parser, PhpKey, PhpKeyRef, PhpValueRef, PhpVar, RefKind,
+            UnserializeError, UnserializeErrorKind, UnserializeOptions};
+
+// msgpack and CBOR have no notion of objects or references, so those are
+// wrapped in an extension type (msgpack) or tag (CBOR) holding a two
+// element array:
+//   object:  [class, {name: value, ...}]
+//   custom:  [class, data]
+//   enum:    [class, case]
+//   ref:     [kind, value]   the first time a shared var is written
+//   backref: [kind, n]       every later time, n counting the refs
+// kind is 0 for `r:` and 1 for `R:`. PHP strings are byte strings either
+// way and arrays are always maps, in order.
+const OBJECT: u8 = 1;
+const CUSTOM: u8 = 2;
+const ENUM: u8 = 3;
+const REF: u8 = 4;
+const BACKREF: u8 = 5;
+
+// "php\0" + the code above, well clear of the registered CBOR tags
+const CBOR_TAG_BASE: u64 = 0x7068_7000;
+
+// every payload starts with a marker byte, the format letter and the
+// version of the layout above. msgpack never uses 0xc1, but in CBOR it is
+// tag 1 (epoch time), which takes a number and never the byte strings the
+// letters read as (0x4d and 0x43), so only all three bytes tell a payload
+// apart
+const MAGIC: u8 = 0xc1;
+const VERSION: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Packing {
+    MsgPack,
+    Cbor,
+}
+
+impl Packing {
+    fn letter(self) -> u8 {
+        match self {
+            Packing::MsgPack => b'M',
+            Packing::Cbor => b'C',
+        }
+    }
+
+    // the packing of a `pack`ed payload, going by its whole header
+    pub fn detect(raw: &[u8]) -> Option<Packing> {
+        match raw {
+            [MAGIC, b'M', VERSION, ..] => Some(Packing::MsgPack),
+            [MAGIC, b'C', VERSION, ..] => Some(Packing::Cbor),
+            _ => None
+        }
+    }
+}
+
+enum Token<'a> {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Bytes(&'a [u8]),
+    Array(usize),
+    Map(usize),
+    // one of the codes above and, for msgpack, where its payload ends
+    Ext(u8, Option<usize>),
+}
+
+struct Unpacker<'a, 'o> {
+    p: Parser<'a, 'o>,
+    packing: Packing,
+    // node ids of the vars written with `REF`
+    refs: Vec<usize>,
+}
+
+impl<'a, 'o> Unpacker<'a, 'o> {
+    fn read_uint(&mut self, width: usize) -> Parsed<u64> {
+        Ok(self.p.read_bytes(width)?.iter().fold(0, |v, c| v << 8 | *c as u64))
+    }
+
+    fn read_len(&mut self, start: usize, width: usize) -> Parsed<usize> {
+        let l = self.read_uint(width)? as usize;
+        if l > self.p.opts.max_string_len {
+            return self.p.fail(start, UnserializeErrorKind::LimitExceeded(Limit::StringLength), "shorter string");
+        }
+        Ok(l)
+    }
+
+    fn read_count(&mut self, start: usize, width: usize) -> Parsed<usize> {
+        let l = self.read_uint(width)? as usize;
+        if l > self.p.opts.max_elements - self.p.nodes.len() {
+            return self.p.fail(start, UnserializeErrorKind::LimitExceeded(Limit::Elements), "fewer elements");
+        }
+        Ok(l)
+    }
+
+    fn int(&self, start: usize, v: u64) -> Parsed<i64> {
+        if v > i64::MAX as u64 {
+            return self.p.fail(start, UnserializeErrorKind::Malformed, "64-bit integer");
+        }
+        Ok(v as i64)
+    }
+
+    fn msgpack_token(&mut self) -> Parsed<Token<'a>> {
+        let start = self.p.cur;
+        let c = self.p.read_byte("type marker")?;
+        Ok(match c {
+            0x00..=0x7f => Token::Int(c as i64),
+            0x80..=0x8f => Token::Map((c & 0x0f) as usize),
+            0x90..=0x9f => Token::Array((c & 0x0f) as usize),
+            0xa0..=0xbf => Token::Bytes(self.p.read_bytes((c & 0x1f) as usize)?),
+            0xc0 => Token::Nil,
+            0xc2 => Token::Bool(false),
+            0xc3 => Token::Bool(true),
+            // bin 8/16/32, str 8/16/32
+            0xc4..=0xc6 | 0xd9..=0xdb => {
+                let width = 1 << ((c - if c >= 0xd9 { 0xd9 } else { 0xc4 }) as usize);
+                let l = self.read_len(start, width)?;
+                Token::Bytes(self.p.read_bytes(l)?)
+            },
+            // ext 8/16/32 and fixext 1/2/4/8/16
+            0xc7..=0xc9 | 0xd4..=0xd8 => {
+                let l = match c {
+                    0xc7..=0xc9 => self.read_uint(1 << (c - 0xc7))? as usize,
+                    _ => 1 << (c - 0xd4),
+                };
+                let code = self.p.read_byte("extension type")?;
+                Token::Ext(code, Some(self.p.cur.saturating_add(l)))
+            },
+            0xca => Token::Float(f32::from_bits(self.read_uint(4)? as u32) as f64),
+            0xcb => Token::Float(f64::from_bits(self.read_uint(8)?)),
+            0xcc..=0xcf => {
+                let v = self.read_uint(1 << (c - 0xcc))?;
+                Token::Int(self.int(start, v)?)
+            },
+            // sign extended from the width they were written with
+            0xd0 => Token::Int(self.read_uint(1)? as u8 as i8 as i64),
+            0xd1 => Token::Int(self.read_uint(2)? as u16 as i16 as i64),
+            0xd2 => Token::Int(self.read_uint(4)? as u32 as i32 as i64),
+            0xd3 => Token::Int(self.read_uint(8)? as i64),
+            0xdc | 0xdd => Token::Array(self.read_count(start, if c == 0xdc { 2 } else { 4 })?),
+            0xde | 0xdf => Token::Map(self.read_count(start, if c == 0xde { 2 } else { 4 })?),
+            0xe0..=0xff => Token::Int(c as i8 as i64),
+            _ => return self.p.fail(start, UnserializeErrorKind::UnknownType, "type marker")
+        })
+    }
+
+    fn cbor_token(&mut self) -> Parsed<Token<'a>> {
+        let start = self.p.cur;
+        let c = self.p.read_byte("initial byte")?;
+        let (major, info) = (c >> 5, c & 0x1f);
+        // the argument: immediate or 1, 2, 4 or 8 bytes after
+        let width = match info {
+            0..=23 => 0,
+            24..=27 => 1 << (info - 24),
+            // indefinite lengths and reserved values are never written
+            _ => return self.p.fail(start, UnserializeErrorKind::Malformed, "definite length"),
+        };
+        if major == 7 {
+            return match info {
+                20 => Ok(Token::Bool(false)),
+                21 => Ok(Token::Bool(true)),
+                22 => Ok(Token::Nil),
+                26 => Ok(Token::Float(f32::from_bits(self.read_uint(4)? as u32) as f64)),
+                27 => Ok(Token::Float(f64::from_bits(self.read_uint(8)?))),
+                _ => self.p.fail(start, UnserializeErrorKind::UnknownType, "simple value")
+            };
+        }
+        let arg = if width == 0 { info as u64 } else { self.read_uint(width)? };
+        Ok(match major {
+            0 => Token::Int(self.int(start, arg)?),
+            1 => Token::Int(-1 - self.int(start, arg)?),
+            2 | 3 => {
+                if arg > self.p.opts.max_string_len as u64 {
+                    return self.p.fail(start, UnserializeErrorKind::LimitExceeded(Limit::StringLength), "shorter string");
+                }
+                Token::Bytes(self.p.read_bytes(arg as usize)?)
+            },
+            4 | 5 => {
+                if arg > (self.p.opts.max_elements - self.p.nodes.len()) as u64 {
+                    return self.p.fail(start, UnserializeErrorKind::LimitExceeded(Limit::Elements), "fewer elements");
+                }
+                if major == 4 { Token::Array(arg as usize) } else { Token::Map(arg as usize) }
+            },
+            _ => match arg.checked_sub(CBOR_TAG_BASE) {
+                Some(code) if code <= BACKREF as u64 => Token::Ext(code as u8, None),
+                _ => return self.p.fail(start, UnserializeErrorKind::UnknownType, "tag")
+            }
+        })
+    }
+
+    fn next_token(&mut self) -> Parsed<Token<'a>> {
+        match self.packing {
+            Packing::MsgPack => self.msgpack_token(),
+            Packing::Cbor => self.cbor_token(),
+        }
+    }
+
+    fn expect_pair(&mut self) -> Parsed<()> {
+        let start = self.p.cur;
+        match self.next_token()? {
+            Token::Array(2) => Ok(()),
+            _ => self.p.fail(start, UnserializeErrorKind::Malformed, "2-element array")
+        }
+    }
+
+    fn expect_bytes(&mut self, expected: &'static str) -> Parsed<&'a [u8]> {
+        let start = self.p.cur;
+        match self.next_token()? {
+            Token::Bytes(s) => Ok(s),
+            _ => self.p.fail(start, UnserializeErrorKind::Malformed, expected)
+        }
+    }
+
+    fn expect_int(&mut self, expected: &'static str) -> Parsed<i64> {
+        let start = self.p.cur;
+        match self.next_token()? {
+            Token::Int(i) => Ok(i),
+            _ => self.p.fail(start, UnserializeErrorKind::Malformed, expected)
+        }
+    }
+
+    fn expect_class(&mut self) -> Parsed<&'a [u8]> {
+        let start = self.p.cur;
+        let class = self.expect_bytes("class name")?;
+        if !valid_class_name(class) {
+            return self.p.fail(start, UnserializeErrorKind::Malformed, "class name");
+        }
+        Ok(class)
+    }
+
+    fn expect_kind(&mut self) -> Parsed<RefKind> {
+        let start = self.p.cur;
+        match self.expect_int("reference kind")? {
+            0 => Ok(RefKind::Value),
+            1 => Ok(RefKind::Shared),
+            _ => self.p.fail(start, UnserializeErrorKind::Malformed, "reference kind")
+        }
+    }
+
+    fn read_key(&mut self) -> Parsed<PhpKeyRef<'a>> {
+        let start = self.p.cur;
+        match self.next_token()? {
+            Token::Int(i) => Ok(PhpKeyRef::Int(i)),
+            Token::Bytes(s) => Ok(PhpKeyRef::from_bytes(s)),
+            _ => self.p.fail(start, UnserializeErrorKind::UnknownType, "integer or string key")
+        }
+    }
+
+    fn read_entries(&mut self, l: usize) -> Parsed<Vec<(PhpKeyRef<'a>, usize)>> {
+        let mut items = vec![];
+        for _i in 0..l {
+            let k = self.read_key()?;
+            let v = self.read_var()?;
+            items.push((k, v));
+        }
+        Ok(items)
+    }
+
+    fn read_ext(&mut self, start: usize, code: u8) -> Parsed<Node<'a>> {
+        self.expect_pair()?;
+        match code {
+            OBJECT => {
+                let class = self.expect_class()?;
+                let map = self.p.cur;
+                match self.next_token()? {
+                    Token::Map(l) => Ok(Node::Object(class, self.read_entries(l)?)),
+                    _ => self.p.fail(map, UnserializeErrorKind::Malformed, "map")
+                }
+            },
+            CUSTOM => {
+                let class = self.expect_class()?;
+                let data = self.expect_bytes("string")?;
+                if !self.p.opts.allowed_classes.allows(class) {
+                    return Ok(Node::Object(class, vec![]));
+                }
+                Ok(Node::Value(PhpValueRef::Custom { class, data }))
+            },
+            ENUM => {
+                let class = self.expect_class()?;
+                let case = self.expect_bytes("enum case name")?;
+                if !self.p.opts.allowed_classes.allows(class) {
+                    return self.p.fail(start, UnserializeErrorKind::DisallowedClass, "allowed class");
+                }
+                Ok(Node::Value(PhpValueRef::Enum { class, case }))
+            },
+            BACKREF => {
+                let kind = self.expect_kind()?;
+                let n = self.expect_int("reference number")?;
+                let target = match self.refs.get(n as usize) {
+                    Some(&t) if n >= 0 => t,
+                    _ => return self.p.fail(start, UnserializeErrorKind::DanglingReference, "reference number")
+                };
+                if let Node::Pending = self.p.nodes[target] {
+                    return self.p.fail(start, UnserializeErrorKind::DanglingReference, "reference number");
+                }
+                self.p.refer(target, kind);
+                Ok(Node::Ref(kind, target))
+            },
+            _ => self.p.fail(start, UnserializeErrorKind::UnknownType, "extension type")
+        }
+    }
+
+    fn read_var(&mut self) -> Parsed<usize> {
+        let start = self.p.cur;
+        let token = self.next_token()?;
+        let (code, end) = match token {
+            Token::Ext(code, end) => (Some(code), end),
+            _ => (None, None)
+        };
+        let nested = matches!(token, Token::Array(_) | Token::Map(_) | Token::Ext(..));
+        if nested {
+            if self.p.depth >= self.p.opts.max_depth {
+                return self.p.fail(start, UnserializeErrorKind::LimitExceeded(Limit::Depth), "shallower nesting");
+            }
+            self.p.depth += 1;
+        }
+
+        let id = if code == Some(REF) {
+            // the var itself is numbered, the wrapper takes no node
+            self.expect_pair()?;
+            let kind = self.expect_kind()?;
+            let id = self.read_var()?;
+            self.p.targets[id] = Some(kind);
+            self.refs.push(id);
+            id
+        } else {
+            let id = self.p.nodes.len();
+            if id >= self.p.opts.max_elements {
+                return self.p.fail(start, UnserializeErrorKind::LimitExceeded(Limit::Elements), "fewer elements");
+            }
+            self.p.nodes.push(Node::Pending);
+            self.p.targets.push(None);
+            let node = match token {
+                Token::Nil => Node::Value(PhpValueRef::Null),
+                Token::Bool(b) => Node::Value(PhpValueRef::Bool(b)),
+                Token::Int(i) => Node::Value(PhpValueRef::Int(i)),
+                Token::Float(f) => Node::Value(PhpValueRef::Float(f)),
+                Token::Bytes(s) => Node::Value(PhpValueRef::String(s)),
+                // lists written by someone else
+                Token::Array(l) => {
+                    let mut items = vec![];
+                    for i in 0..l {
+                        items.push((PhpKeyRef::Int(i as i64), self.read_var()?));
+                    }
+                    Node::Array(items)
+                },
+                Token::Map(l) => Node::Array(self.read_entries(l)?),
+                Token::Ext(code, _) => self.read_ext(start, code)?,
+            };
+            self.p.nodes[id] = node;
+            id
+        };
+
+        if nested {
+            self.p.depth -= 1;
+        }
+        match end {
+            Some(end) if end != self.p.cur => self.p.fail(start, UnserializeErrorKind::BadLength, "extension length"),
+            _ => Ok(id)
+        }
+    }
+}
+
+// the `packed` counterpart of `parse_nodes`
+pub(super) fn packed_nodes<'a, 'o>(raw: &'a [u8], opts: &'o UnserializeOptions) -> Parsed<(Parser<'a, 'o>, usize)> {
+    let mut parser = Parser::new(raw, opts);
+    if raw.len() > opts.max_input_len {
+        return parser.fail(opts.max_input_len, UnserializeErrorKind::LimitExceeded(Limit::InputLength), "shorter input");
+    }
+    let packing = match Packing::detect(raw) {
+        Some(packing) => packing,
+        None if raw.len() < 3 => return parser.fail(raw.len(), UnserializeErrorKind::Truncated, "packing header"),
+        None => return parser.fail(0, UnserializeErrorKind::Malformed, "packing header"),
+    };
+    parser.cur = 3;
+    let mut unpacker = Unpacker { p: parser, packing, refs: vec![] };
+    let id = unpacker.read_var()?;
+    if unpacker.p.len != unpacker.p.cur {
+        return unpacker.p.fail(unpacker.p.cur, UnserializeErrorKind::TrailingData, "end of input");
+    }
+    Ok((unpacker.p, id))
+}
+
+// reads what `pack` wrote with either packing
+pub fn unpack_with(raw: &[u8], opts: &UnserializeOptions) -> ::std::result::Result<PhpVar, UnserializeError> {
+    let (mut parser, id) = packed_nodes(raw, opts)?;
+    Ok(*parser.build(id)?)
+}
+
+pub fn unpack(raw: &[u8]) -> ::std::result::Result<PhpVar, UnserializeError> {
+    unpack_with(raw, &UnserializeOptions::default())
+}
+
+struct Packer {
+    out: Vec<u8>,
+    packing: Packing,
+    refs: usize,
+    seen: HashMap<*const RefCell<PhpVar>, usize>,
+}
+
+impl Packer {
+    // CBOR major type and argument, in the shortest form
+    fn cbor_head(&mut self, major: u8, arg: u64) {
+        let major = major << 5;
+        if arg < 24 {
+            self.out.push(major | arg as u8);
+        } else if arg <= 0xff {
+            self.out.push(major | 24);
+            self.out.push(arg as u8);
+        } else if arg <= 0xffff {
+            self.out.push(major | 25);
+            self.out.extend_from_slice(&(arg as u16).to_be_bytes());
+        } else if arg <= 0xffff_ffff {
+            self.out.push(major | 26);
+            self.out.extend_from_slice(&(arg as u32).to_be_bytes());
+        } else {
+            self.out.push(major | 27);
+            self.out.extend_from_slice(&arg.to_be_bytes());
+        }
+    }
+
+    // msgpack lengths and counts: `fix` for short ones, then 8 (if `m8`
+    // is given), 16 and 32 bits
+    fn msgpack_head(&mut self, fix: Option<(u8, usize)>, m8: Option<u8>, m16: u8, l: usize) -> Result<()> {
+        match fix {
+            Some((marker, max)) if l <= max => self.out.push(marker | l as u8),
+            _ => match m8 {
+                Some(m8) if l <= 0xff => {
+                    self.out.push(m8);
+                    self.out.push(l as u8);
+                },
+                _ if l <= 0xffff => {
+                    self.out.push(m16);
+                    self.out.extend_from_slice(&(l as u16).to_be_bytes());
+                },
+                _ if l <= 0xffff_ffff => {
+                    self.out.push(m16 + 1);
+                    self.out.extend_from_slice(&(l as u32).to_be_bytes());
+                },
+                _ => bail!("{} does not fit msgpack's 32-bit lengths", l)
+            }
+        }
+        Ok(())
+    }
+
+    fn write_int(&mut self, i: i64) {
+        match self.packing {
+            Packing::Cbor if i >= 0 => self.cbor_head(0, i as u64),
+            Packing::Cbor => self.cbor_head(1, !(i as u64)),
+            Packing::MsgPack => {
+                if (-32..=127).contains(&i) {
+                    self.out.push(i as u8);
+                } else if i > 0 {
+                    let (marker, width) = match i {
+                        0..=0xff => (0xcc, 1),
+                        0x100..=0xffff => (0xcd, 2),
+                        0x1_0000..=0xffff_ffff => (0xce, 4),
+                        _ => (0xcf, 8),
+                    };
+                    self.out.push(marker);
+                    self.out.extend_from_slice(&i.to_be_bytes()[(8 - width)..]);
+                } else {
+                    let (marker, width) = if i >= i8::MIN as i64 {
+                        (0xd0, 1)
+                    } else if i >= i16::MIN as i64 {
+                        (0xd1, 2)
+                    } else if i >= i32::MIN as i64 {
+                        (0xd2, 4)
+                    } else {
+                        (0xd3, 8)
+                    };
+                    self.out.push(marker);
+                    self.out.extend_from_slice(&i.to_be_bytes()[(8 - width)..]);
+                }
+            }
+        }
+    }
+
+    fn write_bytes(&mut self, s: &[u8]) -> Result<()> {
+        match self.packing {
+            Packing::Cbor => self.cbor_head(2, s.len() as u64),
+            Packing::MsgPack => self.msgpack_head(None, Some(0xc4), 0xc5, s.len())?,
+        }
+        self.out.extend_from_slice(s);
+        Ok(())
+    }
+
+    fn write_array(&mut self, l: usize) -> Result<()> {
+        match self.packing {
+            Packing::Cbor => self.cbor_head(4, l as u64),
+            Packing::MsgPack => self.msgpack_head(Some((0x90, 15)), None, 0xdc, l)?,
+        }
+        Ok(())
+    }
+
+    fn write_map(&mut self, l: usize) -> Result<()> {
+        match self.packing {
+            Packing::Cbor => self.cbor_head(5, l as u64),
+            Packing::MsgPack => self.msgpack_head(Some((0x80, 15)), None, 0xde, l)?,
+        }
+        Ok(())
+    }
+
+    // `f` writes the two elements of the wrapped array
+    fn write_ext<F: FnOnce(&mut Self) -> Result<()>>(&mut self, code: u8, f: F) -> Result<()> {
+        match self.packing {
+            Packing::Cbor => {
+                self.cbor_head(6, CBOR_TAG_BASE + code as u64);
+                self.write_array(2)?;
+                f(self)
+            },
+            // the length comes first, so the payload is written aside
+            Packing::MsgPack => {
+                let outer = std::mem::take(&mut self.out);
+                self.write_array(2)?;
+                let r = f(self);
+                let payload = std::mem::replace(&mut self.out, outer);
+                r?;
+                match payload.len() {
+                    1 => self.out.push(0xd4),
+                    2 => self.out.push(0xd5),
+                    4 => self.out.push(0xd6),
+                    8 => self.out.push(0xd7),
+                    16 => self.out.push(0xd8),
+                    l => self.msgpack_head(None, Some(0xc7), 0xc8, l)?,
+                }
+                self.out.push(code);
+                self.out.extend_from_slice(&payload);
+                Ok(())
+            }
+        }
+    }
+
+    fn write_var(&mut self, var: &PhpVar) -> Result<()> {
+        match var {
+            PhpVar::Null => self.out.push(if self.packing == Packing::Cbor { 0xf6 } else { 0xc0 }),
+            PhpVar::Bool(b) => self.out.push(match (self.packing, b) {
+                (Packing::Cbor, false) => 0xf4,
+                (Packing::Cbor, true) => 0xf5,
+                (Packing::MsgPack, false) => 0xc2,
+                (Packing::MsgPack, true) => 0xc3,
+            }),
+            PhpVar::Int(i) => self.write_int(*i),
+            PhpVar::Float(f) => {
+                self.out.push(if self.packing == Packing::Cbor { 0xfb } else { 0xcb });
+                self.out.extend_from_slice(&f.to_bits().to_be_bytes());
+            },
+            PhpVar::String(s) => self.write_bytes(s)?,
+            PhpVar::Array(arr) => {
+                self.write_map(arr.len())?;
+                for (k, v) in arr {
+                    match k {
+                        PhpKey::Int(i) => self.write_int(*i),
+                        PhpKey::String(s) => self.write_bytes(s)?,
+                    }
+                    self.write_var(v)?;
+                }
+            },
+            PhpVar::Object { class, props } => {
+                let (class, props) = match var.incomplete_class() {
+                    Some(name) => (name, &props[1..]),
+                    None => (class.as_slice(), &props[..]),
+                };
+                self.write_ext(OBJECT, |w| {
+                    w.write_bytes(class)?;
+                    w.write_map(props.len())?;
+                    for (name, v) in props {
+                        w.write_bytes(name)?;
+                        w.write_var(v)?;
+                    }
+                    Ok(())
+                })?;
+            },
+            PhpVar::Custom { class, data } => self.write_ext(CUSTOM, |w| {
+                w.write_bytes(class)?;
+                w.write_bytes(data)
+            })?,
+            PhpVar::Enum { class, case } => self.write_ext(ENUM, |w| {
+                w.write_bytes(class)?;
+                w.write_bytes(case)
+            })?,
+            PhpVar::Ref(kind, v) => {
+                let kind = (*kind == RefKind::Shared) as i64;
+                let ptr = Rc::as_ptr(v);
+                match self.seen.get(&ptr) {
+                    Some(&n) => self.write_ext(BACKREF, |w| {
+                        w.write_int(kind);
+                        w.write_int(n as i64);
+                        Ok(())
+                    })?,
+                    None => {
+                        self.seen.insert(ptr, self.refs);
+                        self.refs += 1;
+                        self.write_ext(REF, |w| {
+                            w.write_int(kind);
+                            w.write_var(&v.borrow())
+                        })?;
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+// `var` with the header that `unpack` and `Packing::detect` look for
+pub fn pack(var: &PhpVar, packing: Packing) -> Result<Vec<u8>> {
+    let mut packer = Packer {
+        out: vec![MAGIC, packing.letter(), VERSION],
+        packing,
+        refs: 0,
+        seen: HashMap::new(),
+    };
+    packer.write_var(var)?;
+    Ok(packer.out)
+}
+
+#[cfg(test)]
+use super::{serialize, try_unserialize, AllowedClasses};
+
+#[test]
+fn test_pack() -> Result<()> {
+    // a PHP value and its msgpack and CBOR packing, without the header
+    let cases: &[(&[u8], &[u8], &[u8])] = &[
+        (b"N;", b"\xc0", b"\xf6"),
+        (b"b:1;", b"\xc3", b"\xf5"),
+        (b"i:-1;", b"\xff", b"\x20"),
+        (b"i:300;", b"\xcd\x01\x2c", b"\x19\x01\x2c"),
+        (b"i:-9223372036854775808;", b"\xd3\x80\x00\x00\x00\x00\x00\x00\x00", b"\x3b\x7f\xff\xff\xff\xff\xff\xff\xff"),
+        (b"d:0.5;", b"\xcb\x3f\xe0\x00\x00\x00\x00\x00\x00", b"\xfb\x3f\xe0\x00\x00\x00\x00\x00\x00"),
+        (b"s:3:\"foo\";", b"\xc4\x03foo", b"\x43foo"),
+        (b"a:1:{i:0;b:1;}", b"\x81\x00\xc3", b"\xa1\x00\xf5"),
+        (b"O:3:\"Foo\":1:{s:1:\"a\";i:1;}",
+         b"\xc7\x0b\x01\x92\xc4\x03Foo\x81\xc4\x01a\x01", b"\xda\x70\x68\x70\x01\x82\x43Foo\xa1\x41a\x01"),
+        // `$a = 1; $b = [&$a, &$a];`
+        (b"a:2:{i:0;i:1;i:1;R:2;}",
+         b"\x82\x00\xc7\x03\x04\x92\x01\x01\x01\xc7\x03\x05\x92\x01\x00",
+         b"\xa2\x00\xda\x70\x68\x70\x04\x82\x01\x01\x01\xda\x70\x68\x70\x05\x82\x01\x00"),
+    ];
+    for (php, msgpack, cbor) in cases {
+        let var = try_unserialize(php).unwrap();
+        for (packing, body) in &[(Packing::MsgPack, msgpack), (Packing::Cbor, cbor)] {
+            let mut packed = vec![0xc1, packing.letter(), 1];
+            packed.extend_from_slice(body);
+            assert_eq!(pack(&var, *packing)?, packed, "{}", String::from_utf8_lossy(php));
+            assert_eq!(Packing::detect(&packed), Some(*packing));
+            assert_eq!(serialize(&unpack(&packed).unwrap())?, php.to_vec());
+        }
+    }
+
+    let cases: &[&[u8]] = &[
+        b"a:3:{i:-5;d:-1.5E+25;s:1:\"x\";a:1:{s:0:\"\";a:0:{}}i:10;s:300:\"",
+        b"a:2:{i:0;O:3:\"Foo\":0:{}i:1;r:2;}",
+        b"a:2:{i:0;a:1:{i:0;i:1;}i:1;R:2;}",
+        b"a:3:{i:0;C:3:\"Foo\":2:{ab}i:1;E:7:\"Foo:Bar\";i:2;i:9223372036854775807;}",
+    ];
+    let long = [b'y'; 300];
+    for php in cases {
+        let php = if php.ends_with(b"\"") { [php, &long[..], b"\";}"].concat() } else { php.to_vec() };
+        let var = try_unserialize(&php).unwrap();
+        for packing in &[Packing::MsgPack, Packing::Cbor] {
+            assert_eq!(serialize(&unpack(&pack(&var, *packing)?).unwrap())?, php);
+        }
+    }
+
+    // neither another version nor a CBOR epoch time is taken for a payload
+    assert_eq!(Packing::detect(b"\xc1M\x02\xc0"), None);
+    assert_eq!(Packing::detect(b"\xc1\x1a\x5f\x5e\x10\x00"), None);
+    assert_eq!(Packing::detect(b"\xc1C"), None);
+
+    // lists and text strings written by other encoders read like PHP arrays
+    let var = unpack(b"\xc1M\x01\x92\xa1a\x01").unwrap();
+    assert_eq!(serialize(&var)?, b"a:2:{i:0;s:1:\"a\";i:1;i:1;}".to_vec());
+    let var = unpack(b"\xc1C\x01\x82\x61a\x01").unwrap();
+    assert_eq!(serialize(&var)?, b"a:2:{i:0;s:1:\"a\";i:1;i:1;}".to_vec());
+    Ok(())
+}
+
+#[test]
+fn test_unpack_errors() -> Result<()> {
+    let cases: &[(&[u8], usize, UnserializeErrorKind)] = &[
+        (b"", 0, UnserializeErrorKind::Truncated),
+        (b"\xc1M\x02\xc0", 0, UnserializeErrorKind::Malformed),
+        (b"\xc1X\x01\xc0", 0, UnserializeErrorKind::Malformed),
+        (b"\xc1M\x01\xc0\xc0", 4, UnserializeErrorKind::TrailingData),
+        (b"\xc1M\x01\xc4\x03fo", 7, UnserializeErrorKind::Truncated),
+        (b"\xc1M\x01\xc1", 3, UnserializeErrorKind::UnknownType),
+        (b"\xc1M\x01\xcf\xff\xff\xff\xff\xff\xff\xff\xff", 3, UnserializeErrorKind::Malformed),
+        (b"\xc1M\x01\xc7\x03\x05\x92\x01\x00", 3, UnserializeErrorKind::DanglingReference),
+        (b"\xc1M\x01\xc7\x0a\x01\x92\xc4\x03Foo\x81\xc4\x01a\x01", 3, UnserializeErrorKind::BadLength),
+        (b"\xc1M\x01\xc7\x03\x09\x92\x01\x00", 3, UnserializeErrorKind::UnknownType),
+        (b"\xc1M\x01\xdd\xff\xff\xff\xff", 3, UnserializeErrorKind::LimitExceeded(Limit::Elements)),
+        (b"\xc1C\x01\x9f\xff", 3, UnserializeErrorKind::Malformed),
+        (b"\xc1C\x01\xc6\x00", 3, UnserializeErrorKind::UnknownType),
+        (b"\xc1C\x01\xa1\xf6\x00", 4, UnserializeErrorKind::UnknownType),
+    ];
+    for (raw, offset, kind) in cases {
+        let err = unpack(raw).unwrap_err();
+        assert_eq!((err.offset, err.kind), (*offset, *kind), "{:?}", raw);
+    }
+
+    let opts = UnserializeOptions { allowed_classes: AllowedClasses::Only(vec![]), ..Default::default() };
+    let custom = PhpVar::Custom { class: b"Foo".to_vec(), data: b"ab".to_vec() };
+    let var = unpack_with(&pack(&custom, Packing::Cbor)?, &opts).unwrap();
+    assert_eq!(var.incomplete_class(), Some(&b"Foo"[..]));
+    let case = PhpVar::Enum { class: b"Foo".to_vec(), case: b"Bar".to_vec() };
+    let err = unpack_with(&pack(&case, Packing::MsgPack)?, &opts).unwrap_err();
+    assert_eq!(err.kind, UnserializeErrorKind::DisallowedClass);
+    let opts = UnserializeOptions { max_depth: 1, ..Default::default() };
+    let err = unpack_with(b"\xc1M\x01\x81\x00\x80", &opts).unwrap_err();
+    assert_eq!((err.offset, err.kind), (5, UnserializeErrorKind::LimitExceeded(Limit::Depth)));
+    Ok(())
+}
@@ -0,0 +1,304 @@
+use std::mem;
+
+use crate::errors::*;
+use super::{PhpArray, PhpKey, PhpVar, RefKind};
+
+// a path is a first name followed by `.name` and `[key]` steps, e.g.
+// `accounts[3].secret`. keys are cast like PHP casts array keys, so
+// `a.3`, `a[3]` and `a["3"]` are all the integer key 3. quoted keys may
+// hold `.`, `[` and `]` with `\"` and `\\` escaped, and `[]` appends
+// (only when setting). names select public properties of objects.
+#[derive(Debug, PartialEq)]
+enum Step {
+    Key(PhpKey),
+    Append,
+}
+
+fn parse(path: &str) -> Result<Vec<Step>> {
+    let s = path.as_bytes();
+    let mut steps = vec![];
+    let mut i = 0;
+    while i < s.len() {
+        match s[i] {
+            b'[' => {
+                i += 1;
+                let key = match s.get(i) {
+                    Some(b']') => {
+                        steps.push(Step::Append);
+                        i += 1;
+                        continue;
+                    },
+                    Some(&q) if q == b'"' || q == b'\'' => {
+                        let mut key = vec![];
+                        i += 1;
+                        loop {
+                            match s.get(i) {
+                                Some(b'\\') if i + 1 < s.len() => {
+                                    key.push(s[i + 1]);
+                                    i += 2;
+                                },
+                                Some(&c) if c == q => break,
+                                Some(&c) => {
+                                    key.push(c);
+                                    i += 1;
+                                },
+                                None => bail!("unterminated key in path {:?}", path)
+                            }
+                        }
+                        i += 1;
+                        key
+                    },
+                    _ => {
+                        let end = s[i..].iter().position(|c| *c == b']').map_or(s.len(), |l| i + l);
+                        let key = s[i..end].to_vec();
+                        i = end;
+                        key
+                    }
+                };
+                if s.get(i) != Some(&b']') {
+                    bail!("expected ']' at {} in path {:?}", i, path);
+                }
+                i += 1;
+                steps.push(Step::Key(PhpKey::from_bytes(&key)));
+            },
+            c => {
+                // a name: the first step or one after a `.`
+                if c == b'.' && i > 0 {
+                    i += 1;
+                } else if c == b'.' || i > 0 {
+                    bail!("expected '.' or '[' at {} in path {:?}", i, path);
+                }
+                let end = s[i..].iter().position(|c| *c == b'.' || *c == b'[' || *c == b']').map_or(s.len(), |l| i + l);
+                if end == i {
+                    bail!("empty name at {} in path {:?}", i, path);
+                }
+                steps.push(Step::Key(PhpKey::from_bytes(&s[i..end])));
+                i = end;
+            }
+        }
+    }
+    Ok(steps)
+}
+
+fn prop_name(key: &PhpKey) -> Vec<u8> {
+    match key {
+        PhpKey::Int(i) => i.to_string().into_bytes(),
+        PhpKey::String(s) => s.clone(),
+    }
+}
+
+fn child<'v>(var: &'v PhpVar, key: &PhpKey) -> Option<&'v PhpVar> {
+    match var {
+        PhpVar::Array(arr) => arr.get(key),
+        PhpVar::Object { props, .. } => {
+            let name = prop_name(key);
+            props.iter().find(|(k, _)| *k == name).map(|(_, v)| v)
+        },
+        _ => None
+    }
+}
+
+fn child_mut<'v>(var: &'v mut PhpVar, key: &PhpKey) -> Option<&'v mut PhpVar> {
+    match var {
+        PhpVar::Array(arr) => arr.get_mut(key),
+        PhpVar::Object { props, .. } => {
+            let name = prop_name(key);
+            props.iter_mut().find(|(k, _)| *k == name).map(|(_, v)| v)
+        },
+        _ => None
+    }
+}
+
+// references are followed transparently, whatever their kind. a cell that
+// is already borrowed (a reference cycle built by hand) ends the lookup
+fn get<T, F: FnOnce(&PhpVar) -> T>(var: &PhpVar, steps: &[Step], f: F) -> Option<T> {
+    if let PhpVar::Ref(_, cell) = var {
+        return get(&*cell.try_borrow().ok()?, steps, f);
+    }
+    match steps.split_first() {
+        None => Some(f(var)),
+        Some((Step::Key(key), rest)) => get(child(var, key)?, rest, f),
+        Some((Step::Append, _)) => None,
+    }
+}
+
+// an `r:` slot only shares its cell until it is written to: from then on
+// it holds a copy of its own, like `$b = $a` does in PHP. unserialize only
+// leaves such cells for objects, but a var built in code can hold any. objects
+// are handles, so writes to them are seen by every holder either way
+fn detach(var: &mut PhpVar) -> Result<()> {
+    if let PhpVar::Ref(RefKind::Value, cell) = var {
+        let copy = match cell.try_borrow() {
+            Ok(v) if matches!(*v, PhpVar::Object { .. } | PhpVar::Custom { .. } | PhpVar::Enum { .. }) => return Ok(()),
+            Ok(v) => v.clone(),
+            Err(_) => bail!("reference cycle in path")
+        };
+        *var = copy;
+    }
+    Ok(())
+}
+
+fn get_mut<T, F: FnOnce(&mut PhpVar) -> T>(var: &mut PhpVar, steps: &[Step], f: F) -> Option<T> {
+    detach(var).ok()?;
+    if let PhpVar::Ref(_, cell) = var {
+        return get_mut(&mut *cell.try_borrow_mut().ok()?, steps, f);
+    }
+    match steps.split_first() {
+        None => Some(f(var)),
+        Some((Step::Key(key), rest)) => get_mut(child_mut(var, key)?, rest, f),
+        Some((Step::Append, _)) => None,
+    }
+}
+
+// `$var[a][b] = value`: missing entries and nulls on the way become
+// arrays, anything else that is not an array or object is an error
+fn set(var: &mut PhpVar, steps: &[Step], value: PhpVar) -> Result<Option<PhpVar>> {
+    detach(var)?;
+    if let PhpVar::Ref(_, cell) = var {
+        let mut cell = match cell.try_borrow_mut() {
+            Ok(cell) => cell,
+            Err(_) => bail!("reference cycle in path")
+        };
+        return set(&mut cell, steps, value);
+    }
+    let (step, rest) = match steps.split_first() {
+        Some(split) => split,
+        None => return Ok(Some(mem::replace(var, value)))
+    };
+    if let PhpVar::Null = var {
+        *var = PhpVar::Array(PhpArray::new());
+    }
+    let (slot, created) = match (var, step) {
+        (PhpVar::Array(arr), Step::Key(key)) => {
+            let created = !arr.contains_key(key);
+            if created {
+                arr.insert(key, PhpVar::Null);
+            }
+            (arr.get_mut(key), created)
+        },
+        (PhpVar::Array(arr), Step::Append) => {
+            let key = arr.push(PhpVar::Null)?;
+            (arr.get_mut(key), true)
+        },
+        (PhpVar::Object { props, .. }, Step::Key(key)) => {
+            let name = prop_name(key);
+            let (i, created) = match props.iter().position(|(k, _)| *k == name) {
+                Some(i) => (i, false),
+                None => {
+                    props.push((name, PhpVar::Null));
+                    (props.len() - 1, true)
+                }
+            };
+            (props.get_mut(i).map(|(_, v)| v), created)
+        },
+        (PhpVar::Object { .. }, Step::Append) => bail!("cannot append to an object"),
+        (var, _) => bail!("cannot use {:?} as an array", var)
+    };
+    let old = match slot {
+        Some(slot) => set(slot, rest, value)?,
+        None => bail!(ErrorKind::Unknown)
+    };
+    Ok(if created { None } else { old })
+}
+
+impl PhpVar {
+    // calls `f` with the var at `path`, if there is one
+    pub fn pointer<T, F: FnOnce(&PhpVar) -> T>(&self, path: &str, f: F) -> Option<T> {
+        get(self, &parse(path).ok()?, f)
+    }
+
+    // the same with the var borrowed mutably. writes through `R:` slots and
+    // to objects are seen by every other holder, `r:` copies of anything
+    // else on the way are detached first and only this one changes
+    pub fn pointer_mut<T, F: FnOnce(&mut PhpVar) -> T>(&mut self, path: &str, f: F) -> Option<T> {
+        get_mut(self, &parse(path).ok()?, f)
+    }
+
+    // sets the var at `path`, creating arrays on the way like PHP does, and
+    // returns what was there before
+    pub fn set_path(&mut self, path: &str, value: PhpVar) -> Result<Option<PhpVar>> {
+        set(self, &parse(path)?, value)
+    }
+
+    // `unset()`: removes the var at `path` and returns it
+    pub fn remove_path(&mut self, path: &str) -> Option<PhpVar> {
+        let mut steps = parse(path).ok()?;
+        let key = match steps.pop()? {
+            Step::Key(key) => key,
+            Step::Append => return None,
+        };
+        get_mut(self, &steps, |parent| match parent {
+            PhpVar::Array(arr) => arr.remove(&key),
+            PhpVar::Object { props, .. } => {
+                let name = prop_name(&key);
+                let i = props.iter().position(|(k, _)| *k == name)?;
+                Some(props.remove(i).1)
+            },
+            _ => None
+        })?
+    }
+}
+
+#[cfg(test)]
+use super::{unserialize_with, serialize, UnserializeOptions};
+
+#[test]
+fn test_parse_path() {
+    let key = |s: &str| Step::Key(PhpKey::from(s));
+    assert_eq!(parse("").unwrap(), vec![]);
+    assert_eq!(parse("a").unwrap(), vec![key("a")]);
+    assert_eq!(parse("accounts[3].secret").unwrap(), vec![key("accounts"), Step::Key(PhpKey::Int(3)), key("secret")]);
+    assert_eq!(parse("a.3").unwrap(), parse("a[3]").unwrap());
+    assert_eq!(parse("a[\"3\"]").unwrap(), parse("a[3]").unwrap());
+    assert_eq!(parse("a[03]").unwrap(), vec![key("a"), Step::Key(PhpKey::String(b"03".to_vec()))]);
+    assert_eq!(parse("[\"a.b\"]['c\\'d'][]").unwrap(), vec![key("a.b"), key("c'd"), Step::Append]);
+    assert_eq!(parse("[-1][x y]").unwrap(), vec![Step::Key(PhpKey::Int(-1)), key("x y")]);
+    for path in &["a.", ".a", "a..b", "a[", "a[1", "a[\"1]", "a[1]b", "a]"] {
+        assert!(parse(path).is_err(), "{}", path);
+    }
+}
+
+#[test]
+fn test_path_query() -> Result<()> {
+    let raw = b"a:2:{s:8:\"accounts\";a:2:{i:3;a:1:{s:6:\"secret\";s:3:\"abc\";}i:4;R:3;}s:4:\"meta\";O:8:\"stdClass\":1:{s:6:\"issuer\";s:4:\"babi\";}}";
+    let mut var = unserialize_with(raw, &UnserializeOptions::default()).unwrap();
+    assert_eq!(var.pointer("accounts[3].secret", |v| v.clone()), Some(PhpVar::String(b"abc".to_vec())));
+    assert_eq!(var.pointer("accounts.4[\"secret\"]", |v| v.clone()), Some(PhpVar::String(b"abc".to_vec())));
+    assert_eq!(var.pointer("meta.issuer", |v| v.to_string()), Some("babi".to_string()));
+    assert_eq!(var.pointer("accounts[5]", |_| ()), None);
+    assert_eq!(var.pointer("meta.issuer.x", |_| ()), None);
+    assert_eq!(var.pointer("accounts[", |_| ()), None);
+
+    // accounts[4] is a reference to accounts[3]
+    var.pointer_mut("accounts[4].secret", |v| *v = PhpVar::String(b"xyz".to_vec()));
+    assert_eq!(var.pointer("accounts[3].secret", |v| v.to_string()), Some("xyz".to_string()));
+    assert_eq!(var.set_path("accounts[3].secret", PhpVar::Int(1))?, Some(PhpVar::String(b"xyz".to_vec())));
+    assert_eq!(var.pointer("accounts[4].secret", |v| v.clone()), Some(PhpVar::Int(1)));
+
+    assert_eq!(var.set_path("meta.issuer", PhpVar::Null)?, Some(PhpVar::String(b"babi".to_vec())));
+    assert_eq!(var.set_path("meta.port", PhpVar::Int(80))?, None);
+    assert_eq!(var.set_path("new[x][]", PhpVar::Int(1))?, None);
+    assert_eq!(var.set_path("new[x][]", PhpVar::Int(2))?, None);
+    let removed = var.remove_path("accounts.4").unwrap();
+    assert_eq!(removed.pointer("secret", |v| v.clone()), Some(PhpVar::Int(1)));
+    assert_eq!(var.remove_path("accounts.4"), None);
+    assert_eq!(var.remove_path("meta.issuer"), Some(PhpVar::Null));
+    assert_eq!(serialize(&var)?, b"a:3:{s:8:\"accounts\";a:1:{i:3;a:1:{s:6:\"secret\";i:1;}}s:4:\"meta\";O:8:\"stdClass\":1:{s:4:\"port\";i:80;}s:3:\"new\";a:1:{s:1:\"x\";a:2:{i:0;i:1;i:1;i:2;}}}".to_vec());
+
+    assert!(var.set_path("accounts[3].secret.x", PhpVar::Null).is_err());
+    assert!(var.set_path("meta[]", PhpVar::Null).is_err());
+    assert!(var.set_path("a..b", PhpVar::Null).is_err());
+
+    // r: copies part ways when one of them is written to, objects do not
+    let raw = b"a:4:{i:0;a:1:{s:1:\"k\";s:1:\"v\";}i:1;r:2;i:2;O:8:\"stdClass\":1:{s:1:\"k\";i:1;}i:3;r:5;}";
+    let mut var = unserialize_with(raw, &UnserializeOptions::default()).unwrap();
+    assert_eq!(var.set_path("1.k", PhpVar::String(b"w".to_vec()))?, Some(PhpVar::String(b"v".to_vec())));
+    assert_eq!(var.pointer("0.k", |v| v.to_string()), Some("v".to_string()));
+    var.pointer_mut("0.k", |v| *v = PhpVar::String(b"x".to_vec()));
+    assert_eq!(var.pointer("1.k", |v| v.to_string()), Some("w".to_string()));
+    assert_eq!(var.set_path("3.k", PhpVar::Int(2))?, Some(PhpVar::Int(1)));
+    assert_eq!(var.pointer("2.k", |v| v.clone()), Some(PhpVar::Int(2)));
+    assert_eq!(serialize(&var)?, b"a:4:{i:0;a:1:{s:1:\"k\";s:1:\"x\";}i:1;a:1:{s:1:\"k\";s:1:\"w\";}\
+i:2;O:8:\"stdClass\":1:{s:1:\"k\";i:2;}i:3;r:6;}".to_vec());
+    Ok(())
+}
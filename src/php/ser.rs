@@ -0,0 +1,345 @@
+use std::fmt::Display;
+use serde::ser::{self, Serialize};
+
+use crate::errors::*;
+use super::{serialize, PhpArray, PhpKey, PhpVar};
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        ErrorKind::Msg(msg.to_string()).into()
+    }
+}
+
+// whether structs become `O:<name>` objects or plain string keyed arrays
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StructFormat {
+    Object,
+    Array,
+}
+
+// builds a `PhpVar` tree out of any `Serialize` value
+#[derive(Clone, Copy, Debug)]
+pub struct Serializer {
+    structs: StructFormat,
+}
+
+impl Serializer {
+    pub fn new(structs: StructFormat) -> Self {
+        Serializer { structs }
+    }
+}
+
+impl Default for Serializer {
+    fn default() -> Self {
+        Serializer::new(StructFormat::Object)
+    }
+}
+
+pub fn to_var<T: ?Sized + Serialize>(value: &T) -> Result<PhpVar> {
+    value.serialize(Serializer::default())
+}
+
+pub fn to_vec_with<T: ?Sized + Serialize>(value: &T, structs: StructFormat) -> Result<Vec<u8>> {
+    serialize(&value.serialize(Serializer::new(structs))?)
+}
+
+pub fn to_vec<T: ?Sized + Serialize>(value: &T) -> Result<Vec<u8>> {
+    to_vec_with(value, StructFormat::Object)
+}
+
+// enum variants with data are externally tagged: `[variant => data]`
+fn tagged(variant: &'static str, v: PhpVar) -> PhpVar {
+    let mut arr = PhpArray::new();
+    arr.insert(variant, v);
+    PhpVar::Array(arr)
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = PhpVar;
+    type Error = Error;
+
+    type SerializeSeq = SeqSerializer;
+    type SerializeTuple = SeqSerializer;
+    type SerializeTupleStruct = SeqSerializer;
+    type SerializeTupleVariant = SeqSerializer;
+    type SerializeMap = MapSerializer;
+    type SerializeStruct = StructSerializer;
+    type SerializeStructVariant = StructSerializer;
+
+    fn serialize_bool(self, v: bool) -> Result<PhpVar> {
+        Ok(PhpVar::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<PhpVar> {
+        Ok(PhpVar::Int(v as i64))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<PhpVar> {
+        Ok(PhpVar::Int(v as i64))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<PhpVar> {
+        Ok(PhpVar::Int(v as i64))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<PhpVar> {
+        Ok(PhpVar::Int(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<PhpVar> {
+        Ok(PhpVar::Int(v as i64))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<PhpVar> {
+        Ok(PhpVar::Int(v as i64))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<PhpVar> {
+        Ok(PhpVar::Int(v as i64))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<PhpVar> {
+        if v > i64::MAX as u64 {
+            bail!("integer {} does not fit a PHP int", v)
+        }
+        Ok(PhpVar::Int(v as i64))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<PhpVar> {
+        Ok(PhpVar::Float(v as f64))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<PhpVar> {
+        Ok(PhpVar::Float(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<PhpVar> {
+        Ok(PhpVar::String(v.to_string().into_bytes()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<PhpVar> {
+        Ok(PhpVar::String(v.as_bytes().to_vec()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<PhpVar> {
+        Ok(PhpVar::String(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<PhpVar> {
+        Ok(PhpVar::Null)
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<PhpVar> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<PhpVar> {
+        Ok(PhpVar::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<PhpVar> {
+        Ok(PhpVar::Null)
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<PhpVar> {
+        Ok(PhpVar::String(variant.as_bytes().to_vec()))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str, value: &T) -> Result<PhpVar> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(self, _name: &'static str, _index: u32,
+                                                       variant: &'static str, value: &T) -> Result<PhpVar> {
+        Ok(tagged(variant, value.serialize(self)?))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SeqSerializer> {
+        Ok(SeqSerializer { ser: self, arr: PhpArray::new(), variant: None })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SeqSerializer> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SeqSerializer> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, variant: &'static str,
+                               _len: usize) -> Result<SeqSerializer> {
+        Ok(SeqSerializer { ser: self, arr: PhpArray::new(), variant: Some(variant) })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapSerializer> {
+        Ok(MapSerializer { ser: self, arr: PhpArray::new(), key: None })
+    }
+
+    fn serialize_struct(self, name: &'static str, _len: usize) -> Result<StructSerializer> {
+        Ok(StructSerializer { ser: self, name, props: vec![], variant: None })
+    }
+
+    fn serialize_struct_variant(self, name: &'static str, _index: u32, variant: &'static str,
+                                _len: usize) -> Result<StructSerializer> {
+        Ok(StructSerializer { ser: self, name, props: vec![], variant: Some(variant) })
+    }
+}
+
+pub struct SeqSerializer {
+    ser: Serializer,
+    arr: PhpArray,
+    variant: Option<&'static str>,
+}
+
+impl SeqSerializer {
+    fn push<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.arr.push(value.serialize(self.ser)?)?;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<PhpVar> {
+        let v = PhpVar::Array(self.arr);
+        Ok(match self.variant {
+            Some(variant) => tagged(variant, v),
+            None => v
+        })
+    }
+}
+
+impl ser::SerializeSeq for SeqSerializer {
+    type Ok = PhpVar;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<PhpVar> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SeqSerializer {
+    type Ok = PhpVar;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<PhpVar> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqSerializer {
+    type Ok = PhpVar;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<PhpVar> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SeqSerializer {
+    type Ok = PhpVar;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<PhpVar> {
+        self.finish()
+    }
+}
+
+pub struct MapSerializer {
+    ser: Serializer,
+    arr: PhpArray,
+    key: Option<PhpKey>,
+}
+
+impl ser::SerializeMap for MapSerializer {
+    type Ok = PhpVar;
+    type Error = Error;
+
+    // keys go through PHP's array key casts, so anything but arrays and
+    // objects is accepted
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<()> {
+        self.key = Some(PhpKey::from_var(&key.serialize(self.ser)?)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        match self.key.take() {
+            Some(key) => {
+                self.arr.insert(key, value.serialize(self.ser)?);
+                Ok(())
+            },
+            None => bail!(ErrorKind::Invalid)
+        }
+    }
+
+    fn end(self) -> Result<PhpVar> {
+        Ok(PhpVar::Array(self.arr))
+    }
+}
+
+pub struct StructSerializer {
+    ser: Serializer,
+    name: &'static str,
+    props: Vec<(Vec<u8>, PhpVar)>,
+    variant: Option<&'static str>,
+}
+
+impl StructSerializer {
+    fn field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<()> {
+        self.props.push((key.as_bytes().to_vec(), value.serialize(self.ser)?));
+        Ok(())
+    }
+
+    fn finish(self) -> Result<PhpVar> {
+        let v = match self.ser.structs {
+            StructFormat::Object => PhpVar::Object {
+                class: self.name.as_bytes().to_vec(),
+                props: self.props,
+            },
+            StructFormat::Array => PhpVar::Array(self.props.into_iter().collect()),
+        };
+        Ok(match self.variant {
+            Some(variant) => tagged(variant, v),
+            None => v
+        })
+    }
+}
+
+impl ser::SerializeStruct for StructSerializer {
+    type Ok = PhpVar;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<()> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<PhpVar> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for StructSerializer {
+    type Ok = PhpVar;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<()> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<PhpVar> {
+        self.finish()
+    }
+}
@@ -0,0 +1,257 @@
+use crate::errors::*;
+use super::borrowed::RefBuilder;
+use super::igbinary::{igbinary_nodes, igbinary_serialize, igbinary_unserialize_with};
+use super::packed::{pack, packed_nodes, unpack_with, Packing};
+use super::{parse, serialize, unserialize_ref_with, Limit, Parsed, Parser, PhpArray, PhpKey, PhpKeyRef, PhpValueRef, PhpVar,
+            UnserializeError, UnserializeErrorKind, UnserializeOptions, Writer};
+
+// `session.serialize_handler`: how the vars of a session are laid out in
+// the session file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionHandler {
+    // `name|<var>name|<var>`, PHP's default
+    Php,
+    // `<name length byte>name<var>` with names of at most 127 bytes
+    PhpBinary,
+    // the whole session as one serialized array
+    PhpSerialize,
+    // the same, in igbinary's format
+    Igbinary,
+    // the same again as msgpack or CBOR, see `pack`
+    MsgPack,
+    Cbor,
+}
+
+// php_binary names of 128 bytes and more would need the high bit, which
+// older PHP versions used to flag a var as unset
+const BINARY_NAME_MAX: usize = 127;
+
+impl SessionHandler {
+    // `session.serialize_handler` names, plus ours for the packed formats
+    pub fn from_name(name: &str) -> Option<SessionHandler> {
+        match name {
+            "php" => Some(SessionHandler::Php),
+            "php_binary" => Some(SessionHandler::PhpBinary),
+            "php_serialize" => Some(SessionHandler::PhpSerialize),
+            "igbinary" => Some(SessionHandler::Igbinary),
+            "msgpack" => Some(SessionHandler::MsgPack),
+            "cbor" => Some(SessionHandler::Cbor),
+            _ => None
+        }
+    }
+
+    // igbinary and the packed formats have a header, the others go by their
+    // first bytes: a serialized var starts with a type tag and `:` (or is
+    // `N;`), and a php_binary name length is practically always a control
+    // character
+    pub fn detect(raw: &[u8]) -> SessionHandler {
+        match raw {
+            [0, 0, 0, 1, ..] | [0, 0, 0, 2, ..] => SessionHandler::Igbinary,
+            _ if Packing::detect(raw) == Some(Packing::MsgPack) => SessionHandler::MsgPack,
+            _ if Packing::detect(raw) == Some(Packing::Cbor) => SessionHandler::Cbor,
+            [b'N', b';', ..] => SessionHandler::PhpSerialize,
+            [b'a', b':', ..] | [b'b', b':', ..] | [b'i', b':', ..] | [b'd', b':', ..] | [b's', b':', ..] |
+            [b'O', b':', ..] | [b'C', b':', ..] | [b'E', b':', ..] => SessionHandler::PhpSerialize,
+            [c, ..] if *c < 0x20 => SessionHandler::PhpBinary,
+            _ => SessionHandler::Php,
+        }
+    }
+}
+
+// session var names with the arena ids of their values
+type Vars<'a> = Vec<(&'a [u8], usize)>;
+
+// reads `name` + var pairs into the arena; the vars share their slot
+// numbers, so a later var may refer to an earlier one
+fn parse_vars<'a, 'o>(raw: &'a [u8], handler: SessionHandler, opts: &'o UnserializeOptions)
+                      -> Parsed<(Parser<'a, 'o>, Vars<'a>)> {
+    let mut parser = Parser::new(raw, opts);
+    if raw.len() > opts.max_input_len {
+        return parser.fail(opts.max_input_len, UnserializeErrorKind::LimitExceeded(Limit::InputLength), "shorter input");
+    }
+    let mut vars = vec![];
+    while parser.cur < parser.len {
+        let name = match handler {
+            SessionHandler::PhpBinary => {
+                let l = parser.read_byte("name length")? as usize;
+                if l > BINARY_NAME_MAX {
+                    return parser.fail(parser.cur - 1, UnserializeErrorKind::Malformed, "name length");
+                }
+                parser.read_bytes(l)?
+            },
+            _ => match raw[parser.cur..].iter().position(|c| *c == b'|') {
+                Some(i) => {
+                    let name = &raw[parser.cur..(parser.cur + i)];
+                    parser.cur += i + 1;
+                    name
+                },
+                None => return parser.fail(parser.len, UnserializeErrorKind::Truncated, "'|'")
+            }
+        };
+        let id = parser.read_var()?;
+        vars.push((name, id));
+    }
+    Ok((parser, vars))
+}
+
+fn not_an_array<T>() -> ::std::result::Result<T, UnserializeError> {
+    Err(UnserializeError { offset: 0, expected: "array", kind: UnserializeErrorKind::Malformed })
+}
+
+// session_decode(): the session vars by name, later vars of the same name
+// replacing earlier ones
+pub fn session_decode(raw: &[u8], handler: SessionHandler, opts: &UnserializeOptions)
+                      -> ::std::result::Result<PhpArray, UnserializeError> {
+    if handler == SessionHandler::PhpSerialize {
+        return match *parse(raw, opts)? {
+            PhpVar::Array(arr) => Ok(arr),
+            _ => not_an_array()
+        };
+    }
+    if handler == SessionHandler::Igbinary {
+        return match igbinary_unserialize_with(raw, opts)? {
+            PhpVar::Array(arr) => Ok(arr),
+            _ => not_an_array()
+        };
+    }
+    if handler == SessionHandler::MsgPack || handler == SessionHandler::Cbor {
+        return match unpack_with(raw, opts)? {
+            PhpVar::Array(arr) => Ok(arr),
+            _ => not_an_array()
+        };
+    }
+    let (mut parser, vars) = parse_vars(raw, handler, opts)?;
+    let mut arr = PhpArray::new();
+    for (name, id) in vars {
+        arr.insert(PhpKey::from_bytes(name), *parser.build(id)?);
+    }
+    Ok(arr)
+}
+
+// the same as `session_decode`, borrowing from `raw` like `unserialize_ref`
+pub fn session_decode_ref<'a>(raw: &'a [u8], handler: SessionHandler, opts: &UnserializeOptions)
+                              -> ::std::result::Result<PhpValueRef<'a>, UnserializeError> {
+    if handler == SessionHandler::PhpSerialize {
+        return match unserialize_ref_with(raw, opts)? {
+            v @ PhpValueRef::Array(_) => Ok(v),
+            _ => not_an_array()
+        };
+    }
+    if handler != SessionHandler::Php && handler != SessionHandler::PhpBinary {
+        let (mut parser, id) = if handler == SessionHandler::Igbinary {
+            igbinary_nodes(raw, opts)?
+        } else {
+            packed_nodes(raw, opts)?
+        };
+        return match RefBuilder::new(&mut parser).build(id)? {
+            v @ PhpValueRef::Array(_) => Ok(v),
+            _ => not_an_array()
+        };
+    }
+    let (mut parser, vars) = parse_vars(raw, handler, opts)?;
+    let mut builder = RefBuilder::new(&mut parser);
+    let mut values = Vec::with_capacity(vars.len());
+    for (name, id) in vars {
+        values.push((PhpKeyRef::from_bytes(name), builder.build(id)?));
+    }
+    Ok(builder.collapse(values))
+}
+
+// session_encode(). integer keys are written as their decimal names, which
+// `session_decode` casts right back (PHP itself drops them). php_binary
+// names that do not fit the length byte and php names with a `|` in them
+// fail the whole session with `ErrorKind::SessionVarName`, rather than
+// losing the var like PHP does.
+pub fn session_encode(vars: &PhpArray, handler: SessionHandler) -> Result<Vec<u8>> {
+    match handler {
+        SessionHandler::PhpSerialize => return serialize(&PhpVar::Array(vars.clone())),
+        SessionHandler::Igbinary => return igbinary_serialize(&PhpVar::Array(vars.clone())),
+        SessionHandler::MsgPack => return pack(&PhpVar::Array(vars.clone()), Packing::MsgPack),
+        SessionHandler::Cbor => return pack(&PhpVar::Array(vars.clone()), Packing::Cbor),
+        _ => ()
+    }
+    // one writer for all vars, so that references between them survive
+    let mut ser = Writer::new();
+    for (key, v) in vars {
+        let name = match key {
+            PhpKey::Int(i) => i.to_string().into_bytes(),
+            PhpKey::String(name) => name.clone(),
+        };
+        match handler {
+            SessionHandler::PhpBinary if name.len() > BINARY_NAME_MAX => {
+                bail!(ErrorKind::SessionVarName("too long for php_binary"))
+            },
+            SessionHandler::PhpBinary => ser.out.push(name.len() as u8),
+            _ if name.contains(&b'|') => bail!(ErrorKind::SessionVarName("contains '|'")),
+            _ => (),
+        }
+        ser.out.extend_from_slice(&name);
+        if handler == SessionHandler::Php {
+            ser.out.push(b'|');
+        }
+        ser.write_var(v)?;
+    }
+    Ok(ser.out)
+}
+
+#[test]
+fn test_session_decode() {
+    let opts = UnserializeOptions::default();
+    // php > session_start(); $_SESSION['a'] = 'x'; $_SESSION['b'] = [1]; $_SESSION['c'] = &$_SESSION['a'];
+    let php = b"a|s:1:\"x\";b|a:1:{i:0;i:1;}c|R:1;";
+    let binary = b"\x01as:1:\"x\";\x01ba:1:{i:0;i:1;}\x01cR:1;";
+    let whole = b"a:3:{s:1:\"a\";s:1:\"x\";s:1:\"b\";a:1:{i:0;i:1;}s:1:\"c\";R:2;}";
+    assert_eq!(SessionHandler::detect(php), SessionHandler::Php);
+    assert_eq!(SessionHandler::detect(binary), SessionHandler::PhpBinary);
+    assert_eq!(SessionHandler::detect(whole), SessionHandler::PhpSerialize);
+
+    let expected = session_decode(whole, SessionHandler::PhpSerialize, &opts).unwrap();
+    for (raw, handler) in &[(&php[..], SessionHandler::Php), (&binary[..], SessionHandler::PhpBinary)] {
+        let vars = session_decode(raw, *handler, &opts).unwrap();
+        assert_eq!(vars, expected);
+        assert_eq!(session_decode_ref(raw, *handler, &opts).unwrap().into_owned(), PhpVar::Array(expected.clone()));
+        assert_eq!(session_encode(&vars, *handler).unwrap(), raw.to_vec());
+    }
+
+    assert_eq!(session_decode(b"", SessionHandler::Php, &opts).unwrap(), PhpArray::new());
+    let err = session_decode(b"a|i:1;b", SessionHandler::Php, &opts).unwrap_err();
+    assert_eq!((err.offset, err.kind), (7, UnserializeErrorKind::Truncated));
+    let err = session_decode(b"\x80", SessionHandler::PhpBinary, &opts).unwrap_err();
+    assert_eq!((err.offset, err.kind), (0, UnserializeErrorKind::Malformed));
+    assert!(session_decode(b"i:1;", SessionHandler::PhpSerialize, &opts).is_err());
+
+    // the same session the way the igbinary handler stores it
+    let ig = b"\x00\x00\x00\x02\x14\x03\x11\x01a\x25\x11\x01x\x11\x01b\x14\x01\x06\x00\x06\x01\x11\x01c\x01\x01";
+    assert_eq!(SessionHandler::detect(ig), SessionHandler::Igbinary);
+    assert_eq!(session_decode(ig, SessionHandler::Igbinary, &opts).unwrap(), expected);
+    assert_eq!(session_decode_ref(ig, SessionHandler::Igbinary, &opts).unwrap().into_owned(), PhpVar::Array(expected.clone()));
+    assert_eq!(session_encode(&expected, SessionHandler::Igbinary).unwrap(), ig.to_vec());
+
+    for handler in &[SessionHandler::MsgPack, SessionHandler::Cbor] {
+        let raw = session_encode(&expected, *handler).unwrap();
+        assert_eq!(SessionHandler::detect(&raw), *handler);
+        assert_eq!(session_decode(&raw, *handler, &opts).unwrap(), expected);
+        assert_eq!(session_decode_ref(&raw, *handler, &opts).unwrap().into_owned(), PhpVar::Array(expected.clone()));
+    }
+    assert_eq!(SessionHandler::from_name("cbor"), Some(SessionHandler::Cbor));
+    assert_eq!(SessionHandler::from_name("json"), None);
+}
+
+#[test]
+fn test_session_encode() -> Result<()> {
+    let vars: PhpArray = vec![(&b"1"[..], PhpVar::Int(1)), (b"x", PhpVar::Null)].into_iter().collect();
+    assert_eq!(session_encode(&vars, SessionHandler::Php)?, b"1|i:1;x|N;".to_vec());
+    assert_eq!(session_decode(b"1|i:1;x|N;", SessionHandler::Php, &UnserializeOptions::default()).unwrap(), vars);
+
+    let long = [b'n'; 128];
+    let vars: PhpArray = vec![(&long[..127], PhpVar::Null)].into_iter().collect();
+    assert_eq!(session_encode(&vars, SessionHandler::PhpBinary)?, [&b"\x7f"[..], &long[..127], b"N;"].concat());
+    let vars: PhpArray = vec![(&long[..], PhpVar::Null), (b"x", PhpVar::Null)].into_iter().collect();
+    let err = session_encode(&vars, SessionHandler::PhpBinary).unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::SessionVarName(_)));
+
+    let vars: PhpArray = vec![(&b"a|b"[..], PhpVar::Null)].into_iter().collect();
+    let err = session_encode(&vars, SessionHandler::Php).unwrap_err();
+    assert_eq!(err.to_string(), "session var name contains '|'");
+    Ok(())
+}
@@ -0,0 +1,612 @@
+use std::collections::VecDeque;
+use std::io::{self, Read};
+
+use super::{valid_class_name, valid_float, Limit, Parsed, PhpKey, PhpVar, RefKind, UnserializeError,
+            UnserializeErrorKind, UnserializeOptions, INCOMPLETE_CLASS, INCOMPLETE_CLASS_NAME};
+
+const CHUNK: usize = 8192;
+
+// one step of a pull parse. every value is either a single `Scalar`, a `Ref`
+// or a `Start*` .. `End*` pair with `Key`, value pairs in between. scalars
+// include `C:` payloads and enum cases, which have no inner structure.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    StartArray(usize),
+    StartObject(Vec<u8>, usize),
+    Key(PhpKey),
+    Scalar(PhpVar),
+    // `r:`/`R:` with the slot number they point at, which is not resolved
+    // since nothing is kept around
+    Ref(RefKind, usize),
+    EndArray,
+    EndObject,
+}
+
+struct Frame {
+    object: bool,
+    remaining: usize,
+    // the slot of the array or object itself, which cannot be referenced
+    // before it is complete
+    slot: usize,
+    key_next: bool,
+}
+
+// pulls events out of any reader through a fixed size buffer, so memory use
+// is bounded by the nesting depth and the longest single string instead of
+// the size of the payload
+pub struct StreamParser<R> {
+    reader: R,
+    buf: Vec<u8>,
+    pos: usize,
+    end: usize,
+    // bytes consumed before `buf[0]`
+    base: usize,
+    opts: UnserializeOptions,
+    stack: Vec<Frame>,
+    queue: VecDeque<Event>,
+    slots: usize,
+    elements: usize,
+    done: bool,
+    finished: bool,
+}
+
+impl<R: Read> StreamParser<R> {
+    pub fn new(reader: R) -> Self {
+        StreamParser::with_options(reader, UnserializeOptions::default())
+    }
+
+    pub fn with_options(reader: R, opts: UnserializeOptions) -> Self {
+        StreamParser {
+            reader,
+            buf: vec![0; CHUNK],
+            pos: 0,
+            end: 0,
+            base: 0,
+            opts,
+            stack: vec![],
+            queue: VecDeque::new(),
+            slots: 0,
+            elements: 0,
+            done: false,
+            finished: false,
+        }
+    }
+
+    fn offset(&self) -> usize {
+        self.base + self.pos
+    }
+
+    fn fail<T>(&self, offset: usize, kind: UnserializeErrorKind, expected: &'static str) -> Parsed<T> {
+        Err(UnserializeError { offset, expected, kind })
+    }
+
+    // refills the buffer once it is used up, false at the end of input
+    fn fill(&mut self) -> Parsed<bool> {
+        if self.pos < self.end {
+            return Ok(true);
+        }
+        self.base += self.end;
+        self.pos = 0;
+        self.end = 0;
+        // never read past max_input_len, only probe for a byte beyond it
+        let want = CHUNK.min(self.opts.max_input_len - self.base).max(1);
+        let n = loop {
+            match self.reader.read(&mut self.buf[..want]) {
+                Ok(n) => break n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return self.fail(self.base, UnserializeErrorKind::Io(e.kind()), "more data"),
+            }
+        };
+        if n > 0 && self.base == self.opts.max_input_len {
+            return self.fail(self.base, UnserializeErrorKind::LimitExceeded(Limit::InputLength), "shorter input");
+        }
+        self.end = n;
+        Ok(n > 0)
+    }
+
+    fn peek(&mut self) -> Parsed<Option<u8>> {
+        if self.fill()? {
+            Ok(Some(self.buf[self.pos]))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn read_byte(&mut self, expected: &'static str) -> Parsed<u8> {
+        match self.peek()? {
+            Some(c) => {
+                self.pos += 1;
+                Ok(c)
+            },
+            None => self.fail(self.offset(), UnserializeErrorKind::Truncated, expected)
+        }
+    }
+
+    fn expect_byte(&mut self, c: u8, expected: &'static str) -> Parsed<()> {
+        if self.read_byte(expected)? == c {
+            Ok(())
+        } else {
+            self.fail(self.offset() - 1, UnserializeErrorKind::Malformed, expected)
+        }
+    }
+
+    fn read_bytes(&mut self, l: usize) -> Parsed<Vec<u8>> {
+        let mut s = vec![];
+        while s.len() < l {
+            if !self.fill()? {
+                return self.fail(self.offset(), UnserializeErrorKind::Truncated, "more data");
+            }
+            let n = (l - s.len()).min(self.end - self.pos);
+            s.extend_from_slice(&self.buf[self.pos..(self.pos + n)]);
+            self.pos += n;
+        }
+        Ok(s)
+    }
+
+    // unsigned lengths, counts and slot numbers
+    fn read_number(&mut self, expected: &'static str) -> Parsed<usize> {
+        let start = self.offset();
+        let mut v: usize = 0;
+        while let Some(c) = self.peek()? {
+            if !c.is_ascii_digit() {
+                break;
+            }
+            v = match v.checked_mul(10).and_then(|v| v.checked_add((c - b'0') as usize)) {
+                Some(v) => v,
+                None => return self.fail(start, UnserializeErrorKind::BadLength, expected)
+            };
+            self.pos += 1;
+        }
+        match self.peek()? {
+            _ if self.offset() != start => Ok(v),
+            None => self.fail(start, UnserializeErrorKind::Truncated, expected),
+            Some(_) => self.fail(start, UnserializeErrorKind::Malformed, expected),
+        }
+    }
+
+    fn read_signed(&mut self) -> Parsed<i64> {
+        let start = self.offset();
+        let neg = match self.peek()? {
+            Some(b'-') => { self.pos += 1; true },
+            Some(b'+') => { self.pos += 1; false },
+            _ => false
+        };
+        let digits = self.offset();
+        let mut v: i64 = 0;
+        while let Some(c) = self.peek()? {
+            if !c.is_ascii_digit() {
+                break;
+            }
+            let d = (c - b'0') as i64;
+            v = match v.checked_mul(10).and_then(|v| if neg { v.checked_sub(d) } else { v.checked_add(d) }) {
+                Some(v) => v,
+                None => return self.fail(start, UnserializeErrorKind::Malformed, "64-bit integer")
+            };
+            self.pos += 1;
+        }
+        match self.peek()? {
+            _ if self.offset() != digits => Ok(v),
+            None => self.fail(self.offset(), UnserializeErrorKind::Truncated, "integer"),
+            Some(_) => self.fail(self.offset(), UnserializeErrorKind::Malformed, "integer"),
+        }
+    }
+
+    fn read_float(&mut self) -> Parsed<f64> {
+        let start = self.offset();
+        let mut s = vec![];
+        loop {
+            match self.peek()? {
+                Some(b';') => break,
+                Some(c) if s.len() < self.opts.max_string_len => {
+                    s.push(c);
+                    self.pos += 1;
+                },
+                Some(_) => return self.fail(start, UnserializeErrorKind::Malformed, "float"),
+                None => return self.fail(self.offset(), UnserializeErrorKind::Truncated, "';'"),
+            }
+        }
+        let f = match s.as_slice() {
+            b"NAN" => Some(f64::NAN),
+            b"INF" => Some(f64::INFINITY),
+            b"-INF" => Some(f64::NEG_INFINITY),
+            s if valid_float(s) => String::from_utf8_lossy(s).parse().ok(),
+            _ => None
+        };
+        match f {
+            Some(f) => {
+                self.pos += 1;
+                Ok(f)
+            },
+            None => self.fail(start, UnserializeErrorKind::Malformed, "float")
+        }
+    }
+
+    fn read_len(&mut self) -> Parsed<usize> {
+        let start = self.offset();
+        let l = self.read_number("length")?;
+        if l > self.opts.max_string_len {
+            return self.fail(start, UnserializeErrorKind::LimitExceeded(Limit::StringLength), "shorter string");
+        }
+        Ok(l)
+    }
+
+    fn read_count(&mut self) -> Parsed<usize> {
+        let start = self.offset();
+        let l = self.read_number("element count")?;
+        if l > self.opts.max_elements - self.elements {
+            return self.fail(start, UnserializeErrorKind::LimitExceeded(Limit::Elements), "fewer elements");
+        }
+        Ok(l)
+    }
+
+    // `<len>:"<bytes>"`
+    fn read_string_body(&mut self) -> Parsed<Vec<u8>> {
+        let l = self.read_len()?;
+        self.expect_byte(b':', "':'")?;
+        self.expect_byte(b'"', "'\"'")?;
+        let s = self.read_bytes(l)?;
+        match self.read_byte("'\"'")? {
+            b'"' => Ok(s),
+            _ => self.fail(self.offset() - 1, UnserializeErrorKind::BadLength, "'\"'")
+        }
+    }
+
+    fn read_string(&mut self) -> Parsed<Vec<u8>> {
+        let s = self.read_string_body()?;
+        self.expect_byte(b';', "';'")?;
+        Ok(s)
+    }
+
+    fn read_class(&mut self) -> Parsed<Vec<u8>> {
+        let start = self.offset();
+        let class = self.read_string_body()?;
+        self.expect_byte(b':', "':'")?;
+        if !valid_class_name(&class) {
+            return self.fail(start, UnserializeErrorKind::Malformed, "class name");
+        }
+        Ok(class)
+    }
+
+    fn read_key(&mut self, object: bool) -> Parsed<PhpKey> {
+        let key = match self.read_byte("key")? {
+            b'i' => {
+                self.expect_byte(b':', "':'")?;
+                let i = self.read_signed()?;
+                self.expect_byte(b';', "';'")?;
+                PhpKey::Int(i)
+            },
+            b's' => {
+                self.expect_byte(b':', "':'")?;
+                PhpKey::from_bytes(&self.read_string()?)
+            },
+            b'}' => return self.fail(self.offset() - 1, UnserializeErrorKind::BadLength, "key"),
+            _ => return self.fail(self.offset() - 1, UnserializeErrorKind::UnknownType, "'i' or 's' key")
+        };
+        // property names are always strings
+        Ok(match key {
+            PhpKey::Int(i) if object => PhpKey::String(i.to_string().into_bytes()),
+            key => key
+        })
+    }
+
+    fn open(&mut self, object: bool, remaining: usize) {
+        self.stack.push(Frame { object, remaining, slot: self.slots, key_next: true });
+    }
+
+    // a value in the current array or object (or the whole input) is complete
+    fn value_done(&mut self) {
+        match self.stack.last_mut() {
+            Some(frame) => {
+                frame.remaining -= 1;
+                frame.key_next = true;
+            },
+            None => self.done = true,
+        }
+    }
+
+    // the events PHP's incomplete class object has in front of the real
+    // properties
+    fn queue_incomplete(&mut self, class: Vec<u8>) {
+        self.queue.push_back(Event::Key(PhpKey::String(INCOMPLETE_CLASS_NAME.to_vec())));
+        self.queue.push_back(Event::Scalar(PhpVar::String(class)));
+    }
+
+    fn read_value(&mut self) -> Parsed<Event> {
+        let start = self.offset();
+        if self.elements >= self.opts.max_elements {
+            return self.fail(start, UnserializeErrorKind::LimitExceeded(Limit::Elements), "fewer elements");
+        }
+        self.elements += 1;
+
+        let tag = self.read_byte("type tag")?;
+        match tag {
+            b'N' | b'b' | b'i' | b'd' | b's' | b'a' | b'O' | b'C' | b'E' | b'r' | b'R' => (),
+            b'}' => return self.fail(start, UnserializeErrorKind::BadLength, "value"),
+            _ => return self.fail(start, UnserializeErrorKind::UnknownType, "type tag")
+        }
+        // slots already taken before this value
+        let taken = self.slots;
+        if tag != b'R' {
+            self.slots += 1;
+        }
+        if tag == b'N' {
+            self.expect_byte(b';', "';'")?;
+            self.value_done();
+            return Ok(Event::Scalar(PhpVar::Null));
+        }
+        self.expect_byte(b':', "':'")?;
+
+        if (tag == b'a' || tag == b'O') && self.stack.len() >= self.opts.max_depth {
+            return self.fail(start, UnserializeErrorKind::LimitExceeded(Limit::Depth), "shallower nesting");
+        }
+        let event = match tag {
+            b'b' => {
+                let v = match self.read_byte("'0' or '1'")? {
+                    b'0' => false,
+                    b'1' => true,
+                    _ => return self.fail(self.offset() - 1, UnserializeErrorKind::Malformed, "'0' or '1'")
+                };
+                self.expect_byte(b';', "';'")?;
+                Event::Scalar(PhpVar::Bool(v))
+            },
+            b'i' => {
+                let v = self.read_signed()?;
+                self.expect_byte(b';', "';'")?;
+                Event::Scalar(PhpVar::Int(v))
+            },
+            b'd' => Event::Scalar(PhpVar::Float(self.read_float()?)),
+            b's' => Event::Scalar(PhpVar::String(self.read_string()?)),
+            b'a' => {
+                let l = self.read_count()?;
+                self.expect_byte(b':', "':'")?;
+                self.expect_byte(b'{', "'{'")?;
+                self.open(false, l);
+                return Ok(Event::StartArray(l));
+            },
+            b'O' => {
+                let class = self.read_class()?;
+                let l = self.read_count()?;
+                self.expect_byte(b':', "':'")?;
+                self.expect_byte(b'{', "'{'")?;
+                self.open(true, l);
+                if self.opts.allowed_classes.allows(&class) {
+                    return Ok(Event::StartObject(class, l));
+                }
+                self.queue_incomplete(class);
+                return Ok(Event::StartObject(INCOMPLETE_CLASS.to_vec(), l + 1));
+            },
+            b'C' => {
+                let class = self.read_class()?;
+                let l = self.read_len()?;
+                self.expect_byte(b':', "':'")?;
+                self.expect_byte(b'{', "'{'")?;
+                let data = self.read_bytes(l)?;
+                match self.read_byte("'}'")? {
+                    b'}' => (),
+                    _ => return self.fail(self.offset() - 1, UnserializeErrorKind::BadLength, "'}'")
+                }
+                if self.opts.allowed_classes.allows(&class) {
+                    Event::Scalar(PhpVar::Custom { class, data })
+                } else {
+                    // the payload is dropped, like the slice parser does
+                    self.queue_incomplete(class);
+                    self.queue.push_back(Event::EndObject);
+                    Event::StartObject(INCOMPLETE_CLASS.to_vec(), 1)
+                }
+            },
+            b'E' => {
+                let name_at = self.offset();
+                let name = self.read_string()?;
+                match name.iter().position(|c| *c == b':') {
+                    Some(i) if valid_class_name(&name[..i]) && valid_class_name(&name[(i + 1)..])
+                        && !name[(i + 1)..].contains(&b'\\') => {
+                        if !self.opts.allowed_classes.allows(&name[..i]) {
+                            return self.fail(name_at, UnserializeErrorKind::DisallowedClass, "allowed class");
+                        }
+                        Event::Scalar(PhpVar::Enum { class: name[..i].to_vec(), case: name[(i + 1)..].to_vec() })
+                    },
+                    _ => return self.fail(name_at, UnserializeErrorKind::Malformed, "enum case name")
+                }
+            },
+            _ => {
+                let kind = if tag == b'r' { RefKind::Value } else { RefKind::Shared };
+                let at = self.offset();
+                let v = self.read_number("slot number")?;
+                self.expect_byte(b';', "';'")?;
+                if v == 0 || v > taken || self.stack.iter().any(|f| f.slot == v) {
+                    return self.fail(at, UnserializeErrorKind::DanglingReference, "slot number");
+                }
+                Event::Ref(kind, v)
+            },
+        };
+        self.value_done();
+        Ok(event)
+    }
+
+    fn step(&mut self) -> Parsed<Option<Event>> {
+        if let Some(event) = self.queue.pop_front() {
+            return Ok(Some(event));
+        }
+        if self.done {
+            if !self.finished {
+                self.finished = true;
+                if self.peek()?.is_some() {
+                    return self.fail(self.offset(), UnserializeErrorKind::TrailingData, "end of input");
+                }
+            }
+            return Ok(None);
+        }
+        let (object, remaining, key_next) = match self.stack.last() {
+            Some(frame) => (frame.object, frame.remaining, frame.key_next),
+            None => (false, 0, false),
+        };
+        if !key_next {
+            return self.read_value().map(Some);
+        }
+        if remaining > 0 {
+            let key = self.read_key(object)?;
+            if let Some(frame) = self.stack.last_mut() {
+                frame.key_next = false;
+            }
+            return Ok(Some(Event::Key(key)));
+        }
+        // a `}` anywhere else means fewer elements than declared
+        match self.read_byte("'}'")? {
+            b'}' => (),
+            _ => return self.fail(self.offset() - 1, UnserializeErrorKind::BadLength, "'}'")
+        }
+        self.stack.pop();
+        self.value_done();
+        Ok(Some(if object { Event::EndObject } else { Event::EndArray }))
+    }
+
+    // the next event, `None` once the input has been read completely. after
+    // an error no further events are produced.
+    pub fn next_event(&mut self) -> Parsed<Option<Event>> {
+        let r = self.step();
+        if r.is_err() {
+            self.queue.clear();
+            self.done = true;
+            self.finished = true;
+        }
+        r
+    }
+
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+}
+
+impl<R: Read> Iterator for StreamParser<R> {
+    type Item = Parsed<Event>;
+
+    fn next(&mut self) -> Option<Parsed<Event>> {
+        self.next_event().transpose()
+    }
+}
+
+#[cfg(test)]
+use super::{try_unserialize, unserialize_with, AllowedClasses};
+
+// hands out one byte per read call
+#[cfg(test)]
+struct Trickle<'a>(&'a [u8]);
+
+#[cfg(test)]
+impl<'a> Read for Trickle<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.split_first() {
+            Some((c, rest)) if !buf.is_empty() => {
+                buf[0] = *c;
+                self.0 = rest;
+                Ok(1)
+            },
+            _ => Ok(0)
+        }
+    }
+}
+
+#[cfg(test)]
+fn events(raw: &[u8], opts: UnserializeOptions) -> Parsed<Vec<Event>> {
+    StreamParser::with_options(Trickle(raw), opts).collect()
+}
+
+#[test]
+fn test_stream_events() -> Parsed<()> {
+    let raw = b"a:3:{i:0;s:1:\"a\";s:1:\"b\";a:1:{i:5;d:0.5;}i:1;O:3:\"Foo\":1:{i:0;R:2;}}";
+    assert_eq!(events(raw, UnserializeOptions::default())?, vec![
+        Event::StartArray(3),
+        Event::Key(PhpKey::Int(0)),
+        Event::Scalar(PhpVar::String(b"a".to_vec())),
+        Event::Key(PhpKey::String(b"b".to_vec())),
+        Event::StartArray(1),
+        Event::Key(PhpKey::Int(5)),
+        Event::Scalar(PhpVar::Float(0.5)),
+        Event::EndArray,
+        Event::Key(PhpKey::Int(1)),
+        Event::StartObject(b"Foo".to_vec(), 1),
+        Event::Key(PhpKey::String(b"0".to_vec())),
+        Event::Ref(RefKind::Shared, 2),
+        Event::EndObject,
+        Event::EndArray,
+    ]);
+
+    let opts = UnserializeOptions { allowed_classes: AllowedClasses::Only(vec![]), ..Default::default() };
+    assert_eq!(events(b"a:2:{i:0;O:3:\"Foo\":0:{}i:1;C:3:\"Bar\":1:{x}}", opts)?, vec![
+        Event::StartArray(2),
+        Event::Key(PhpKey::Int(0)),
+        Event::StartObject(INCOMPLETE_CLASS.to_vec(), 1),
+        Event::Key(PhpKey::String(INCOMPLETE_CLASS_NAME.to_vec())),
+        Event::Scalar(PhpVar::String(b"Foo".to_vec())),
+        Event::EndObject,
+        Event::Key(PhpKey::Int(1)),
+        Event::StartObject(INCOMPLETE_CLASS.to_vec(), 1),
+        Event::Key(PhpKey::String(INCOMPLETE_CLASS_NAME.to_vec())),
+        Event::Scalar(PhpVar::String(b"Bar".to_vec())),
+        Event::EndObject,
+        Event::EndArray,
+    ]);
+    Ok(())
+}
+
+#[test]
+fn test_stream_matches_slice_parser() -> Parsed<()> {
+    // the streaming parser accepts and rejects exactly what the slice parser
+    // does, at the same offsets
+    let cases: &[&[u8]] = &[
+        b"N;", b"b:1;", b"i:-12;", b"d:-INF;", b"d:1.5E+3;", b"s:0:\"\";", b"E:7:\"Foo:Bar\";",
+        b"a:2:{i:0;i:1;i:1;r:2;}", b"a:2:{i:0;a:0:{}i:1;R:2;}", b"C:3:\"Foo\":2:{ab}",
+        b"", b"a:2:{i:0;N;", b"s:3:\"ab", b"s:1:\"ab\";", b"a:2:{i:0;N;}", b"a:1:{i:0;N;i:1;N;}",
+        b"x:1;", b"a:1:{N;N;}", b"a:2:{i:0;N;i:1;r:5;}", b"a:1:{i:0;R:1;}", b"a:1:{i:0;r:2;}",
+        b"i:1;i:2;", b"b:2;", b"i:1x", b"d:1.2.3;", b"d:1", b"O:1:\"-\":0:{}", b"i:99999999999999999999;",
+        b"s:18446744073709551611:\"\";", b"a:18446744073709551615:{}", b"E:7:\"Foo:B\\r\";",
+    ];
+    for raw in cases {
+        let expected = try_unserialize(raw).err();
+        let got = events(raw, UnserializeOptions::default()).err();
+        assert_eq!(got, expected, "{}", String::from_utf8_lossy(raw));
+    }
+
+    let opts = UnserializeOptions { max_depth: 2, max_elements: 4, max_string_len: 3, ..Default::default() };
+    let cases: &[&[u8]] = &[
+        b"a:1:{i:0;a:1:{i:0;N;}}", b"a:1:{i:0;a:1:{i:0;a:0:{}}}", b"a:4:{}", b"a:2:{i:0;N;i:1;a:1:{i:0;N;}}",
+        b"s:4:\"abcd\";", b"a:1:{i:0;s:3:\"abc\";}",
+    ];
+    for raw in cases {
+        let expected = unserialize_with(raw, &opts).err();
+        let got = events(raw, opts.clone()).err();
+        assert_eq!(got, expected, "{}", String::from_utf8_lossy(raw));
+    }
+
+    // the input size is only known once it has been read past, so anything
+    // wrong before that point is reported first
+    let opts = UnserializeOptions { max_input_len: 8, ..Default::default() };
+    let e = events(b"a:1:{i:0;N;}", opts.clone()).unwrap_err();
+    assert_eq!((e.offset, e.kind), (8, UnserializeErrorKind::LimitExceeded(Limit::InputLength)));
+    assert_eq!(events(b"i:123456;", opts.clone()).unwrap_err(), unserialize_with(b"i:123456;", &opts).unwrap_err());
+    assert_eq!(events(b"i:12345;", opts)?, vec![Event::Scalar(PhpVar::Int(12345))]);
+    Ok(())
+}
+
+#[test]
+fn test_stream_large() -> Parsed<()> {
+    // far more data than the buffer holds, read through a plain slice
+    let n = 100_000;
+    let mut raw = format!("a:{}:{{", n).into_bytes();
+    for i in 0..n {
+        raw.extend_from_slice(format!("i:{};s:3:\"{:03}\";", i, i % 1000).as_bytes());
+    }
+    raw.push(b'}');
+    let opts = UnserializeOptions { max_elements: n + 1, max_input_len: raw.len(), ..Default::default() };
+
+    let mut parser = StreamParser::with_options(&raw[..], opts);
+    let mut strings = 0;
+    while let Some(event) = parser.next_event()? {
+        if let Event::Scalar(PhpVar::String(s)) = event {
+            assert_eq!(s.len(), 3);
+            strings += 1;
+        }
+    }
+    assert_eq!(strings, n);
+    Ok(())
+}
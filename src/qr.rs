@@ -0,0 +1,713 @@
+use crate::errors::*;
+
+// reads the text out of a QR code in a PNG image, like the ones /gen links
+// to and authenticator apps show for export. the code may be scaled and
+// turned by quarter turns, but not drawn in perspective: modules are
+// sampled on the grid the three finder patterns span, without correcting
+// against the alignment patterns.
+
+// whatever size the PNG claims, and whatever the decoder needs besides the
+// frame for its own buffers
+const MAX_PIXELS: usize = 1 << 22;
+const MAX_DECODER_BYTES: usize = 1 << 24;
+
+// error correction codewords per block and blocks, per level (L, M, Q, H)
+// and version, as ISO/IEC 18004 table 9 has them
+const ECC_CODEWORDS_PER_BLOCK: [[u8; 41]; 4] = [
+    [0, 7, 10, 15, 20, 26, 18, 20, 24, 30, 18, 20, 24, 26, 30, 22, 24, 28, 30, 28, 28, 28, 28, 30, 30, 26, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30],
+    [0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26, 26, 26, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28],
+    [0, 13, 22, 18, 26, 18, 24, 18, 22, 20, 24, 28, 26, 24, 20, 30, 24, 28, 28, 26, 30, 28, 30, 30, 30, 30, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30],
+    [0, 17, 28, 22, 16, 22, 28, 26, 26, 24, 28, 24, 28, 22, 24, 24, 30, 28, 28, 26, 28, 30, 24, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30],
+];
+const ERROR_CORRECTION_BLOCKS: [[u8; 41]; 4] = [
+    [0, 1, 1, 1, 1, 1, 2, 2, 2, 2, 4, 4, 4, 4, 4, 6, 6, 6, 6, 7, 8, 8, 9, 9, 10, 12, 12, 12, 13, 14, 15, 16, 17, 18, 19, 19, 20, 21, 22, 24, 25],
+    [0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16, 17, 17, 18, 20, 21, 23, 25, 26, 28, 29, 31, 33, 35, 37, 38, 40, 43, 45, 47, 49],
+    [0, 1, 1, 2, 2, 4, 4, 6, 6, 8, 8, 8, 10, 12, 16, 12, 17, 16, 18, 21, 20, 23, 23, 25, 27, 29, 34, 34, 35, 38, 40, 43, 45, 48, 51, 53, 56, 59, 62, 65, 68],
+    [0, 1, 1, 2, 4, 4, 4, 5, 6, 8, 8, 11, 11, 16, 16, 18, 16, 19, 21, 25, 25, 25, 34, 30, 32, 35, 37, 40, 42, 45, 48, 51, 54, 57, 60, 63, 66, 70, 74, 77, 81],
+];
+
+const ALPHANUMERIC: &[u8; 45] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+
+// the image as dark and light pixels
+struct Bitmap {
+    width: usize,
+    height: usize,
+    dark: Vec<bool>,
+}
+
+impl Bitmap {
+    // grayscale with transparent pixels taken as the white page behind
+    // them, split halfway between the darkest and the lightest pixel
+    fn from_png(png: &[u8]) -> Result<Self> {
+        let mut decoder = png::Decoder::new_with_limits(png, png::Limits { bytes: MAX_DECODER_BYTES });
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info()?;
+        let (width, height) = (reader.info().width as usize, reader.info().height as usize);
+        if width * height > MAX_PIXELS {
+            bail!("image too large");
+        }
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf)?;
+        let channels = info.color_type.samples();
+
+        let mut gray = Vec::with_capacity(width * height);
+        for line in buf.chunks(info.line_size).take(height) {
+            for px in line[..(width * channels)].chunks(channels) {
+                let luma = |r: u8, g: u8, b: u8| (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+                let (v, alpha) = match *px {
+                    [v] => (v as u32, 255),
+                    [v, a] => (v as u32, a as u32),
+                    [r, g, b] => (luma(r, g, b), 255),
+                    [r, g, b, a] => (luma(r, g, b), a as u32),
+                    _ => bail!("unexpected PNG color type"),
+                };
+                gray.push((v * alpha + 255 * (255 - alpha)) / 255);
+            }
+        }
+        let (lo, hi) = match (gray.iter().min(), gray.iter().max()) {
+            (Some(&lo), Some(&hi)) if hi - lo >= 32 => (lo, hi),
+            _ => bail!("no QR code in the image")
+        };
+        let dark = gray.iter().map(|v| v * 2 < lo + hi).collect();
+        Ok(Bitmap { width, height, dark })
+    }
+
+    fn get(&self, x: isize, y: isize) -> Option<bool> {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return None;
+        }
+        Some(self.dark[y as usize * self.width + x as usize])
+    }
+
+    // the runs of dark, light, dark ... pixels through the dark pixel
+    // (x, y) along (dx, dy), if they look like the middle of a finder
+    // pattern: the center of its middle run along that axis and their
+    // total length
+    fn cross_check(&self, x: usize, y: usize, dx: isize, dy: isize) -> Option<(f64, usize)> {
+        if self.get(x as isize, y as isize) != Some(true) {
+            return None;
+        }
+        let runs = |sign: isize| {
+            let mut counts = [0; 3];
+            let (mut cx, mut cy) = (x as isize, y as isize);
+            for (i, count) in counts.iter_mut().enumerate() {
+                while self.get(cx, cy) == Some(i != 1) {
+                    *count += 1;
+                    cx += sign * dx;
+                    cy += sign * dy;
+                }
+            }
+            counts
+        };
+        let (back, forth) = (runs(-1), runs(1));
+        let runs = [back[2], back[1], back[0] + forth[0] - 1, forth[1], forth[2]];
+        if !is_finder(&runs) {
+            return None;
+        }
+        let start = (if dx != 0 { x } else { y }) + 1 - back[0];
+        Some((start as f64 + runs[2] as f64 / 2.0, runs.iter().sum()))
+    }
+}
+
+// five runs in the 1:1:3:1:1 proportion of a line through the middle of
+// a finder pattern
+fn is_finder(runs: &[usize; 5]) -> bool {
+    let total: usize = runs.iter().sum();
+    if total < 7 {
+        return false;
+    }
+    let module = total as f64 / 7.0;
+    runs.iter().zip([1.0, 1.0, 3.0, 1.0, 1.0]).all(|(run, n)| (*run as f64 - n * module).abs() < n * module / 2.0)
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Finder {
+    x: f64,
+    y: f64,
+    module: f64,
+    // rows it was found on
+    hits: usize,
+}
+
+impl Finder {
+    fn distance(&self, other: &Finder) -> f64 {
+        (self.x - other.x).hypot(self.y - other.y)
+    }
+}
+
+// every row is scanned for the 1:1:3:1:1 runs of a finder pattern, which
+// is confirmed by the column through its middle. finder patterns are found
+// on several rows, the real ones on more than anything that happens to
+// look like one
+fn finders(bm: &Bitmap) -> Vec<Finder> {
+    let mut found: Vec<Finder> = vec![];
+    for y in 0..bm.height {
+        // (start, length) of the runs from the first dark one on
+        let mut runs = vec![];
+        let mut x = 0;
+        while x < bm.width {
+            let (start, dark) = (x, bm.dark[y * bm.width + x]);
+            while x < bm.width && bm.dark[y * bm.width + x] == dark {
+                x += 1;
+            }
+            if dark || !runs.is_empty() {
+                runs.push((start, x - start));
+            }
+        }
+        for w in runs.windows(5).step_by(2) {
+            if !is_finder(&[w[0].1, w[1].1, w[2].1, w[3].1, w[4].1]) {
+                continue;
+            }
+            let (cy, vertical) = match bm.cross_check(w[2].0 + w[2].1 / 2, y, 0, 1) {
+                Some(check) => check,
+                None => continue
+            };
+            let (cx, horizontal) = match bm.cross_check(w[2].0 + w[2].1 / 2, cy as usize, 1, 0) {
+                Some(check) => check,
+                None => continue
+            };
+            let f = Finder { x: cx, y: cy, module: (vertical + horizontal) as f64 / 14.0, hits: 1 };
+            match found.iter_mut().find(|g| {
+                (g.x - f.x).abs() <= g.module && (g.y - f.y).abs() <= g.module && (g.module - f.module).abs() <= g.module
+            }) {
+                Some(g) => {
+                    let n = g.hits as f64;
+                    g.x = (g.x * n + f.x) / (n + 1.0);
+                    g.y = (g.y * n + f.y) / (n + 1.0);
+                    g.module = (g.module * n + f.module) / (n + 1.0);
+                    g.hits += 1;
+                },
+                None => found.push(f),
+            }
+        }
+    }
+    found.sort_by_key(|f| std::cmp::Reverse(f.hits));
+    found
+}
+
+// the modules of the code, dark or light
+struct Grid {
+    size: usize,
+    dark: Vec<bool>,
+}
+
+impl Grid {
+    fn get(&self, x: usize, y: usize) -> bool {
+        self.dark[y * self.size + x]
+    }
+}
+
+// the grid spanned by the top left, top right and bottom left finder
+// patterns, whose centers are 3.5 modules in from the corners
+fn sample(bm: &Bitmap) -> Result<Grid> {
+    let found = finders(bm);
+    if found.len() < 3 {
+        bail!("no QR code in the image");
+    }
+    let (a, b, c) = (found[0], found[1], found[2]);
+    // the top left one is the corner opposite the longest side, and the
+    // top right one is clockwise from it
+    let (tl, mut tr, mut bl) = if b.distance(&c) >= a.distance(&b).max(a.distance(&c)) {
+        (a, b, c)
+    } else if a.distance(&c) >= a.distance(&b) {
+        (b, a, c)
+    } else {
+        (c, a, b)
+    };
+    if (tr.x - tl.x) * (bl.y - tl.y) - (tr.y - tl.y) * (bl.x - tl.x) < 0.0 {
+        std::mem::swap(&mut tr, &mut bl);
+    }
+
+    let module = (tl.module + tr.module + bl.module) / 3.0;
+    let span = (tl.distance(&tr) + tl.distance(&bl)) / 2.0 / module;
+    let size = match span.round() as usize + 7 {
+        size if size % 4 == 1 => size,
+        size if size % 4 == 0 => size + 1,
+        size if size % 4 == 2 => size - 1,
+        _ => bail!("no QR code in the image"),
+    };
+    if !(21..=177).contains(&size) {
+        bail!("no QR code in the image");
+    }
+
+    let mut dark = Vec::with_capacity(size * size);
+    let side = (size - 7) as f64;
+    for row in 0..size {
+        for col in 0..size {
+            let u = (col as f64 + 0.5 - 3.5) / side;
+            let v = (row as f64 + 0.5 - 3.5) / side;
+            let x = tl.x + u * (tr.x - tl.x) + v * (bl.x - tl.x);
+            let y = tl.y + u * (tr.y - tl.y) + v * (bl.y - tl.y);
+            match bm.get(x.floor() as isize, y.floor() as isize) {
+                Some(d) => dark.push(d),
+                None => bail!("the QR code is cut off")
+            }
+        }
+    }
+    Ok(Grid { size, dark })
+}
+
+// the 15 bit format information for the 2 bit error correction level and
+// the 3 bit mask, BCH coded and masked with 0x5412
+fn format_bits(data: u32) -> u32 {
+    let mut rem = data;
+    for _ in 0..10 {
+        rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+    }
+    (data << 10 | rem) ^ 0x5412
+}
+
+// the error correction level, as an index into the tables, and the mask.
+// either copy may have up to three bad bits
+fn read_format(grid: &Grid) -> Result<(usize, usize)> {
+    let size = grid.size;
+    let bit = |x, y| grid.get(x, y) as u32;
+    let mut first = 0;
+    for x in 0..6 {
+        first = first << 1 | bit(x, 8);
+    }
+    first = first << 1 | bit(7, 8);
+    first = first << 1 | bit(8, 8);
+    first = first << 1 | bit(8, 7);
+    for y in (0..6).rev() {
+        first = first << 1 | bit(8, y);
+    }
+    let mut second = 0;
+    for y in ((size - 7)..size).rev() {
+        second = second << 1 | bit(8, y);
+    }
+    for x in (size - 8)..size {
+        second = second << 1 | bit(x, 8);
+    }
+
+    let (data, errors) = (0..32).map(|data| {
+        let bits = format_bits(data);
+        (data, (bits ^ first).count_ones().min((bits ^ second).count_ones()))
+    }).min_by_key(|(_, errors)| *errors).unwrap_or_default();
+    if errors > 3 {
+        bail!("unreadable QR format information");
+    }
+    // the levels are coded as M, L, H, Q
+    Ok(([1, 0, 3, 2][(data >> 3) as usize], (data & 7) as usize))
+}
+
+fn alignment_positions(version: usize, size: usize) -> Vec<usize> {
+    if version == 1 {
+        return vec![];
+    }
+    let count = version / 7 + 2;
+    let step = if version == 32 { 26 } else { (version * 4 + count * 2 + 1) / (count * 2 - 2) * 2 };
+    let mut positions: Vec<usize> = (0..(count - 1)).map(|i| size - 7 - i * step).collect();
+    positions.push(6);
+    positions.reverse();
+    positions
+}
+
+// finder, timing, alignment, format and version modules, which hold no data
+fn function_modules(version: usize, size: usize) -> Vec<bool> {
+    let mut function = vec![false; size * size];
+    let mut fill = |x0: usize, y0: usize, w: usize, h: usize| {
+        for y in y0..(y0 + h) {
+            for x in x0..(x0 + w) {
+                function[y * size + x] = true;
+            }
+        }
+    };
+    fill(0, 0, 9, 9);
+    fill(size - 8, 0, 8, 9);
+    fill(0, size - 8, 9, 8);
+    fill(6, 0, 1, size);
+    fill(0, 6, size, 1);
+    let positions = alignment_positions(version, size);
+    let last = positions.len().saturating_sub(1);
+    for (i, x) in positions.iter().enumerate() {
+        for (j, y) in positions.iter().enumerate() {
+            // the corners with finder patterns have none
+            if ![(0, 0), (0, last), (last, 0)].contains(&(i, j)) {
+                fill(x - 2, y - 2, 5, 5);
+            }
+        }
+    }
+    if version >= 7 {
+        fill(size - 11, 0, 3, 6);
+        fill(0, size - 11, 6, 3);
+    }
+    function
+}
+
+fn masked(mask: usize, x: usize, y: usize) -> bool {
+    match mask {
+        0 => (x + y).is_multiple_of(2),
+        1 => y.is_multiple_of(2),
+        2 => x.is_multiple_of(3),
+        3 => (x + y).is_multiple_of(3),
+        4 => (x / 3 + y / 2).is_multiple_of(2),
+        5 => x * y % 2 + x * y % 3 == 0,
+        6 => (x * y % 2 + x * y % 3).is_multiple_of(2),
+        _ => ((x + y) % 2 + x * y % 3).is_multiple_of(2),
+    }
+}
+
+// the codewords in the order they were placed: in two module wide columns
+// from the right, going up and down in turn, skipping the timing column
+fn read_codewords(grid: &Grid, version: usize, mask: usize) -> Vec<u8> {
+    let size = grid.size;
+    let function = function_modules(version, size);
+    let mut bits = vec![];
+    let mut right = size - 1;
+    while right >= 1 {
+        if right == 6 {
+            right = 5;
+        }
+        for vert in 0..size {
+            for x in [right, right - 1] {
+                let upward = (right + 1) & 2 == 0;
+                let y = if upward { size - 1 - vert } else { vert };
+                if !function[y * size + x] {
+                    bits.push(grid.get(x, y) ^ masked(mask, x, y));
+                }
+            }
+        }
+        if right < 2 {
+            break;
+        }
+        right -= 2;
+    }
+    // whatever is left over after the last whole codeword is padding
+    bits.chunks_exact(8).map(|byte| byte.iter().fold(0, |v, b| v << 1 | *b as u8)).collect()
+}
+
+// GF(2^8) with the polynomial QR codes use, x^8 + x^4 + x^3 + x^2 + 1
+struct Field {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+impl Field {
+    fn new() -> Self {
+        let mut f = Field { exp: [0; 512], log: [0; 256] };
+        let mut x: u16 = 1;
+        for i in 0..255 {
+            f.exp[i] = x as u8;
+            f.log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+        }
+        for i in 255..512 {
+            f.exp[i] = f.exp[i - 255];
+        }
+        f
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            return 0;
+        }
+        self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+    }
+
+    // b is never 0
+    fn div(&self, a: u8, b: u8) -> u8 {
+        if a == 0 {
+            return 0;
+        }
+        self.exp[self.log[a as usize] as usize + 255 - self.log[b as usize] as usize]
+    }
+
+    // alpha^n
+    fn pow(&self, n: usize) -> u8 {
+        self.exp[n % 255]
+    }
+
+    // coefficients from the lowest power up
+    fn eval(&self, poly: &[u8], x: u8) -> u8 {
+        poly.iter().rev().fold(0, |v, c| self.mul(v, x) ^ c)
+    }
+}
+
+// Reed-Solomon decoding of one block, whose generator has the roots
+// alpha^0 .. alpha^(ecc - 1): Berlekamp-Massey finds the error locator,
+// a Chien search the positions and Forney's formula the values
+fn correct(f: &Field, block: &mut [u8], ecc: usize) -> Result<()> {
+    let n = block.len();
+    let syndromes: Vec<u8> = (0..ecc).map(|i| {
+        let x = f.pow(i);
+        block.iter().fold(0, |v, c| f.mul(v, x) ^ c)
+    }).collect();
+    if syndromes.iter().all(|s| *s == 0) {
+        return Ok(());
+    }
+
+    let mut locator = vec![1];
+    let mut prev = vec![1];
+    let (mut errors, mut shift, mut last) = (0, 1, 1);
+    for i in 0..ecc {
+        let mut d = syndromes[i];
+        for j in 1..=errors.min(locator.len() - 1) {
+            d ^= f.mul(locator[j], syndromes[i - j]);
+        }
+        if d == 0 {
+            shift += 1;
+            continue;
+        }
+        let coef = f.div(d, last);
+        let old = locator.clone();
+        if locator.len() < prev.len() + shift {
+            locator.resize(prev.len() + shift, 0);
+        }
+        for (j, p) in prev.iter().enumerate() {
+            locator[j + shift] ^= f.mul(coef, *p);
+        }
+        if 2 * errors <= i {
+            errors = i + 1 - errors;
+            prev = old;
+            last = d;
+            shift = 1;
+        } else {
+            shift += 1;
+        }
+    }
+    if 2 * errors > ecc {
+        bail!("too many errors in the QR code");
+    }
+
+    // S(x) * locator(x) mod x^ecc
+    let mut evaluator = vec![0; ecc];
+    for (i, s) in syndromes.iter().enumerate() {
+        for (j, l) in locator.iter().enumerate().take(ecc - i) {
+            evaluator[i + j] ^= f.mul(*s, *l);
+        }
+    }
+    // the formal derivative only keeps the odd powers
+    let derivative: Vec<u8> = locator.iter().enumerate().skip(1)
+        .map(|(i, l)| if i % 2 == 1 { *l } else { 0 }).collect();
+
+    let mut found = 0;
+    for (k, byte) in block.iter_mut().enumerate() {
+        // the byte at k is the coefficient of x^(n - 1 - k)
+        let x = f.pow(n - 1 - k);
+        let x_inv = f.pow(255 - (n - 1 - k) % 255);
+        if f.eval(&locator, x_inv) != 0 {
+            continue;
+        }
+        let denominator = f.eval(&derivative, x_inv);
+        if denominator == 0 {
+            bail!("too many errors in the QR code");
+        }
+        *byte ^= f.mul(x, f.div(f.eval(&evaluator, x_inv), denominator));
+        found += 1;
+    }
+    if found != errors {
+        bail!("too many errors in the QR code");
+    }
+    Ok(())
+}
+
+// the data codewords: blocks are interleaved a codeword at a time, the
+// longer blocks (one more data codeword) last, and each is corrected
+fn data_codewords(codewords: &[u8], version: usize, level: usize) -> Result<Vec<u8>> {
+    let blocks = ERROR_CORRECTION_BLOCKS[level][version] as usize;
+    let ecc = ECC_CODEWORDS_PER_BLOCK[level][version] as usize;
+    let short = codewords.len() / blocks;
+    let shorts = blocks - codewords.len() % blocks;
+    let data = short - ecc;
+
+    // the short blocks are laid out as if they had a gap where the last
+    // data codeword of the long ones is
+    let mut split = vec![vec![]; blocks];
+    let mut next = codewords.iter();
+    for i in 0..=short {
+        for (j, block) in split.iter_mut().enumerate() {
+            if i == data && j < shorts {
+                continue;
+            }
+            block.extend(next.next());
+        }
+    }
+
+    let f = Field::new();
+    let mut out = vec![];
+    for block in &mut split {
+        correct(&f, block, ecc)?;
+        out.extend_from_slice(&block[..(block.len() - ecc)]);
+    }
+    Ok(out)
+}
+
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Bits<'_> {
+    fn left(&self) -> usize {
+        self.data.len() * 8 - self.pos
+    }
+
+    fn read(&mut self, n: usize) -> Result<usize> {
+        if n > self.left() {
+            bail!("truncated QR code segment");
+        }
+        let mut v = 0;
+        for _ in 0..n {
+            v = v << 1 | (self.data[self.pos / 8] >> (7 - self.pos % 8) & 1) as usize;
+            self.pos += 1;
+        }
+        Ok(v)
+    }
+}
+
+// the numeric, alphanumeric and byte segments, one after the other. ECI
+// designators are skipped, the bytes are returned as they are
+fn segments(data: &[u8], version: usize) -> Result<Vec<u8>> {
+    let width = match version {
+        1..=9 => 0,
+        10..=26 => 1,
+        _ => 2,
+    };
+    let mut bits = Bits { data, pos: 0 };
+    let mut out = vec![];
+    while bits.left() >= 4 {
+        match bits.read(4)? {
+            0 => break,
+            0b0001 => {
+                let mut count = bits.read([10, 12, 14][width])?;
+                while count > 0 {
+                    let digits = count.min(3);
+                    let v = bits.read([4, 7, 10][digits - 1])?;
+                    if v >= [10, 100, 1000][digits - 1] {
+                        bail!("bad numeric QR code segment");
+                    }
+                    out.extend_from_slice(format!("{:01$}", v, digits).as_bytes());
+                    count -= digits;
+                }
+            },
+            0b0010 => {
+                let mut count = bits.read([9, 11, 13][width])?;
+                while count > 0 {
+                    let chars = count.min(2);
+                    let v = bits.read([6, 11][chars - 1])?;
+                    let (hi, lo) = if chars == 2 { (Some(v / 45), v % 45) } else { (None, v) };
+                    for c in hi.into_iter().chain(Some(lo)) {
+                        match ALPHANUMERIC.get(c) {
+                            Some(c) => out.push(*c),
+                            None => bail!("bad alphanumeric QR code segment")
+                        }
+                    }
+                    count -= chars;
+                }
+            },
+            0b0100 => {
+                let count = bits.read([8, 16, 16][width])?;
+                for _ in 0..count {
+                    out.push(bits.read(8)? as u8);
+                }
+            },
+            0b0111 => {
+                let len = match bits.read(1)? {
+                    0 => 7,
+                    _ if bits.read(1)? == 0 => 14,
+                    _ => 21,
+                };
+                bits.read(len)?;
+            },
+            mode => bail!("unsupported QR code segment mode {}", mode)
+        }
+    }
+    Ok(out)
+}
+
+// the text in the QR code in a PNG image
+pub fn decode(png: &[u8]) -> Result<Vec<u8>> {
+    let grid = sample(&Bitmap::from_png(png)?)?;
+    let version = (grid.size - 17) / 4;
+    let (level, mask) = read_format(&grid)?;
+    let codewords = read_codewords(&grid, version, mask);
+    segments(&data_codewords(&codewords, version, level)?, version)
+}
+
+#[cfg(test)]
+use qrcodegen::{QrCode, QrCodeEcc, QrSegment, Version, Mask};
+
+// `code` as a grayscale PNG, `scale` pixels a module with a 4 module
+// quiet zone, turned clockwise `turns` times, with the modules in `flip`
+// inverted
+#[cfg(test)]
+pub(crate) fn render(code: &QrCode, scale: usize, turns: usize, flip: &[(i32, i32)]) -> Vec<u8> {
+    let size = code.size() as usize;
+    let width = (size + 8) * scale;
+    let mut pixels = vec![255u8; width * width];
+    for (i, px) in pixels.iter_mut().enumerate() {
+        let (mut x, mut y) = ((i % width / scale) as i32 - 4, (i / width / scale) as i32 - 4);
+        for _ in 0..turns {
+            let t = x;
+            x = y;
+            y = size as i32 - 1 - t;
+        }
+        if code.get_module(x, y) != flip.contains(&(x, y)) {
+            *px = 0;
+        }
+    }
+    let mut out = vec![];
+    let mut encoder = png::Encoder::new(&mut out, width as u32, width as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(&pixels).unwrap();
+    writer.finish().unwrap();
+    out
+}
+
+#[test]
+fn test_decode() -> Result<()> {
+    let uri = "otpauth://totp/babi:demo?secret=JBSWY3DPEHPK3PXP&issuer=babi";
+    for (i, ecl) in [QrCodeEcc::Low, QrCodeEcc::Medium, QrCodeEcc::Quartile, QrCodeEcc::High].iter().enumerate() {
+        let code = QrCode::encode_text(uri, *ecl).unwrap();
+        assert_eq!(decode(&render(&code, 1 + i, i, &[]))?, uri.as_bytes());
+    }
+
+    // every mask, numeric and alphanumeric segments, ECI
+    for mask in 0..8 {
+        let segs = [QrSegment::make_eci(26), QrSegment::make_numeric("0123456789"),
+                    QrSegment::make_alphanumeric("OTPAUTH://TOTP/A $%*+-./:"), QrSegment::make_bytes(b"\xc3\xa9")];
+        let code = QrCode::encode_segments_advanced(&segs, QrCodeEcc::Medium, Version::new(2), Version::new(40),
+                                                    Some(Mask::new(mask)), false).unwrap();
+        assert_eq!(decode(&render(&code, 3, 0, &[]))?, "0123456789OTPAUTH://TOTP/A $%*+-./:\u{e9}".as_bytes());
+    }
+
+    // versions with version information, alignment patterns and blocks of
+    // two lengths
+    for len in [200, 1000, 2900] {
+        let text = "otpauth://totp/a?secret=".repeat(len / 24 + 1);
+        let code = QrCode::encode_text(&text[..len], QrCodeEcc::Low).unwrap();
+        assert_eq!(decode(&render(&code, 2, 3, &[]))?, &text.as_bytes()[..len]);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_decode_errors() -> Result<()> {
+    // version 1-M holds 10 error correction codewords, so 5 bad ones
+    let code = QrCode::encode_segments_advanced(&QrSegment::make_segments("otpauth://x"), QrCodeEcc::Medium,
+                                                Version::new(1), Version::new(1), None, false).unwrap();
+    // one module in each of five codewords, two bits of the format
+    let bad = [(20, 20), (20, 16), (20, 12), (18, 9), (18, 13), (0, 8), (1, 8)];
+    assert_eq!(decode(&render(&code, 2, 0, &bad))?, b"otpauth://x");
+    let bad: Vec<(i32, i32)> = [20, 18, 16].iter().flat_map(|x| (9..21).map(move |y| (*x, y))).collect();
+    assert_ne!(decode(&render(&code, 2, 0, &bad)).ok(), Some(b"otpauth://x".to_vec()));
+
+    // without its top left finder pattern
+    let finder: Vec<(i32, i32)> = (0..7).flat_map(|x| (0..7).map(move |y| (x, y)))
+        .filter(|(x, y)| code.get_module(*x, *y)).collect();
+    assert!(decode(&render(&code, 2, 0, &finder)).is_err());
+    assert!(decode(b"\x89PNG\r\n\x1a\n").is_err());
+
+    // a header claiming a frame far larger than the upload
+    let mut png = Vec::new();
+    let mut writer = png::Encoder::new(&mut png, 1 << 16, 1 << 16).write_header().unwrap();
+    writer.write_chunk(png::chunk::IDAT, b"\x78\x9c\x03\x00\x00\x00\x00\x01").unwrap();
+    drop(writer);
+    assert_eq!(decode(&png).unwrap_err().to_string(), "image too large");
+    Ok(())
+}